@@ -0,0 +1,42 @@
+//! Times comparing two identical 1GB files with small (4KiB), default (64KiB) and large (1MiB) blocks.
+//!
+//! Run with `cargo bench --bench block_size`. The files are left in `target/bench-block-size`.
+
+extern crate ubiquity;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use ubiquity::compare_files::{file_contents_equal_in_blocks, DEFAULT_BLOCK_SIZE};
+
+const FILE_SIZE: usize = 1024 * 1024 * 1024;
+
+fn main() {
+    let base = Path::new("target/bench-block-size");
+    let a = base.join("a");
+    let b = base.join("b");
+    if fs::metadata(&b).map(|m| m.len() as usize).ok() != Some(FILE_SIZE) {
+        fs::create_dir_all(base).unwrap();
+        write_file(&a);
+        write_file(&b);
+    }
+
+    for &block_size in &[4 * 1024, DEFAULT_BLOCK_SIZE, 1024 * 1024] {
+        let start = Instant::now();
+        assert!(file_contents_equal_in_blocks(&a, &b, block_size).unwrap());
+        println!("{}KiB blocks: {:?}", block_size / 1024, start.elapsed());
+    }
+}
+
+fn write_file(path: &Path) {
+    let mut chunk = vec![0u8; 1024 * 1024];
+    for (i, byte) in chunk.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    let mut file = fs::File::create(path).unwrap();
+    for _ in 0..FILE_SIZE / chunk.len() {
+        file.write_all(&chunk).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Times detection over a directory of 1000 large files, half of which differ between the replicas,
+//! with the file contents compared on one thread, on four, and then on every CPU.
+//!
+//! Run with `cargo bench --bench parallel_comparison`. The replicas (about 2GB) are left in `target/bench-replicas`.
+
+#[macro_use]
+extern crate generic_array;
+extern crate ubiquity;
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use ubiquity::archive::Archive;
+use ubiquity::config::SyncInfo;
+use ubiquity::detect;
+
+const FILES: usize = 1000;
+const FILE_SIZE: usize = 1024 * 1024;
+
+fn main() {
+    let base = Path::new("target/bench-replicas");
+    let a = base.join("a");
+    let b = base.join("b");
+    if !a.join(format!("{}", FILES - 1)).exists() {
+        write_replicas(&a, &b);
+    }
+
+    let cpus = SyncInfo::new(arr![PathBuf; a.clone(), b.clone()]).comparison_threads;
+    let mut thread_counts = vec![1, 4, cpus];
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+    for threads in thread_counts {
+        let mut config = SyncInfo::new(arr![PathBuf; a.clone(), b.clone()]);
+        config.comparison_threads = threads;
+
+        // a fresh archive, so that every file has to be compared
+        let archive_path = base.join("archive");
+        if archive_path.exists() {
+            fs::remove_dir_all(&archive_path).unwrap();
+        }
+        fs::create_dir_all(&archive_path).unwrap();
+        let archive = Archive::new(archive_path).unwrap();
+
+        let start = Instant::now();
+        let result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        assert_eq!(result.differences.len(), FILES / 2);
+        println!("{} threads: {:?}", threads, start.elapsed());
+    }
+}
+
+/// Writes identical files to both replicas, except that every other file differs in its last byte,
+/// so that telling them apart means reading them in full.
+fn write_replicas(a: &Path, b: &Path) {
+    fs::create_dir_all(a).unwrap();
+    fs::create_dir_all(b).unwrap();
+    let mut contents = vec![0u8; FILE_SIZE];
+    for (i, byte) in contents.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    for i in 0..FILES {
+        fs::write(a.join(format!("{}", i)), &contents).unwrap();
+        let mut file = fs::File::create(b.join(format!("{}", i))).unwrap();
+        if i % 2 == 0 {
+            file.write_all(&contents).unwrap();
+        } else {
+            file.write_all(&contents[..FILE_SIZE - 1]).unwrap();
+            file.write_all(&[!contents[FILE_SIZE - 1]]).unwrap();
+        }
+    }
+}
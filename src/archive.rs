@@ -1,129 +1,721 @@
-use crate::util::hash_value;
-use bincode::{self, deserialize_from, serialize_into};
+use bincode::{self, Options};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fnv::FnvHasher;
 use fs2::FileExt;
 use generic_array::GenericArray;
-use std::collections::hash_map;
 use std::convert::From;
+use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
 use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::config::SyncInfo;
+use crate::error::{DescribeIoError, SyncError};
 use crate::state::ArchiveEntryPerReplica;
 use crate::util::FnvHashMap;
 use crate::NumRoots;
 use serde::{Deserialize, Serialize};
 
-const ARCHIVE_VERSION: u32 = 3;
+const ARCHIVE_VERSION: u32 = 8;
+
+/// The file in the archive directory which records the roots the archive was built for (see `Archive::check_roots`)
+const ROOTS_FILE: &str = "roots";
+
+/// How long to wait between attempts to lock an archive file, with `LockPolicy::Timeout`
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 pub type HashedPath = u64;
 
+/// Hashes paths into the names of archive files and the keys of the entries inside them.
+///
+/// This is implemented for every `BuildHasher`, see `Archive::with_hasher`.
+pub trait PathHasher: fmt::Debug + Send + Sync {
+    fn hash_path(&self, path: &Path) -> HashedPath;
+}
+
+impl<S: BuildHasher + fmt::Debug + Send + Sync> PathHasher for S {
+    fn hash_path(&self, path: &Path) -> HashedPath {
+        self.hash_one(path)
+    }
+}
+
+/// The hasher used by `Archive::new`, and by every archive written before `Archive::with_hasher` existed.
+pub type DefaultPathHasher = fnv::FnvBuildHasher;
+
+fn default_hasher() -> Arc<dyn PathHasher> {
+    Arc::new(DefaultPathHasher::default())
+}
+
+/// What to do when an archive file is locked by another process (eg: another sync of the same replicas).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LockPolicy {
+    /// Wait for as long as it takes. This is the default.
+    #[default]
+    Wait,
+    /// Fail immediately with `SyncError::ArchiveLocked`
+    FailFast,
+    /// Keep trying for this long, and then fail with `SyncError::ArchiveLocked`
+    Timeout(Duration),
+}
+
+/// How long an `ArchiveFile` holds on to its lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LockScope {
+    /// The lock is acquired by the first read or write, and held until `ArchiveFile::unlock` is called
+    /// or the `ArchiveFile` is dropped. This is the default.
+    #[default]
+    UntilUnlocked,
+    /// The lock is only held while reading or writing, and the lock file is closed in between,
+    /// so a process which forks while an `ArchiveFile` is open doesn't pass a held lock on to the child.
+    /// A lock taken explicitly with `ArchiveFile::lock_shared` or `lock_exclusive` is still held until `unlock`.
+    PerOperation,
+}
+
+/// How archive files are arranged inside the archive directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ArchiveLayout {
+    /// Every archive file is directly inside the archive directory, named after its hashed path.
+    /// This is the default, and how archives were always laid out before `Sharded` existed.
+    #[default]
+    Flat,
+    /// Archive files are spread across up to 256 subdirectories, named after the first byte of the hash in hex
+    /// (eg: `archive/a3/11797432947380183458`), so that no single directory holds millions of files.
+    /// The subdirectories are created as they are needed.
+    Sharded,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// The `Archive` struct stores the state of the replicas after the last syncing operation.
 /// It is used to detect differences to replicas more quickly, and must be kept up to date after propagating changes.
 pub struct Archive {
     pub directory: PathBuf,
+    /// Return `ReadError::InvalidArchiveVersion` when reading an archive file with an unknown version
+    /// (one which can't be migrated), instead of discarding its contents (which makes every item look new).
+    #[serde(default)]
+    pub strict_version: bool,
+    /// What to do when another process is using an archive file
+    #[serde(default)]
+    pub lock_policy: LockPolicy,
+    /// Whether archive files are locked for as long as they are open, or only while being read or written
+    #[serde(default)]
+    pub lock_scope: LockScope,
+    /// Where the archive files are kept. After changing this, `migrate_layout` moves the existing files.
+    #[serde(default)]
+    pub layout: ArchiveLayout,
+    /// Use the archive even if it was built for different roots, instead of failing with `SyncError::ArchiveRootMismatch`
+    #[serde(default)]
+    pub allow_root_mismatch: bool,
+    /// Never write to the archive directory: writes are skipped, nothing is locked, and `find_updates`
+    /// doesn't record the items it finds in sync (see `Archive::read_only`)
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(skip, default = "default_hasher")]
+    hasher: Arc<dyn PathHasher>,
 }
 
 impl Archive {
     /// Initializes a directory at the provided path and gets ready to start reading/writing archive data.
     pub fn new(directory: PathBuf) -> Result<Self, io::Error> {
+        Self::with_hasher(directory, DefaultPathHasher::default())
+    }
+
+    /// Like `new`, but paths are hashed with `hasher` instead of `DefaultPathHasher`
+    /// (eg: a keyed hash, which is harder to provoke collisions in).
+    ///
+    /// Archive files are named and keyed by these hashes, so the hasher must give the same hashes
+    /// in every process which opens the archive, otherwise all of its entries are missed:
+    /// deleted items come back and edits look like conflicts. That rules out
+    /// `std::collections::hash_map::RandomState`, which picks new keys in every process.
+    /// Instead, use a fixed key which is stored alongside the configuration:
+    ///
+    /// ```
+    /// # use std::hash::BuildHasher;
+    /// # use std::path::PathBuf;
+    /// # use twox_hash::XxHash64;
+    /// # use ubiquity::archive::Archive;
+    /// #[derive(Debug)]
+    /// struct KeyedHasher(u64);
+    ///
+    /// impl BuildHasher for KeyedHasher {
+    ///     type Hasher = XxHash64;
+    ///     fn build_hasher(&self) -> XxHash64 {
+    ///         XxHash64::with_seed(self.0)
+    ///     }
+    /// }
+    ///
+    /// let key = 0x5eed_cafe_f00d_d00d; // eg: read from the configuration file
+    /// let archive = Archive::with_hasher(PathBuf::from("tests/replicas/keyed_archive"), KeyedHasher(key)).unwrap();
+    /// ```
+    ///
+    /// The hashes are still 64 bits, whatever the hasher.
+    pub fn with_hasher<S>(directory: PathBuf, hasher: S) -> Result<Self, io::Error>
+    where
+        S: BuildHasher + fmt::Debug + Send + Sync + 'static,
+    {
         // creates the archive directory
         if !directory.exists() {
             fs::create_dir_all(&directory)?;
         }
-        Ok(Archive { directory })
+        Ok(Archive {
+            directory,
+            strict_version: false,
+            lock_policy: LockPolicy::Wait,
+            lock_scope: LockScope::UntilUnlocked,
+            layout: ArchiveLayout::Flat,
+            allow_root_mismatch: false,
+            read_only: false,
+            hasher: Arc::new(hasher),
+        })
+    }
+
+    /// Opens an existing archive without ever writing to it (eg: on a read-only snapshot),
+    /// so that detection can be used to audit the replicas without changing anything.
+    ///
+    /// The archive directory isn't created, and no lock files are made, so another process
+    /// may be writing to the archive while it is being read. Propagation still changes the replicas,
+    /// but the archive isn't updated to match.
+    pub fn read_only(directory: PathBuf) -> Self {
+        Self::read_only_with_hasher(directory, DefaultPathHasher::default())
+    }
+
+    /// Like `read_only`, for an archive which was written using `with_hasher`.
+    /// The same hasher (eg: with the same key) is needed to find any of its entries.
+    pub fn read_only_with_hasher<S>(directory: PathBuf, hasher: S) -> Self
+    where
+        S: BuildHasher + fmt::Debug + Send + Sync + 'static,
+    {
+        Archive {
+            directory,
+            strict_version: false,
+            lock_policy: LockPolicy::Wait,
+            lock_scope: LockScope::UntilUnlocked,
+            layout: ArchiveLayout::Flat,
+            allow_root_mismatch: false,
+            read_only: true,
+            hasher: Arc::new(hasher),
+        }
     }
 
     /// Constructs an `ArchiveFile` representing the entire `directory` in the replicas.
     pub fn for_directory(&self, directory: &Path) -> ArchiveFile {
-        self.for_hashed_directory(Self::hash(directory))
+        self.for_hashed_directory(self.hash(directory))
     }
 
     /// Constructs an `ArchiveFile` from a hashed directory, representing an entire directory in the replicas.
     pub fn for_hashed_directory(&self, directory: HashedPath) -> ArchiveFile {
-        let path = self.directory.join(directory.to_string());
+        let path = self.path_for_hash(directory, self.layout);
 
-        ArchiveFile::new(path)
+        ArchiveFile::new(
+            path,
+            self.strict_version,
+            self.lock_policy,
+            self.lock_scope,
+            self.read_only,
+            self.hasher.clone(),
+        )
     }
 
-    pub fn hash(path: &Path) -> HashedPath {
-        hash_value(path)
+    /// Where the archive file for a hashed directory is kept with `layout`.
+    fn path_for_hash(&self, directory: HashedPath, layout: ArchiveLayout) -> PathBuf {
+        let name = directory.to_string();
+        match layout {
+            ArchiveLayout::Flat => self.directory.join(name),
+            ArchiveLayout::Sharded => self
+                .directory
+                .join(format!("{:02x}", directory >> 56))
+                .join(name),
+        }
+    }
+
+    /// Hashes a path with this archive's hasher.
+    pub fn hash(&self, path: &Path) -> HashedPath {
+        self.hasher.hash_path(path)
+    }
+
+    /// Checks that the archive was built for `roots`, so that the state of one set of replicas is never
+    /// mistaken for another's (which could make changes look like they were made elsewhere and be overwritten).
+    /// Called by `detect::find_updates`.
+    ///
+    /// The roots are fingerprinted exactly as they are configured, including their order. An archive used with
+    /// relative roots can be moved to another machine along with the replicas, but switching between relative
+    /// and absolute paths to the same roots counts as a mismatch. Archives which haven't recorded any roots yet
+    /// (eg: those written before this check existed) adopt `roots`.
+    pub fn check_roots(&self, roots: &[PathBuf]) -> Result<(), SyncError> {
+        let path = self.directory.join(ROOTS_FILE);
+        let recorded = match fs::read_to_string(&path) {
+            Ok(recorded) => recorded,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.read_only => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return self
+                    .record_roots(roots)
+                    .describe(|| format!("while recording the roots in {:?}", path))
+                    .map_err(SyncError::from)
+            }
+            Err(e) => return Err((e, format!("while reading {:?}", path)).into()),
+        };
+        if recorded.trim() == format!("{:016x}", roots_fingerprint(roots)) {
+            return Ok(());
+        }
+        if self.allow_root_mismatch {
+            warn!(
+                "The archive at {:?} was built for different roots",
+                self.directory
+            );
+            Ok(())
+        } else {
+            Err(SyncError::ArchiveRootMismatch(self.directory.clone()))
+        }
+    }
+
+    /// Records `roots` as the ones this archive belongs to, eg: after moving the replicas somewhere else.
+    pub fn record_roots(&self, roots: &[PathBuf]) -> Result<(), io::Error> {
+        let path = self.directory.join(ROOTS_FILE);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, format!("{:016x}\n", roots_fingerprint(roots)))?;
+        fs::rename(&temporary, &path)
+    }
+
+    /// Reads the archive entries for a single directory in the replicas, for debugging.
+    ///
+    /// The entries are sorted by path.
+    pub fn dump_directory<N: NumRoots>(
+        &self,
+        relative_dir: &Path,
+    ) -> Result<Vec<(PathBuf, Vec<ArchiveEntryPerReplica>)>, ReadError> {
+        let entries: ArchiveEntries<N> = self.for_directory(relative_dir).read()?;
+        let mut dump: Vec<_> = entries
+            .iter()
+            .map(|(path, replicas)| (path.to_path_buf(), replicas.to_vec()))
+            .collect();
+        dump.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(dump)
+    }
+
+    /// Removes the archive files for directories which no longer exist in any of the roots,
+    /// such as those left behind when propagating a deletion failed. Returns how many were removed.
+    /// On unix, lock files without an archive file (eg: left behind by an older version) are removed too.
+    ///
+    /// Archive files are named after the hash of their directory, so the directory is worked out from
+    /// the paths stored alongside each entry. This relies on archive version 7: files whose entries were migrated
+    /// from an older version (and haven't been scanned since) don't know their paths, so they are kept.
+    pub fn prune_orphans<N: NumRoots>(&self, config: &SyncInfo<N>) -> Result<usize, SyncError> {
+        let mut removed = 0;
+        for hash in self.hashed_directories()? {
+            let mut archive_file = self.for_hashed_directory(hash);
+            let entries: ArchiveEntries<N> = archive_file.read()?;
+            let directory = match entries
+                .iter()
+                .find(|(path, _)| !path.as_os_str().is_empty())
+                .and_then(|(path, _)| path.parent())
+            {
+                Some(directory) => directory.to_path_buf(),
+                None => continue,
+            };
+            if config
+                .roots
+                .iter()
+                .all(|root| !root.join(&directory).is_dir())
+            {
+                info!(
+                    "Removing {} for deleted directory {:?}",
+                    archive_file, directory
+                );
+                archive_file.remove_all()?;
+                removed += 1;
+            }
+        }
+        // lock files are only removed on unix (see `ArchiveFile::remove_all`)
+        if cfg!(unix) {
+            for (hash, path) in self.files_named(parse_lock_file_name)? {
+                let mut archive_file = self.for_hashed_directory(hash);
+                if path != archive_file.lock_path() {
+                    continue;
+                }
+                // checked with the lock held, so that an archive file another process has just written isn't removed
+                archive_file.lock_exclusive()?;
+                if !archive_file.path.exists() {
+                    debug!("Removing orphaned lock file {:?}", path);
+                    archive_file.remove_all()?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The hashes of every directory with an archive file in the current layout.
+    pub(crate) fn hashed_directories(&self) -> Result<Vec<HashedPath>, io::Error> {
+        Ok(self
+            .archive_files()?
+            .into_iter()
+            .filter(|(hash, path)| *path == self.path_for_hash(*hash, self.layout))
+            .map(|(hash, _)| hash)
+            .collect())
+    }
+
+    /// Every archive file in either layout, with the hash it is named after.
+    fn archive_files(&self) -> Result<Vec<(HashedPath, PathBuf)>, io::Error> {
+        self.files_named(parse_archive_file_name)
+    }
+
+    /// Every file in either layout whose name `parse` turns into a hash, with that hash.
+    fn files_named(
+        &self,
+        parse: fn(&OsStr) -> Option<HashedPath>,
+    ) -> Result<Vec<(HashedPath, PathBuf)>, io::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if entry.file_type()?.is_dir() {
+                if name.len() != 2 || u8::from_str_radix(name, 16).is_err() {
+                    continue;
+                }
+                for entry in fs::read_dir(entry.path())? {
+                    let entry = entry?;
+                    if let Some(hash) = parse(&entry.file_name()) {
+                        files.push((hash, entry.path()));
+                    }
+                }
+            } else if let Some(hash) = parse(&entry.file_name()) {
+                files.push((hash, entry.path()));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Moves any archive files which aren't where `layout` expects them (eg: after switching to
+    /// `ArchiveLayout::Sharded`), returning how many were moved.
+    ///
+    /// This mustn't run while another process is using the archive, as the files are moved without being locked.
+    pub fn migrate_layout(&self) -> Result<usize, io::Error> {
+        if self.read_only {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        for (hash, path) in self.archive_files()? {
+            let destination = self.path_for_hash(hash, self.layout);
+            if path == destination {
+                continue;
+            }
+            debug!("Moving archive file {:?} to {:?}", path, destination);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&path, &destination)?;
+            // the lock file is recreated next to the archive file when it is needed
+            let _ = fs::remove_file(path.with_extension("lock"));
+            moved += 1;
+        }
+        info!(
+            "Moved {} archive files into the {:?} layout",
+            moved, self.layout
+        );
+        Ok(moved)
+    }
+
+    /// An `ArchiveFile` for every directory stored in the archive, eg: to walk the entire persisted state
+    /// with `ArchiveEntries::iter`. Files in the archive directory which aren't archive files are skipped.
+    pub fn iter_files(&self) -> Result<impl Iterator<Item = ArchiveFile> + '_, io::Error> {
+        Ok(self
+            .hashed_directories()?
+            .into_iter()
+            .map(move |hash| self.for_hashed_directory(hash)))
+    }
+
+    /// Rewrites every archive file written by an older version of Ubiquity in the current format.
+    ///
+    /// Older archive files are also migrated whenever they are read, so this is only needed to upgrade them all at once.
+    pub fn migrate_all<N: NumRoots>(&self) -> Result<(), SyncError> {
+        for hash in self.hashed_directories()? {
+            let mut archive_file = self.for_hashed_directory(hash);
+            archive_file.lock_exclusive()?;
+            let mut entries: ArchiveEntries<N> = archive_file.read()?;
+            if entries.is_dirty() {
+                debug!("Rewriting migrated {}", archive_file);
+                archive_file.write(&mut entries)?;
+            }
+        }
+        Ok(())
     }
 }
 
 /// Abstracts over operations on a single archive file.
 /// Remember each 'file' in the archive represents an entire directory (not recursive) in the replicas.
+///
+/// Archive files are replaced atomically when written, so access is synchronised by locking
+/// a separate `.lock` file alongside each one.
+/// The lock is acquired by the first `read`, `write` or `remove_all` and then held (by keeping the
+/// locked handle open) until `unlock` is called or the `ArchiveFile` is dropped,
+/// unless the archive's `lock_scope` is `LockScope::PerOperation`.
+/// It can also be taken up front with `lock_shared` or `lock_exclusive`.
+/// The lock file is removed along with the archive file by `remove_all` (see there for the exception).
+///
+/// Reading only takes a shared lock, so that several processes can scan the same archive at once.
+/// The lock is upgraded to an exclusive one when writing, which isn't atomic:
+/// another process may write to the archive file in between. Anything which reads entries, changes them
+/// and writes them back should call `lock_exclusive` before reading, so that no other process's write is lost.
+///
+/// The locks belong to the open lock file, which a child process shares after `fork`:
+/// the child holds the lock too, and unlocking it in either process releases it for both.
+/// A process which forks should do so while no `ArchiveFile` holds a lock, or use `LockScope::PerOperation`.
 pub struct ArchiveFile {
     path: PathBuf,
-    file: Option<fs::File>,
+    lock: Option<(fs::File, LockMode)>,
+    strict_version: bool,
+    lock_policy: LockPolicy,
+    lock_scope: LockScope,
+    read_only: bool,
+    hasher: Arc<dyn PathHasher>,
+}
+
+/// The kind of lock currently held on an archive file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockMode {
+    Shared,
+    Exclusive,
 }
 
 impl ArchiveFile {
     /// Creates a new wrapper around the given archive file.
-    fn new(path: PathBuf) -> ArchiveFile {
-        ArchiveFile { path, file: None }
+    fn new(
+        path: PathBuf,
+        strict_version: bool,
+        lock_policy: LockPolicy,
+        lock_scope: LockScope,
+        read_only: bool,
+        hasher: Arc<dyn PathHasher>,
+    ) -> ArchiveFile {
+        ArchiveFile {
+            path,
+            lock: None,
+            strict_version,
+            lock_policy,
+            lock_scope,
+            read_only,
+            hasher,
+        }
     }
 
     /// Remove all entries from this file.
     /// This just slightly more efficient than writing an empty Vec.
-    pub fn remove_all(&mut self) -> Result<(), io::Error> {
-        if self.path.exists() {
+    ///
+    /// On unix the lock file is removed too (while it is still locked), which releases the lock.
+    /// Elsewhere an open file can't always be removed, so the lock file is left behind, and reused if
+    /// the directory is archived again.
+    pub fn remove_all(&mut self) -> Result<(), WriteError> {
+        if self.read_only {
+            debug!("Not removing {}, which is read-only", self);
+        } else if self.path.exists() || self.lock_path().exists() {
             debug!("Removing {} (because entries are empty)", self);
-            self.file = None;
-            fs::remove_file(&self.path)?;
+            let was_locked = self.lock.is_some();
+            self.lock(LockMode::Exclusive)
+                .map_err(|e| WriteError::from_lock_error(self.path.clone(), e))?;
+            let removed = match fs::remove_file(&self.path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                removed => removed,
+            };
+            if removed.is_ok() && cfg!(unix) {
+                if let Err(e) = fs::remove_file(self.lock_path()) {
+                    debug!("Couldn't remove the lock file for {}: {}", self, e);
+                }
+                self.unlock();
+            } else {
+                self.release_after_operation(was_locked);
+            }
+            removed?;
         }
         Ok(())
     }
 
+    fn lock_path(&self) -> PathBuf {
+        self.path.with_extension("lock")
+    }
+
     /// Reads the archive entries into a Vec
     /// This may acquire (or wait for) a lock,
     /// ensuring that multiple threads/processes aren't reading/writing to/from the same archive file.
     pub fn read<N: NumRoots>(&mut self) -> Result<ArchiveEntries<N>, ReadError> {
-        if let Some(ref mut file) = self.file {
-            let data = read_from_file(file, &self.path)?;
-            Ok(ArchiveEntries::new(data))
-        } else if self.path.exists() {
-            let mut file = self.open_file()?;
-            let res = read_from_file(&mut file, &self.path)?;
-            self.file = Some(file);
-            Ok(ArchiveEntries::new(res))
+        if self.path.exists() {
+            let was_locked = self.lock.is_some();
+            self.lock(LockMode::Shared)
+                .map_err(|e| ReadError::from_lock_error(self.path.clone(), e))?;
+            let read = fs::File::open(&self.path)
+                .map_err(ReadError::from)
+                .and_then(|mut file| read_from_file(&mut file, &self.path, self.strict_version));
+            self.release_after_operation(was_locked);
+            let (data, migrated) = read?;
+            Ok(ArchiveEntries::new(data, migrated, self.hasher.clone()))
         } else {
-            Ok(ArchiveEntries::empty()) // an empty set of entries
+            // an empty set of entries
+            Ok(ArchiveEntries::new(
+                Default::default(),
+                false,
+                self.hasher.clone(),
+            ))
+        }
+    }
+
+    /// Waits for (according to the `LockPolicy`) and acquires a shared lock, which is then held until `unlock`,
+    /// whatever the `LockScope`. Any exclusive lock which is already held is kept.
+    pub fn lock_shared(&mut self) -> Result<(), ReadError> {
+        self.lock(LockMode::Shared)
+            .map_err(|e| ReadError::from_lock_error(self.path.clone(), e))
+    }
+
+    /// Like `lock_shared`, but acquires an exclusive lock, eg: to read and then write the file without
+    /// another process writing to it in between.
+    pub fn lock_exclusive(&mut self) -> Result<(), WriteError> {
+        self.lock(LockMode::Exclusive)
+            .map_err(|e| WriteError::from_lock_error(self.path.clone(), e))
+    }
+
+    /// With `LockScope::PerOperation`, releases the lock taken by a read or write, unless it was already held.
+    fn release_after_operation(&mut self, was_locked: bool) {
+        if self.lock_scope == LockScope::PerOperation && !was_locked {
+            self.unlock();
+        }
+    }
+
+    /// Acquires a lock for this archive file, unless a sufficient one is already held.
+    fn lock(&mut self, mode: LockMode) -> Result<(), io::Error> {
+        // the lock file can't be created in a read-only archive
+        if self.read_only {
+            return Ok(());
+        }
+        loop {
+            let (file, held) = match self.lock.take() {
+                Some((file, held)) if held == LockMode::Exclusive || held == mode => {
+                    self.lock = Some((file, held));
+                    return Ok(());
+                }
+                Some((file, held)) => (file, Some(held)),
+                None => {
+                    let file = fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(self.lock_path())?;
+                    (file, None)
+                }
+            };
+
+            // the lock is only recorded once it has been acquired, so a failure never leaves a lock which isn't held
+            trace!("Acquiring {:?} lock for {}", mode, self);
+            match self.acquire(&file, mode) {
+                Ok(()) => {
+                    // `remove_all` may have removed the lock file while this was waiting for it,
+                    // in which case the lock doesn't exclude anyone who opens the file afresh
+                    if !is_same_file(&file, &self.lock_path())? {
+                        debug!("The lock file for {} was removed, locking it again", self);
+                        continue;
+                    }
+                    self.lock = Some((file, mode));
+                    trace!("Acquired lock");
+                    return Ok(());
+                }
+                Err(e) => {
+                    // flock drops the shared lock before waiting for the exclusive one, so it is taken again,
+                    // or forgotten (closing the file) if another process has got in first
+                    if let Some(held) = held {
+                        match FileExt::try_lock_shared(&file) {
+                            Ok(()) => self.lock = Some((file, held)),
+                            Err(_) => warn!("Lost the {:?} lock on {}", held, self),
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Waits for a lock on the lock file, according to the `LockPolicy`.
+    fn acquire(&self, file: &fs::File, mode: LockMode) -> Result<(), io::Error> {
+        let deadline = match self.lock_policy {
+            LockPolicy::Wait => None,
+            LockPolicy::FailFast => Some(Instant::now()),
+            LockPolicy::Timeout(timeout) => Some(Instant::now() + timeout),
+        };
+        let deadline = match (mode, deadline) {
+            (LockMode::Shared, None) => return file.lock_shared(),
+            (LockMode::Exclusive, None) => return file.lock_exclusive(),
+            (_, Some(deadline)) => deadline,
+        };
+        loop {
+            let result = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(file),
+            };
+            match result {
+                Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return result;
+                    }
+                    debug!("{:?} is locked by another process, retrying", self.path);
+                    thread::sleep((deadline - now).min(LOCK_RETRY_INTERVAL));
+                }
+                result => return result,
+            }
         }
     }
 
-    fn open_file(&self) -> Result<fs::File, io::Error> {
-        let file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&self.path)?;
-        trace!("Acquiring shared lock for {}", self);
-        file.lock_exclusive().unwrap();
-        trace!("Acquired lock");
-        Ok(file)
+    /// Releases the lock early, if it is held. It will be acquired again by the next read or write.
+    pub fn unlock(&mut self) {
+        if let Some((lock, _)) = self.lock.take() {
+            trace!("Unlocking archive file {:?}", self.path);
+            if let Err(e) = lock.unlock() {
+                // closing the handle releases the lock anyway
+                warn!("Couldn't unlock archive file {:?}: {}", self.path, e);
+            }
+            trace!("Unlocked");
+        }
     }
 
     /// Writes entries to disk
+    ///
+    /// The entries are written to a temporary file which then replaces the archive file,
+    /// so the previous entries are kept intact if writing fails part way through.
     pub fn write<N: NumRoots>(
         &mut self,
         entries: &mut ArchiveEntries<N>,
     ) -> Result<(), WriteError> {
+        if self.read_only {
+            debug!("Not writing {}, which is read-only", self);
+            return Ok(());
+        }
         // prevents the archive sizes exploding
         entries.prune_deleted();
 
         let entries = &entries.entries;
         if entries.is_empty() {
             self.remove_all()?;
-        } else if let Some(ref mut file) = self.file {
-            write_to_file(file, &self.path, &entries)?;
         } else {
-            let mut file = self.open_file()?;
-            write_to_file(&mut file, &self.path, &entries)?;
-            self.file = Some(file);
+            // shards are created lazily
+            if let Some(parent) = self.path.parent() {
+                if !parent.exists() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            let was_locked = self.lock.is_some();
+            self.lock(LockMode::Exclusive)
+                .map_err(|e| WriteError::from_lock_error(self.path.clone(), e))?;
+            let temporary = self.path.with_extension("tmp");
+            let written = write_to_file(&temporary, entries)
+                .and_then(|()| fs::rename(&temporary, &self.path).map_err(WriteError::from));
+            self.release_after_operation(was_locked);
+            written?;
         }
 
         Ok(())
@@ -142,21 +734,64 @@ impl fmt::Display for ArchiveFile {
 
 impl Drop for ArchiveFile {
     fn drop(&mut self) {
-        if self.path.exists() {
-            let f = fs::File::open(&self.path).unwrap();
-            trace!("Unlocking archive file {:?}", self.path);
-            f.unlock().unwrap();
-            trace!("Unlocked");
-        }
+        self.unlock();
     }
 }
 
-type ArchiveEntryMap<N> = FnvHashMap<HashedPath, GenericArray<ArchiveEntryPerReplica, N>>;
+/// Whether `file` is still the file at `path`, rather than one which has since been removed or replaced.
+#[cfg(unix)]
+fn is_same_file(file: &fs::File, path: &Path) -> Result<bool, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let current = match fs::metadata(path) {
+        Ok(current) => current,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let opened = file.metadata()?;
+    Ok(opened.dev() == current.dev() && opened.ino() == current.ino())
+}
+
+/// Lock files are only removed on unix, so the open file is always the current one.
+#[cfg(not(unix))]
+fn is_same_file(_: &fs::File, _: &Path) -> Result<bool, io::Error> {
+    Ok(true)
+}
+
+/// A hash of the roots, which is the same on every platform and version of Rust.
+fn roots_fingerprint(roots: &[PathBuf]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    for root in roots {
+        hasher.write(root.to_string_lossy().as_bytes());
+        // separates the roots, since no path contains a NUL
+        hasher.write_u8(0);
+    }
+    hasher.finish()
+}
+
+/// The hash an archive file is named after.
+/// Lock and temporary files have an extension, so they don't parse.
+fn parse_archive_file_name(name: &OsStr) -> Option<HashedPath> {
+    name.to_str().and_then(|name| name.parse().ok())
+}
+
+/// The hash of the archive file a lock file belongs to.
+fn parse_lock_file_name(name: &OsStr) -> Option<HashedPath> {
+    name.to_str()
+        .and_then(|name| name.strip_suffix(".lock"))
+        .and_then(|name| name.parse().ok())
+}
+
+/// Each entry is stored alongside its (relative) path, so that the archive can be listed without rescanning.
+/// Entries migrated from versions before 7 have an empty path until they are next seen by `find_updates`.
+type ArchiveEntryMap<N> =
+    FnvHashMap<HashedPath, (PathBuf, GenericArray<ArchiveEntryPerReplica, N>)>;
 
 /// Stores all the archive entries for a specific directory
 pub struct ArchiveEntries<N: NumRoots> {
     entries: ArchiveEntryMap<N>,
     dirty: bool,
+    hasher: Arc<dyn PathHasher>,
 }
 
 impl<N: NumRoots> fmt::Debug for ArchiveEntries<N> {
@@ -166,35 +801,59 @@ impl<N: NumRoots> fmt::Debug for ArchiveEntries<N> {
 }
 
 impl<N: NumRoots> ArchiveEntries<N> {
+    /// An empty set of entries, keyed with `DefaultPathHasher`.
     pub fn empty() -> Self {
-        ArchiveEntries {
-            entries: Default::default(),
-            dirty: false,
-        }
+        ArchiveEntries::new(Default::default(), false, default_hasher())
     }
 
-    fn new(entries: ArchiveEntryMap<N>) -> Self {
+    /// Entries which were migrated from an older version start off dirty, so that they are rewritten.
+    fn new(entries: ArchiveEntryMap<N>, migrated: bool, hasher: Arc<dyn PathHasher>) -> Self {
         ArchiveEntries {
             entries,
-            dirty: false,
+            dirty: migrated,
+            hasher,
         }
     }
 
-    /// Returns an iterator over the entries.
-    pub fn iter(&self) -> hash_map::Iter<HashedPath, GenericArray<ArchiveEntryPerReplica, N>> {
-        self.entries.iter()
+    /// Returns an iterator over the paths and entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &GenericArray<ArchiveEntryPerReplica, N>)> {
+        self.entries
+            .values()
+            .map(|(path, replicas)| (path.as_path(), replicas))
+    }
+
+    /// Returns an iterator over the hashed paths and entries.
+    pub fn iter_hashed(
+        &self,
+    ) -> impl Iterator<Item = (HashedPath, &GenericArray<ArchiveEntryPerReplica, N>)> {
+        self.entries
+            .iter()
+            .map(|(hash, (_, replicas))| (*hash, replicas))
     }
 
     pub fn get(&self, path: &Path) -> Option<&GenericArray<ArchiveEntryPerReplica, N>> {
-        self.entries.get(&Archive::hash(path))
+        self.entries
+            .get(&self.hasher.hash_path(path))
+            .map(|(_, replicas)| replicas)
     }
 
     pub fn insert(&mut self, path: &Path, entries: GenericArray<ArchiveEntryPerReplica, N>) {
-        let hashed_path = Archive::hash(path);
-        self.entries.insert(hashed_path, entries);
+        let hashed_path = self.hasher.hash_path(path);
+        self.entries
+            .insert(hashed_path, (path.to_path_buf(), entries));
         self.dirty = true;
     }
 
+    /// Records the path of an entry which was migrated from an archive that didn't store paths.
+    pub fn restore_path(&mut self, path: &Path) {
+        if let Some((stored, _)) = self.entries.get_mut(&self.hasher.hash_path(path)) {
+            if stored.as_os_str().is_empty() {
+                *stored = path.to_path_buf();
+                self.dirty = true;
+            }
+        }
+    }
+
     // Loops through each ArchiveEntry and removes it if all replicas are empty.
     // Without this archive sizes will probably explode if enough files are created,
     // synced and then deleted.
@@ -202,7 +861,7 @@ impl<N: NumRoots> ArchiveEntries<N> {
         let empties: Vec<_> = self
             .entries
             .iter()
-            .filter(|&(_, ref entry)| {
+            .filter(|&(_, (_, entry))| {
                 let mut delete = true;
                 for replica in entry.iter() {
                     match replica {
@@ -231,14 +890,15 @@ impl<N: NumRoots> ArchiveEntries<N> {
 fn read_from_file<N: NumRoots>(
     file: &mut fs::File,
     path: &Path,
-) -> Result<ArchiveEntryMap<N>, ReadError> {
+    strict_version: bool,
+) -> Result<(ArchiveEntryMap<N>, bool), ReadError> {
     debug!("Reading archive file {:?}", path);
     file.seek(io::SeekFrom::Start(0))?;
     match read_entries(file) {
         Ok(i) => Ok(i),
-        Err(ReadError::InvalidArchiveVersion(version)) => {
+        Err(ReadError::InvalidArchiveVersion(version)) if !strict_version => {
             error!("Invalid archive version {} for file {:?}", version, path);
-            Ok(Default::default())
+            Ok((Default::default(), false))
         }
         // TODO: if have forgotten when this error case is encountered
         // out of safety, I have removed this case and instead a hard error will be thrown
@@ -251,34 +911,48 @@ fn read_from_file<N: NumRoots>(
     }
 }
 
-fn write_to_file<N: NumRoots>(
-    file: &mut fs::File,
-    path: &Path,
-    entries: &ArchiveEntryMap<N>,
-) -> Result<(), WriteError> {
+fn write_to_file<N: NumRoots>(path: &Path, entries: &ArchiveEntryMap<N>) -> Result<(), WriteError> {
     info!("Writing to archive file {:?}: {:#?}", path, entries);
-    file.set_len(0)?;
-
-    let pos = file.seek(io::SeekFrom::Start(0))?;
-    assert_eq!(pos, 0);
-
-    write_entries(file, entries)?;
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    write_entries(&mut file, entries)?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    // make sure the data has reached the disk before the file replaces the previous archive
+    file.sync_all()?;
 
     Ok(())
 }
 
-/// reads a set of entries from a binary stream
-fn read_entries<R, N>(read: &mut R) -> Result<ArchiveEntryMap<N>, ReadError>
+/// The bincode configuration for archive files, following the version header.
+///
+/// This is spelled out (rather than using `bincode::serialize_into`) so that the on-disk format can't change with bincode's defaults:
+/// little-endian, fixed-size integers (so lengths are `u64`s), and no size limit.
+pub(crate) fn encoding() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .with_no_limit()
+        .allow_trailing_bytes()
+}
+
+/// reads a set of entries from a binary stream, migrating them if they were written by an older version.
+/// Also returns whether the entries were migrated.
+fn read_entries<R, N>(read: &mut R) -> Result<(ArchiveEntryMap<N>, bool), ReadError>
 where
     R: io::Read,
     N: NumRoots,
 {
     let version = read.read_u32::<LittleEndian>()?;
-    if version != ARCHIVE_VERSION {
-        return Err(ReadError::InvalidArchiveVersion(version));
-    }
-    let result = deserialize_from(read)?;
-    Ok(result)
+    let result = match version {
+        ARCHIVE_VERSION => return Ok((encoding().deserialize_from(read)?, false)),
+        3 => migrate::read_entries::<_, migrate::ReplicaV3, N>(read, false)?,
+        4 => migrate::read_entries::<_, migrate::ReplicaV4, N>(read, false)?,
+        5 => migrate::read_entries::<_, migrate::ReplicaV5, N>(read, false)?,
+        6 => migrate::read_entries::<_, migrate::ReplicaV7, N>(read, false)?,
+        7 => migrate::read_entries::<_, migrate::ReplicaV7, N>(read, true)?,
+        _ => return Err(ReadError::InvalidArchiveVersion(version)),
+    };
+    info!("Migrated archive entries from version {}", version);
+    Ok((result, true))
 }
 
 // writes a set of entries to a binary stream
@@ -288,7 +962,7 @@ where
     N: NumRoots,
 {
     out.write_u32::<LittleEndian>(ARCHIVE_VERSION)?;
-    serialize_into(out, &entries)?;
+    encoding().serialize_into(out, &entries)?;
     Ok(())
 }
 
@@ -298,6 +972,20 @@ pub enum ReadError {
     InvalidArchiveVersion(u32),
     IoError(io::Error),
     BincodeError(bincode::Error),
+    /// The archive file at this path couldn't be locked (eg: on filesystems without lock support)
+    LockError(PathBuf, io::Error),
+    /// Another process held the lock on the archive file at this path, see `LockPolicy`
+    Locked(PathBuf),
+}
+
+impl ReadError {
+    fn from_lock_error(path: PathBuf, e: io::Error) -> Self {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            ReadError::Locked(path)
+        } else {
+            ReadError::LockError(path, e)
+        }
+    }
 }
 
 impl From<bincode::Error> for ReadError {
@@ -312,11 +1000,54 @@ impl From<io::Error> for ReadError {
     }
 }
 
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::InvalidArchiveVersion(version) => {
+                write!(f, "invalid archive version {}", version)
+            }
+            ReadError::IoError(ref e) => write!(f, "io error: {}", e),
+            ReadError::BincodeError(ref e) => write!(f, "deserialization error: {}", e),
+            ReadError::LockError(ref path, ref e) => {
+                write!(f, "couldn't lock archive file {:?}: {}", path, e)
+            }
+            ReadError::Locked(ref path) => {
+                write!(f, "archive file {:?} is locked by another process", path)
+            }
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReadError::InvalidArchiveVersion(_) | ReadError::Locked(_) => None,
+            ReadError::IoError(ref e) => Some(e),
+            ReadError::BincodeError(ref e) => Some(e),
+            ReadError::LockError(_, ref e) => Some(e),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Various errors explaining why an archive file couldn't be written to
 pub enum WriteError {
     IoError(io::Error),
     BincodeError(bincode::Error),
+    /// The archive file at this path couldn't be locked (eg: on filesystems without lock support)
+    LockError(PathBuf, io::Error),
+    /// Another process held the lock on the archive file at this path, see `LockPolicy`
+    Locked(PathBuf),
+}
+
+impl WriteError {
+    fn from_lock_error(path: PathBuf, e: io::Error) -> Self {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            WriteError::Locked(path)
+        } else {
+            WriteError::LockError(path, e)
+        }
+    }
 }
 
 impl From<bincode::Error> for WriteError {
@@ -330,3 +1061,180 @@ impl From<io::Error> for WriteError {
         WriteError::IoError(e)
     }
 }
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::IoError(ref e) => write!(f, "io error: {}", e),
+            WriteError::BincodeError(ref e) => write!(f, "serialization error: {}", e),
+            WriteError::LockError(ref path, ref e) => {
+                write!(f, "couldn't lock archive file {:?}: {}", path, e)
+            }
+            WriteError::Locked(ref path) => {
+                write!(f, "archive file {:?} is locked by another process", path)
+            }
+        }
+    }
+}
+
+impl Error for WriteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            WriteError::IoError(ref e) => Some(e),
+            WriteError::BincodeError(ref e) => Some(e),
+            WriteError::LockError(_, ref e) => Some(e),
+            WriteError::Locked(_) => None,
+        }
+    }
+}
+
+/// The layouts of archive entries written by older versions, and how to upgrade them.
+mod migrate {
+    use bincode::Options;
+    use generic_array::GenericArray;
+    use serde::Deserialize;
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::{encoding, ArchiveEntryMap, HashedPath, ReadError};
+    use crate::state::{ArchiveEntryExists, ArchiveEntryPerReplica, ArchiveSymlinkEntry};
+    use crate::NumRoots;
+
+    /// Reads entries in an older layout `R`, upgrading each one.
+    /// Versions before 7 stored a map from hashed paths to replicas, without the paths themselves (`has_paths` is false).
+    pub fn read_entries<T, R, N>(
+        read: &mut T,
+        has_paths: bool,
+    ) -> Result<ArchiveEntryMap<N>, ReadError>
+    where
+        T: io::Read,
+        R: for<'de> Deserialize<'de> + Into<ArchiveEntryPerReplica>,
+        N: NumRoots,
+    {
+        // this mirrors how bincode serializes a map of `GenericArray`s:
+        // the number of entries, followed by each key and its fixed-size array of replicas
+        let len: u64 = encoding().deserialize_from(&mut *read)?;
+        let mut entries = ArchiveEntryMap::<N>::default();
+        for _ in 0..len {
+            let hash: HashedPath = encoding().deserialize_from(&mut *read)?;
+            // older versions didn't store paths
+            let path: PathBuf = if has_paths {
+                encoding().deserialize_from(&mut *read)?
+            } else {
+                PathBuf::new()
+            };
+            let mut replicas = GenericArray::<ArchiveEntryPerReplica, N>::default();
+            for replica in replicas.iter_mut() {
+                *replica = encoding().deserialize_from::<_, R>(&mut *read)?.into();
+            }
+            entries.insert(hash, (path, replicas));
+        }
+        Ok(entries)
+    }
+
+    /// Versions 3 and 4 only recorded the inode number and change time.
+    #[derive(Deserialize)]
+    pub struct EntryV3 {
+        ino: u64,
+        ctime: i64,
+    }
+
+    impl From<EntryV3> for ArchiveEntryExists {
+        fn from(entry: EntryV3) -> Self {
+            ArchiveEntryExists::from_ino_ctime(entry.ino, entry.ctime, None)
+        }
+    }
+
+    /// Version 5 added the cached content hash.
+    #[derive(Deserialize)]
+    pub struct EntryV5 {
+        ino: u64,
+        ctime: i64,
+        hash: Option<u64>,
+    }
+
+    impl From<EntryV5> for ArchiveEntryExists {
+        fn from(entry: EntryV5) -> Self {
+            ArchiveEntryExists::from_ino_ctime(entry.ino, entry.ctime, entry.hash)
+        }
+    }
+
+    /// Version 3 didn't record symlink targets.
+    #[derive(Deserialize)]
+    pub enum ReplicaV3 {
+        Empty,
+        Directory(EntryV3),
+        File(EntryV3),
+        Symlink(EntryV3),
+    }
+
+    impl From<ReplicaV3> for ArchiveEntryPerReplica {
+        fn from(replica: ReplicaV3) -> Self {
+            match replica {
+                ReplicaV3::Empty => ArchiveEntryPerReplica::Empty,
+                ReplicaV3::Directory(entry) => ArchiveEntryPerReplica::Directory(entry.into()),
+                ReplicaV3::File(entry) => ArchiveEntryPerReplica::File(entry.into()),
+                // the target is unknown, so the symlink will be compared again on the next run
+                ReplicaV3::Symlink(entry) => ArchiveEntryPerReplica::Symlink(
+                    ArchiveSymlinkEntry::new(entry.into(), PathBuf::new()),
+                ),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct SymlinkV4<E> {
+        entry: E,
+        target: PathBuf,
+    }
+
+    #[derive(Deserialize)]
+    pub enum ReplicaV4<E = EntryV3> {
+        Empty,
+        Directory(E),
+        File(E),
+        Symlink(SymlinkV4<E>),
+    }
+
+    /// Version 5 has the same layout as version 4, apart from the entries themselves.
+    pub type ReplicaV5 = ReplicaV4<EntryV5>;
+
+    /// Versions 6 and 7 recorded everything apart from the permissions.
+    #[derive(Deserialize)]
+    pub struct EntryV7 {
+        ino: u64,
+        ctime: i64,
+        mtime: i64,
+        mtime_nsec: i64,
+        size: u64,
+        hash: Option<u64>,
+    }
+
+    impl From<EntryV7> for ArchiveEntryExists {
+        fn from(entry: EntryV7) -> Self {
+            ArchiveEntryExists::without_mode(
+                entry.ino,
+                entry.ctime,
+                entry.mtime,
+                entry.mtime_nsec,
+                entry.size,
+                entry.hash,
+            )
+        }
+    }
+
+    pub type ReplicaV7 = ReplicaV4<EntryV7>;
+
+    impl<E: Into<ArchiveEntryExists>> From<ReplicaV4<E>> for ArchiveEntryPerReplica {
+        fn from(replica: ReplicaV4<E>) -> Self {
+            match replica {
+                ReplicaV4::Empty => ArchiveEntryPerReplica::Empty,
+                ReplicaV4::Directory(entry) => ArchiveEntryPerReplica::Directory(entry.into()),
+                ReplicaV4::File(entry) => ArchiveEntryPerReplica::File(entry.into()),
+                ReplicaV4::Symlink(symlink) => ArchiveEntryPerReplica::Symlink(
+                    ArchiveSymlinkEntry::new(symlink.entry.into(), symlink.target),
+                ),
+            }
+        }
+    }
+}
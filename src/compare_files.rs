@@ -1,55 +1,87 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::hash::Hasher;
 use std::io;
+use std::io::Read;
 use std::path::Path;
 use std::process::Command;
 use std::process::Stdio;
+use twox_hash::XxHash64;
 
+/// How much of each file is read at a time, unless another size is given (see `SyncInfo::comparison_block_size`)
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Compares the contents of two files using the external `cmp` utility.
+/// Falls back to `file_contents_equal` if `cmp` isn't installed (it never is on Windows).
 pub fn file_contents_equal_cmd(a: &Path, b: &Path) -> io::Result<bool> {
+    file_contents_equal_with(OsStr::new("cmp"), a, b)
+}
+
+/// Like `file_contents_equal_cmd`, but runs `program` instead of `cmp`.
+/// `program` must exit with 0 if the files are the same, or 1 if they differ.
+pub fn file_contents_equal_with(program: &OsStr, a: &Path, b: &Path) -> io::Result<bool> {
     debug!("Comparing {:?} with {:?}", a, b);
-    Ok(Command::new("cmp")
+    let status = match Command::new(program)
         .stdout(Stdio::null())
         .arg(a)
         .arg(b)
-        .status()?
-        .code()
-        .unwrap()
-        == 0)
+        .status()
+    {
+        Ok(status) => status,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("{:?} not found, comparing the files directly", program);
+            return file_contents_equal(a, b);
+        }
+        Err(e) => return Err(e),
+    };
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        // eg: one of the files couldn't be read
+        Some(code) => Err(io::Error::other(format!(
+            "{:?} failed with exit code {}",
+            program, code
+        ))),
+        // killed by a signal
+        None => Err(io::Error::other(format!(
+            "{:?} was terminated ({})",
+            program, status
+        ))),
+    }
+}
+
+/// Compares the contents of two files block by block, without spawning any processes.
+/// Returns `false` as soon as a difference is found.
+pub fn file_contents_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    file_contents_equal_in_blocks(a, b, DEFAULT_BLOCK_SIZE)
 }
 
-/*pub fn file_contents_equal(a: &Path, b: &Path) -> io::Result<bool> {
+/// Like `file_contents_equal`, but reads `block_size` bytes of each file at a time.
+/// Larger blocks are faster on fast storage, at the cost of memory (two blocks are allocated).
+pub fn file_contents_equal_in_blocks(a: &Path, b: &Path, block_size: usize) -> io::Result<bool> {
     debug!("Comparing {:?} with {:?}", a, b);
-    let mut buf_a = vec![0; 4096];
-    let mut buf_b = vec![0; 4096];
-    let mut file_a = try!(File::open(a));
-    let mut file_b = try!(File::open(b));
-    let mut a_eof = false;
-    let mut b_eof = false;
+    let mut file_a = File::open(a)?;
+    let mut file_b = File::open(b)?;
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    // an empty block would never read anything
+    let block_size = block_size.max(1);
+    let mut buf_a = vec![0; block_size];
+    let mut buf_b = vec![0; block_size];
     let mut i = 0;
 
     loop {
-        match file_a.read_exact(&mut*buf_a) {
-            Ok(_) => {},
-            Err(e) => match e.kind() {
-                io::ErrorKind::UnexpectedEof => a_eof = true,
-                _ => return Err(e)
-            }
-        }
-         match file_b.read_exact(&mut*buf_b) {
-            Ok(_) => {},
-            Err(e) => match e.kind() {
-                io::ErrorKind::UnexpectedEof => b_eof = true,
-                _ => return Err(e)
-            }
-        }
+        let read_a = read_block(&mut file_a, &mut buf_a)?;
+        let read_b = read_block(&mut file_b, &mut buf_b)?;
 
-        if buf_a != buf_b {
+        // if one reaches eof and the other doesn't, then they aren't equal
+        if read_a != read_b || buf_a[..read_a] != buf_b[..read_b] {
             return Ok(false);
         }
-
-        // if one reaches eof and the other doesn't, then they aren't equal
-        match (a_eof, b_eof) {
-            (true, true) => return Ok(true),
-            (true, false) | (false, true) => return Ok(false),
-            _ => {}
+        if read_a == 0 {
+            return Ok(true);
         }
 
         i += 1;
@@ -57,4 +89,34 @@ pub fn file_contents_equal_cmd(a: &Path, b: &Path) -> io::Result<bool> {
             trace!("Read {} blocks", i);
         }
     }
-}*/
+}
+
+/// Computes a (non-cryptographic) 64-bit hash of the file's contents.
+pub fn file_hash(path: &Path) -> io::Result<u64> {
+    debug!("Hashing {:?}", path);
+    let mut file = File::open(path)?;
+    let mut hasher = XxHash64::with_seed(0);
+    let mut buf = vec![0; DEFAULT_BLOCK_SIZE];
+    loop {
+        let read = read_block(&mut file, &mut buf)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..read]);
+    }
+}
+
+/// Fills `buf` as much as possible, returning how many bytes were read.
+/// This is only less than `buf.len()` at the end of the file.
+pub(crate) fn read_block<R: Read>(read: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
@@ -1,23 +1,194 @@
 use generic_array::GenericArray;
+use glob::{MatchOptions, Pattern};
 use regex::Regex;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use typenum::U2;
 
+use crate::compare_files::DEFAULT_BLOCK_SIZE;
+use crate::error::SyncError;
 use crate::NumRoots;
 
+/// Which file metadata is used to tell whether a file has changed since it was recorded in the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DetectionMode {
+    /// Use the inode number and change time. This is the most reliable on local filesystems.
+    InoCtime,
+    /// Use the modification time and size. Useful for network mounts (SMB/NFS),
+    /// where inode numbers aren't stable between mounts.
+    MtimeSize,
+}
+
+/// How the contents of two files with the same size are compared during detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContentComparison {
+    /// Always compare the contents (see `SyncInfo::use_cmp` and `SyncInfo::compare_by_hash`).
+    Full,
+    /// Files with the same size are assumed to be identical, without reading them.
+    SizeOnly,
+    /// Files with the same size and modification time are assumed to be identical,
+    /// and the contents are only compared when the modification times differ.
+    ///
+    /// This is much faster than `Full` when most files are unchanged, but a file which was modified
+    /// without changing its size or modification time (eg: by a tool which restores the mtime) won't be noticed.
+    SizeAndMtime,
+}
+
 /// The configuration for the sync business.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SyncInfo<N: NumRoots = U2> {
     pub roots: GenericArray<PathBuf, N>,
     pub ignore: Ignore,
-    pub compare_file_contents: bool,
+    /// How to tell whether files with the same size have the same contents. Defaults to `ContentComparison::Full`.
+    pub content_comparison: ContentComparison,
+    /// Decides whether two files are equal instead of comparing their sizes and contents,
+    /// eg: to treat JSON files with reordered keys as the same (see `FileComparator`).
+    pub file_comparator: Option<FileComparator>,
+    /// Report files and directories whose unix permissions differ between replicas (eg: after a `chmod`),
+    /// even if their contents are the same. Propagating the difference only copies the permissions.
+    pub compare_permissions: bool,
+    /// Compare file contents by spawning the external `cmp` utility,
+    /// instead of reading the files in-process.
+    pub use_cmp: bool,
+    /// How many bytes of each file are read at a time when comparing their contents in-process.
+    /// Defaults to `compare_files::DEFAULT_BLOCK_SIZE` (64KiB); larger blocks can be faster on SSDs,
+    /// while smaller ones save memory (two blocks are allocated per comparison thread).
+    pub comparison_block_size: usize,
+    /// How many threads compare the items in each directory when file contents are being compared.
+    /// Defaults to the number of CPUs; set it to 1 to compare everything on the scanning thread
+    /// (eg: on a spinning disk, where reading several files at once is slower).
+    pub comparison_threads: usize,
+    /// Compare file contents by their hashes, which are cached in the archive
+    /// so that unchanged files don't need to be read again on the next run.
+    /// Doesn't apply to `ContentComparison::SizeOnly`.
+    pub compare_by_hash: bool,
+    /// How to detect whether a file has changed since the last sync
+    pub detection_mode: DetectionMode,
+    /// Detect files which were renamed on one replica, so they can be renamed on the others instead of copied.
+    /// When enabled, `reconcile::guess_operation` may return `Operation::Rename`.
+    pub detect_renames: bool,
+    /// Notice files which are hard links to each other, so that propagation can link them on the other replicas
+    /// instead of copying them twice (see `Difference::hard_link_of`). Only supported on unix.
+    pub detect_hard_links: bool,
+    /// Treat symlinks as whatever they point to, so that the contents of symlinked directories are synced
+    /// instead of the links themselves.
+    ///
+    /// Circular links (eg: a symlink pointing to one of its own ancestors) are only descended into once,
+    /// since directories whose canonical paths have already been scanned are skipped.
+    pub follow_symlinks: bool,
+    /// Files larger than this many bytes are skipped during detection.
+    ///
+    /// If a file is only too large on some replicas, it is still reported as a difference,
+    /// but `propagate` refuses to copy the oversized version with `SyncError::FileTooLarge`.
+    /// This only applies to individual files: the contents of a new directory are copied regardless of their size.
+    pub max_file_size: Option<u64>,
+    /// Files modified more recently than this are left out of detection, since they may still be being written.
+    /// They will be picked up by a later run, once they have settled.
+    pub min_age: Option<Duration>,
+    /// When true, directories which can't be read (eg: because of their permissions) are skipped
+    /// instead of failing the whole detection. They are listed in `DetectionStatistics::skipped_errors`.
+    pub continue_on_error: bool,
+    /// When true, roots which don't exist are created by `find_updates`, instead of failing with `SyncError::RootDoesntExist`.
+    ///
+    /// The archive is ignored when a root has just been created, so that a root which went missing (eg: an unmounted drive)
+    /// isn't mistaken for one where everything was deleted. Items which only exist on one other replica
+    /// are copied into the new root, as are files which are identical on several replicas,
+    /// but items which differ between replicas are reported as conflicts.
+    pub create_missing_roots: bool,
+    /// Checked by `detect::find_updates` before each directory, so that detection can be aborted from another thread
+    pub cancellation: CancellationToken,
 }
 
-#[derive(Debug)]
+/// A user-supplied test of whether two files are equal, called with the absolute paths of the files on two replicas.
+///
+/// This only runs for pairs of regular files, once the items have been found to have the same type on every replica,
+/// and replaces the size and contents checks (including `ContentComparison`, `use_cmp` and `compare_by_hash`).
+#[derive(Clone)]
+pub struct FileComparator(Arc<CompareFiles>);
+
+type CompareFiles = dyn Fn(&Path, &Path) -> io::Result<bool> + Send + Sync;
+
+impl FileComparator {
+    pub fn new<F>(compare: F) -> Self
+    where
+        F: Fn(&Path, &Path) -> io::Result<bool> + Send + Sync + 'static,
+    {
+        FileComparator(Arc::new(compare))
+    }
+
+    pub fn files_equal(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        (self.0)(a, b)
+    }
+}
+
+impl fmt::Debug for FileComparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FileComparator")
+    }
+}
+
+/// A flag shared between threads, used to abort long-running operations.
+///
+/// Clones share the same flag, so one clone can be kept (eg: by a cancel button) while another is handed to Ubiquity.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Requests that the operation is stopped as soon as possible.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns `SyncError::Cancelled` if the token has been cancelled.
+    pub fn check(&self) -> Result<(), SyncError> {
+        if self.is_cancelled() {
+            Err(SyncError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 /// Determines which files should be ignored when detecting updates.
+///
+/// A path is ignored if it matches any of `paths`, `regexes` or `globs`.
+/// Otherwise the `rules` are evaluated in order and the last one which matches decides:
+/// a negated rule re-includes the path, any other rule ignores it.
 pub struct Ignore {
     pub regexes: Vec<Regex>,
     pub paths: Vec<String>,
+    /// Glob patterns (eg: `*.tmp` or `build/**`), matched against paths relative to the replica root
+    pub globs: Vec<Pattern>,
+    /// Ordered, gitignore-style rules, usually read with `Ignore::from_ignore_file`
+    pub rules: Vec<IgnoreRule>,
+    /// Glob patterns matched against the absolute path of the item inside each root (eg: `/home/me/a/cache/**`),
+    /// so that a rule can apply to just one of the replicas. The item is ignored on every replica if it matches in any root.
+    ///
+    /// Unlike the other rules, these are evaluated once per root for every item scanned,
+    /// which means joining the path onto each root, so they are noticeably slower on large trees.
+    pub absolute_globs: Vec<Pattern>,
+    /// Ignore the case of paths when matching, which is useful on case-insensitive filesystems
+    pub case_insensitive: bool,
 }
 
 impl Ignore {
@@ -26,16 +197,272 @@ impl Ignore {
         Ignore {
             regexes: Vec::new(),
             paths: Vec::new(),
+            globs: Vec::new(),
+            rules: Vec::new(),
+            absolute_globs: Vec::new(),
+            case_insensitive: false,
         }
     }
+
+    /// Reads a gitignore-style file (such as `.gitignore` or `.syncignore`) into `rules`.
+    ///
+    /// Blank lines and lines starting with `#` are skipped, and lines starting with `!` are negated.
+    /// Later lines take precedence over earlier ones, so `!important.log` after `*.log` re-includes `important.log`.
+    /// As with git, a file can't be re-included if one of its parent directories is ignored,
+    /// because ignored directories are never scanned.
+    pub fn from_ignore_file(path: &Path) -> io::Result<Ignore> {
+        let contents = fs::read_to_string(path)?;
+        let mut ignore = Ignore::nothing();
+        for (number, line) in contents.lines().enumerate() {
+            if let Some(rule) = IgnoreRule::parse(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?}, line {}: {}", path, number + 1, e),
+                )
+            })? {
+                ignore.rules.push(rule);
+            }
+        }
+        Ok(ignore)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A single line of a gitignore-style file.
+pub struct IgnoreRule {
+    pub pattern: Pattern,
+    /// If true, paths matching this rule are re-included
+    pub negated: bool,
+    /// Which kinds of entries the rule applies to
+    pub applies_to: IgnoreTarget,
+}
+
+/// Restricts an `IgnoreRule` to directories or to everything else.
+///
+/// An entry counts as a directory if it is a directory on any of the replicas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreTarget {
+    Any,
+    /// Written with a trailing slash, eg: `build/`
+    DirOnly,
+    /// Files and symlinks
+    FileOnly,
+}
+
+impl IgnoreRule {
+    /// Parses a line of a gitignore-style file, returning `None` for blank lines and comments.
+    ///
+    /// Patterns without a slash match at any depth; patterns containing one are relative to the replica root.
+    pub fn parse(line: &str) -> Result<Option<IgnoreRule>, glob::PatternError> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        // a trailing slash means the rule only matches directories
+        let applies_to = if line.ends_with('/') {
+            IgnoreTarget::DirOnly
+        } else {
+            IgnoreTarget::Any
+        };
+        let line = line.trim_end_matches('/');
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        let pattern = if let Some(anchored) = line.strip_prefix('/') {
+            anchored.to_owned()
+        } else if line.contains('/') {
+            line.to_owned()
+        } else {
+            format!("**/{}", line)
+        };
+
+        Ok(Some(IgnoreRule {
+            pattern: Pattern::new(&pattern)?,
+            negated,
+            applies_to,
+        }))
+    }
+
+    /// Returns true if the rule matches the path, which should be relative to the replica root.
+    pub fn matches(&self, path: &Path, is_dir: bool, case_sensitive: bool) -> bool {
+        let applies = match self.applies_to {
+            IgnoreTarget::Any => true,
+            IgnoreTarget::DirOnly => is_dir,
+            IgnoreTarget::FileOnly => !is_dir,
+        };
+        applies
+            && self.pattern.matches_path_with(
+                path,
+                MatchOptions {
+                    case_sensitive,
+                    require_literal_separator: true,
+                    require_literal_leading_dot: false,
+                },
+            )
+    }
 }
 
 impl<N: NumRoots> SyncInfo<N> {
+    /// Starts building a `SyncInfo` one root at a time, eg:
+    ///
+    /// ```
+    /// # use ubiquity::config::SyncInfo;
+    /// let config: SyncInfo = SyncInfo::builder()
+    ///     .root("a")
+    ///     .root("b")
+    ///     .ignore_regex(r"\.DS_Store")
+    ///     .ignore_glob("*.tmp")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder() -> SyncInfoBuilder<N> {
+        SyncInfoBuilder {
+            roots: Vec::new(),
+            ignore: Ignore::nothing(),
+            content_comparison: ContentComparison::Full,
+            error: None,
+            _roots: PhantomData,
+        }
+    }
+
+    /// A copy of this configuration for a different set of roots, which may have a different length.
+    pub fn with_roots<M: NumRoots>(&self, roots: GenericArray<PathBuf, M>) -> SyncInfo<M> {
+        SyncInfo {
+            roots,
+            ignore: self.ignore.clone(),
+            content_comparison: self.content_comparison,
+            file_comparator: self.file_comparator.clone(),
+            compare_permissions: self.compare_permissions,
+            use_cmp: self.use_cmp,
+            comparison_block_size: self.comparison_block_size,
+            comparison_threads: self.comparison_threads,
+            compare_by_hash: self.compare_by_hash,
+            detection_mode: self.detection_mode,
+            detect_renames: self.detect_renames,
+            detect_hard_links: self.detect_hard_links,
+            follow_symlinks: self.follow_symlinks,
+            max_file_size: self.max_file_size,
+            min_age: self.min_age,
+            continue_on_error: self.continue_on_error,
+            create_missing_roots: self.create_missing_roots,
+            cancellation: self.cancellation.clone(),
+        }
+    }
+
     pub fn new(roots: GenericArray<PathBuf, N>) -> Self {
         SyncInfo {
             roots,
             ignore: Ignore::nothing(),
-            compare_file_contents: true,
+            content_comparison: ContentComparison::Full,
+            file_comparator: None,
+            compare_permissions: false,
+            use_cmp: false,
+            comparison_block_size: DEFAULT_BLOCK_SIZE,
+            comparison_threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            compare_by_hash: false,
+            detection_mode: DetectionMode::InoCtime,
+            detect_renames: false,
+            detect_hard_links: false,
+            follow_symlinks: false,
+            max_file_size: None,
+            min_age: None,
+            continue_on_error: false,
+            create_missing_roots: false,
+            cancellation: CancellationToken::new(),
+        }
+    }
+}
+
+/// Builds a `SyncInfo` without writing out a `GenericArray` of roots (see `SyncInfo::builder`).
+///
+/// Anything not set here can still be changed on the `SyncInfo` returned by `build`.
+#[derive(Debug)]
+pub struct SyncInfoBuilder<N: NumRoots = U2> {
+    roots: Vec<PathBuf>,
+    ignore: Ignore,
+    content_comparison: ContentComparison,
+    /// The first invalid pattern, which is returned by `build`
+    error: Option<SyncError>,
+    _roots: PhantomData<N>,
+}
+
+impl<N: NumRoots> SyncInfoBuilder<N> {
+    /// Adds the next root. There must be exactly `N` of them by the time `build` is called.
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.roots.push(root.into());
+        self
+    }
+
+    /// Ignores this path (relative to the roots) and everything inside it.
+    pub fn ignore_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.ignore.paths.push(path.into());
+        self
+    }
+
+    /// Ignores paths which match this regex.
+    pub fn ignore_regex(mut self, regex: &str) -> Self {
+        match Regex::new(regex) {
+            Ok(regex) => self.ignore.regexes.push(regex),
+            Err(e) => self.fail(regex, e.to_string()),
+        }
+        self
+    }
+
+    /// Ignores paths which match this glob (eg: `*.tmp`), relative to the roots.
+    pub fn ignore_glob(mut self, glob: &str) -> Self {
+        match Pattern::new(glob) {
+            Ok(glob) => self.ignore.globs.push(glob),
+            Err(e) => self.fail(glob, e.to_string()),
+        }
+        self
+    }
+
+    /// Ignores the case of paths when matching the ignore rules.
+    pub fn ignore_case(mut self, case_insensitive: bool) -> Self {
+        self.ignore.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Whether the contents of files with the same size are compared (`ContentComparison::Full`),
+    /// or they are assumed to be identical (`ContentComparison::SizeOnly`).
+    pub fn compare_contents(mut self, compare_contents: bool) -> Self {
+        self.content_comparison = if compare_contents {
+            ContentComparison::Full
+        } else {
+            ContentComparison::SizeOnly
+        };
+        self
+    }
+
+    /// Returns `SyncError::WrongNumberOfRoots` unless exactly `N` roots were added,
+    /// or `SyncError::InvalidIgnorePattern` if one of the regexes or globs was invalid.
+    pub fn build(self) -> Result<SyncInfo<N>, SyncError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let found = self.roots.len();
+        let roots =
+            GenericArray::from_exact_iter(self.roots).ok_or(SyncError::WrongNumberOfRoots {
+                expected: N::to_usize(),
+                found,
+            })?;
+        let mut config = SyncInfo::new(roots);
+        config.ignore = self.ignore;
+        config.content_comparison = self.content_comparison;
+        Ok(config)
+    }
+
+    fn fail(&mut self, pattern: &str, reason: String) {
+        if self.error.is_none() {
+            self.error = Some(SyncError::InvalidIgnorePattern {
+                pattern: pattern.to_owned(),
+                reason,
+            });
         }
     }
 }
@@ -0,0 +1,72 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::encoding;
+
+use crate::detect::{DetectionStatistics, Difference, SearchDirectories};
+use crate::error::{DescribeIoError, SyncError};
+use crate::NumRoots;
+
+/// Everything `find_updates_resumable` needs to carry on where it left off.
+///
+/// The archive already records every item found to be in sync, so only the directories which haven't been scanned
+/// (and the differences found in those which have) need to be kept.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Checkpoint<N: NumRoots> {
+    pub directories: Vec<PathBuf>,
+    pub recurse: bool,
+    pub differences: Vec<Difference<N>>,
+    pub archive_hits: usize,
+    pub archive_additions: usize,
+    pub directories_scanned: usize,
+}
+
+impl<N: NumRoots> Checkpoint<N> {
+    pub fn new(
+        search: &SearchDirectories,
+        differences: Vec<Difference<N>>,
+        statistics: &DetectionStatistics,
+    ) -> Self {
+        Checkpoint {
+            directories: search.directories.clone(),
+            recurse: search.recurse,
+            differences,
+            archive_hits: statistics.archive_hits,
+            archive_additions: statistics.archive_additions,
+            directories_scanned: statistics.directories_scanned,
+        }
+    }
+
+    /// Reads the checkpoint at `path`, if there is one.
+    pub fn read(path: &Path) -> Result<Option<Self>, SyncError> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err((e, format!("while opening checkpoint {:?}", path)).into()),
+        };
+        let checkpoint = encoding()
+            .deserialize_from(io::BufReader::new(file))
+            .map_err(io::Error::other)
+            .describe(|| format!("while reading checkpoint {:?}", path))?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Saves the checkpoint to `path`, replacing any previous one only once it has been written in full.
+    pub fn write(&self, path: &Path) -> Result<(), SyncError> {
+        let temporary = path.with_extension("tmp");
+        let file = fs::File::create(&temporary)
+            .describe(|| format!("while creating checkpoint {:?}", temporary))?;
+        encoding()
+            .serialize_into(io::BufWriter::new(file), self)
+            .map_err(io::Error::other)
+            .describe(|| format!("while writing checkpoint {:?}", temporary))?;
+        fs::rename(&temporary, path)
+            .describe(|| format!("while replacing checkpoint {:?}", path))?;
+        Ok(())
+    }
+}
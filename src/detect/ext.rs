@@ -1,18 +1,24 @@
 use generic_array::GenericArray;
-use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::panic;
+use std::path::Path;
+use std::thread;
 
-use crate::compare_files::file_contents_equal_cmd;
+use crate::compare_files::{file_contents_equal_cmd, file_contents_equal_in_blocks, file_hash};
+use crate::config::{ContentComparison, SyncInfo};
 use crate::error::SyncError;
 use crate::state::ArchiveEntryPerReplica;
 use crate::NumRoots;
 
 pub fn is_item_in_sync<N: NumRoots>(
     path: &Path,
-    current_entry: &GenericArray<ArchiveEntryPerReplica, N>,
-    compare_file_contents: bool,
-    roots: &GenericArray<PathBuf, N>,
+    current_entry: &mut GenericArray<ArchiveEntryPerReplica, N>,
+    previous_state: Option<&GenericArray<ArchiveEntryPerReplica, N>>,
+    config: &SyncInfo<N>,
 ) -> Result<bool, SyncError> {
+    let roots = &config.roots;
+    // this comes before any content comparison, so that a file and a symlink whose target
+    // has the same contents are always a difference
     trace!("Checking for incompatible entry types (eg: file vs folder vs empty)");
     // loop through 'abcdef' like: ab bc cd de ef
     for entry_window in current_entry.windows(2) {
@@ -23,12 +29,54 @@ pub fn is_item_in_sync<N: NumRoots>(
         }
     }
 
+    if config.compare_permissions {
+        trace!("Checking permissions");
+        for entry_window in current_entry.windows(2) {
+            if entry_window[0].permissions_mode() != entry_window[1].permissions_mode() {
+                warn!("Difference at path {:?} - permissions not equal", path);
+                return Ok(false);
+            }
+        }
+    }
+
+    trace!("Checking for different symlink targets");
+    for entry_window in current_entry.windows(2) {
+        if let (ArchiveEntryPerReplica::Symlink(a), ArchiveEntryPerReplica::Symlink(b)) =
+            (&entry_window[0], &entry_window[1])
+        {
+            if a.target() != b.target() {
+                warn!(
+                    "Difference at path {:?} - symlink targets not equal: {:?} != {:?}",
+                    path,
+                    a.target(),
+                    b.target()
+                );
+                return Ok(false);
+            }
+        }
+    }
+
+    // symlinks are fully described by their targets, so only regular files are compared from here
+    if let Some(ref comparator) = config.file_comparator {
+        trace!("Comparing files with the user-supplied comparator");
+        for (entry_window, roots) in current_entry.windows(2).zip(roots.windows(2)) {
+            if is_file(&entry_window[0])
+                && is_file(&entry_window[1])
+                && !comparator.files_equal(&roots[0].join(path), &roots[1].join(path))?
+            {
+                warn!("Difference at path {:?} - files not equal", path);
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
     trace!("Checking for different file sizes");
     for (entry_window, roots) in current_entry.windows(2).zip(roots.windows(2)) {
         // if the sizes are different
-        if entry_window[0].is_file_or_symlink() && entry_window[1].is_file_or_symlink() {
-            let size_0 = roots[0].join(path).metadata()?.size();
-            let size_1 = roots[1].join(path).metadata()?.size();
+        if is_file(&entry_window[0]) && is_file(&entry_window[1]) {
+            let size_0 = roots[0].join(path).metadata()?.len();
+            let size_1 = roots[1].join(path).metadata()?.len();
             if size_0 != size_1 {
                 warn!(
                     "Difference at path {:?} - file sizes not equal: {} != {}",
@@ -39,13 +87,37 @@ pub fn is_item_in_sync<N: NumRoots>(
         }
     }
 
+    let compare_contents = match config.content_comparison {
+        ContentComparison::Full => true,
+        ContentComparison::SizeOnly => false,
+        ContentComparison::SizeAndMtime => {
+            trace!("Checking file modification times");
+            !current_entry.windows(2).all(|entry_window| {
+                !is_file(&entry_window[0])
+                    || entry_window[0].modification_time() == entry_window[1].modification_time()
+            })
+        }
+    };
+
     // If they are both files, we will compare the contents
-    if compare_file_contents {
+    if compare_contents && config.compare_by_hash {
+        trace!("Checking file hashes");
+        fill_content_hashes(path, current_entry, previous_state, config)?;
+        for entry_window in current_entry.windows(2) {
+            if is_file(&entry_window[0])
+                && is_file(&entry_window[1])
+                && entry_window[0].content_hash() != entry_window[1].content_hash()
+            {
+                warn!("Difference at path {:?} - file hashes not equal", path);
+                return Ok(false);
+            }
+        }
+    } else if compare_contents {
         trace!("Checking file contents");
         for (entry_window, roots) in current_entry.windows(2).zip(roots.windows(2)) {
-            if entry_window[0].is_file_or_symlink()
-                && entry_window[1].is_file_or_symlink()
-                && !file_contents_equal_cmd(&roots[0].join(path), &roots[1].join(path))?
+            if is_file(&entry_window[0])
+                && is_file(&entry_window[1])
+                && !contents_equal(&roots[0].join(path), &roots[1].join(path), config)?
             {
                 warn!("Difference at path {:?} - file contents not equal", path);
                 return Ok(false);
@@ -55,3 +127,203 @@ pub fn is_item_in_sync<N: NumRoots>(
 
     Ok(true)
 }
+
+/// Checks whether a file or symlink is the same on every replica it exists on (and exists on more than one),
+/// comparing contents according to `config.content_comparison`.
+/// Used when there is no archive entry, to tell a conflict apart from an item which is only missing on some replicas.
+pub fn present_replicas_agree<N: NumRoots>(
+    path: &Path,
+    current_entry: &GenericArray<ArchiveEntryPerReplica, N>,
+    config: &SyncInfo<N>,
+) -> Result<bool, SyncError> {
+    let present: Vec<usize> = (0..current_entry.len())
+        .filter(|&i| current_entry[i].entry_exists())
+        .collect();
+    replicas_agree(path, current_entry, &present, config)
+}
+
+/// Checks whether every replica which changed since the last sync (and there is more than one)
+/// now has the same file or symlink, so that it doesn't matter which of them is propagated.
+/// A deletion never agrees with anything here, since `reconcile::guess_operation` already handles those.
+pub fn changed_replicas_agree<N: NumRoots>(
+    path: &Path,
+    current_entry: &GenericArray<ArchiveEntryPerReplica, N>,
+    previous_state: &GenericArray<ArchiveEntryPerReplica, N>,
+    config: &SyncInfo<N>,
+) -> Result<bool, SyncError> {
+    let changed: Vec<usize> = (0..current_entry.len())
+        .filter(|&i| current_entry[i] != previous_state[i])
+        .collect();
+    if changed.iter().any(|&i| !current_entry[i].entry_exists()) {
+        return Ok(false);
+    }
+    replicas_agree(path, current_entry, &changed, config)
+}
+
+/// Compares the file or symlink on each of `replicas` with the next, like `is_item_in_sync` does for every replica.
+fn replicas_agree<N: NumRoots>(
+    path: &Path,
+    current_entry: &GenericArray<ArchiveEntryPerReplica, N>,
+    replicas: &[usize],
+    config: &SyncInfo<N>,
+) -> Result<bool, SyncError> {
+    if replicas.len() < 2 {
+        return Ok(false);
+    }
+    for pair in replicas.windows(2) {
+        let (a, b) = (&current_entry[pair[0]], &current_entry[pair[1]]);
+        match (a, b) {
+            (ArchiveEntryPerReplica::Symlink(a), ArchiveEntryPerReplica::Symlink(b)) => {
+                if a.target() != b.target() {
+                    return Ok(false);
+                }
+            }
+            (ArchiveEntryPerReplica::File(_), ArchiveEntryPerReplica::File(_)) => {
+                if config.compare_permissions && a.permissions_mode() != b.permissions_mode() {
+                    return Ok(false);
+                }
+                let (path_a, path_b) = (
+                    config.roots[pair[0]].join(path),
+                    config.roots[pair[1]].join(path),
+                );
+                let equal = if let Some(ref comparator) = config.file_comparator {
+                    comparator.files_equal(&path_a, &path_b)?
+                } else {
+                    let compare_contents = match config.content_comparison {
+                        ContentComparison::Full => true,
+                        ContentComparison::SizeOnly => false,
+                        ContentComparison::SizeAndMtime => {
+                            a.modification_time() != b.modification_time()
+                        }
+                    };
+                    a.file_size() == b.file_size()
+                        && (!compare_contents || contents_equal(&path_a, &path_b, config)?)
+                };
+                if !equal {
+                    return Ok(false);
+                }
+            }
+            // directories may have different contents, and different types never agree
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// An item in the directory being scanned which the archive couldn't vouch for, so it has to be compared.
+pub struct PendingCheck<'a, N: NumRoots> {
+    pub path: &'a Path,
+    pub current_entry: &'a mut GenericArray<ArchiveEntryPerReplica, N>,
+    pub previous_state: Option<&'a GenericArray<ArchiveEntryPerReplica, N>>,
+    /// The difference won't have a previous state, so `present_replicas_agree` is needed if the item isn't in sync
+    pub without_history: bool,
+}
+
+/// The outcome of a `PendingCheck`
+pub struct ItemCheck {
+    pub in_sync: bool,
+    pub identical_where_present: bool,
+    pub changed_replicas_agree: bool,
+}
+
+/// Runs `is_item_in_sync` on every pending item, returning the results in the same order.
+///
+/// When file contents are compared, the items are split between `SyncInfo::comparison_threads` threads,
+/// since each comparison only reads its own files.
+pub fn check_items_in_sync<N: NumRoots>(
+    mut pending: Vec<PendingCheck<N>>,
+    config: &SyncInfo<N>,
+) -> Vec<Result<ItemCheck, SyncError>> {
+    let compares_contents = config.file_comparator.is_some()
+        || config.content_comparison != ContentComparison::SizeOnly;
+    let threads = config.comparison_threads.min(pending.len());
+    if !compares_contents || threads <= 1 {
+        return pending
+            .iter_mut()
+            .map(|check| check_item(check, config))
+            .collect();
+    }
+
+    let chunk_size = pending.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let workers: Vec<_> = pending
+            .chunks_mut(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter_mut()
+                        .map(|check| check_item(check, config))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+fn check_item<N: NumRoots>(
+    check: &mut PendingCheck<N>,
+    config: &SyncInfo<N>,
+) -> Result<ItemCheck, SyncError> {
+    let in_sync = is_item_in_sync(
+        check.path,
+        check.current_entry,
+        check.previous_state,
+        config,
+    )?;
+    let identical_where_present = !in_sync
+        && check.without_history
+        && present_replicas_agree(check.path, check.current_entry, config)?;
+    let changed_replicas_agree = match check.previous_state {
+        Some(previous_state) if !in_sync && !check.without_history => {
+            changed_replicas_agree(check.path, check.current_entry, previous_state, config)?
+        }
+        _ => false,
+    };
+    Ok(ItemCheck {
+        in_sync,
+        identical_where_present,
+        changed_replicas_agree,
+    })
+}
+
+/// Compares the contents of two files with `cmp` or in-process, as configured.
+fn contents_equal<N: NumRoots>(a: &Path, b: &Path, config: &SyncInfo<N>) -> io::Result<bool> {
+    if config.use_cmp {
+        file_contents_equal_cmd(a, b)
+    } else {
+        file_contents_equal_in_blocks(a, b, config.comparison_block_size)
+    }
+}
+
+fn is_file(entry: &ArchiveEntryPerReplica) -> bool {
+    matches!(*entry, ArchiveEntryPerReplica::File(_))
+}
+
+/// Computes the content hash of every file in `current_entry`,
+/// reusing hashes from the archive for files which haven't changed since.
+fn fill_content_hashes<N: NumRoots>(
+    path: &Path,
+    current_entry: &mut GenericArray<ArchiveEntryPerReplica, N>,
+    previous_state: Option<&GenericArray<ArchiveEntryPerReplica, N>>,
+    config: &SyncInfo<N>,
+) -> Result<(), SyncError> {
+    for (i, entry) in current_entry.iter_mut().enumerate() {
+        if !is_file(entry) || entry.content_hash().is_some() {
+            continue;
+        }
+        let cached = previous_state
+            .map(|previous| &previous[i])
+            .filter(|previous| *previous == entry)
+            .and_then(|previous| previous.content_hash());
+        let hash = match cached {
+            Some(hash) => hash,
+            None => file_hash(&config.roots[i].join(path))?,
+        };
+        entry.set_content_hash(hash);
+    }
+    Ok(())
+}
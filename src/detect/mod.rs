@@ -1,19 +1,35 @@
 use generic_array::GenericArray;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
 
 use crate::archive::{Archive, ArchiveEntries};
-use crate::config::SyncInfo;
-use crate::detect::ext::is_item_in_sync;
+use crate::config::{DetectionMode, SyncInfo};
+use crate::detect::checkpoint::Checkpoint;
+use crate::detect::ext::{check_items_in_sync, PendingCheck};
+use crate::detect::rename::pair_renames;
 use crate::detect::util::*;
-use crate::error::SyncError;
+use crate::error::{DescribeIoError, SyncError};
 use crate::state::ArchiveEntryPerReplica;
-use crate::util::FnvHashMap;
+use crate::util::{check_roots_distinct, FnvHashMap, FnvHashSet};
 use crate::NumRoots;
+use crate::ReplicaIndex;
 
 use serde::{Deserialize, Serialize};
 
+mod checkpoint;
 mod ext;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod rename;
 mod util;
+mod verify;
+
+#[cfg(feature = "async")]
+pub use crate::detect::nonblocking::find_updates_async;
+pub use crate::detect::verify::verify_in_sync;
 
 /// An instance of this struct represents the files/folders differ.
 /// There may be a suggested action to be taken.
@@ -42,12 +58,150 @@ pub struct Difference<N: NumRoots> {
     ))]
     /// The current state of the files
     pub current_state: GenericArray<ArchiveEntryPerReplica, N>,
+
+    /// How the states were read from the filesystem
+    pub detection_mode: DetectionMode,
+
+    /// If the item was renamed on one replica, the path it used to have.
+    /// Only set when `SyncInfo::detect_renames` is enabled.
+    #[serde(default)]
+    pub renamed_from: Option<PathBuf>,
+
+    /// Whether symlinks were followed when reading the states (see `SyncInfo::follow_symlinks`)
+    #[serde(default)]
+    pub follow_symlinks: bool,
+
+    /// Files larger than this won't be copied (see `SyncInfo::max_file_size`)
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+
+    /// For each replica, another path (relative to the root) which is a hard link to the same file, if one was seen earlier
+    /// in the scan. Empty unless `SyncInfo::detect_hard_links` is enabled.
+    #[serde(default)]
+    pub hard_link_of: Vec<Option<PathBuf>>,
+
+    /// Set when there is no `previous_state`, but the item is a file or symlink which is identical on every replica
+    /// it exists on, so that it can be copied from any of them rather than being reported as a conflict.
+    #[serde(default)]
+    pub identical_where_present: bool,
+
+    /// Set when the item changed on more than one replica since the last sync, but every replica it changed on
+    /// now has the same file or symlink, so that any of them can be propagated rather than it being reported as a conflict.
+    #[serde(default)]
+    pub changed_replicas_agree: bool,
 }
 
 impl<N: NumRoots> Difference<N> {
+    /// Builds a difference from states that were obtained some other way (eg: from filesystem events),
+    /// with the same defaults as `SyncInfo::new`.
+    pub fn new(
+        path: PathBuf,
+        roots: GenericArray<PathBuf, N>,
+        previous_state: Option<GenericArray<ArchiveEntryPerReplica, N>>,
+        current_state: GenericArray<ArchiveEntryPerReplica, N>,
+    ) -> Self {
+        Difference {
+            path,
+            roots,
+            previous_state,
+            current_state,
+            detection_mode: DetectionMode::InoCtime,
+            renamed_from: None,
+            follow_symlinks: false,
+            max_file_size: None,
+            hard_link_of: Vec::new(),
+            identical_where_present: false,
+            changed_replicas_agree: false,
+        }
+    }
+
+    /// Like `Difference::new`, but reads the current state of `path` from each of the roots.
+    pub fn from_paths(
+        path: PathBuf,
+        roots: GenericArray<PathBuf, N>,
+        previous_state: Option<GenericArray<ArchiveEntryPerReplica, N>>,
+    ) -> Self {
+        let current_state =
+            ArchiveEntryPerReplica::from_roots::<N>(&roots, &path, DetectionMode::InoCtime, false);
+        Difference::new(path, roots, previous_state, current_state)
+    }
+
     pub fn absolute_path_for_root(&self, index: usize) -> PathBuf {
         self.roots[index].join(&self.path)
     }
+
+    /// When the item was last modified on each replica, or `None` where it doesn't exist.
+    /// These come from the detected state, so the filesystem isn't read again.
+    pub fn replica_mtimes(&self) -> GenericArray<Option<SystemTime>, N> {
+        self.current_state
+            .iter()
+            .map(ArchiveEntryPerReplica::modified)
+            .collect()
+    }
+
+    /// Describes what happened to the item since the last sync, by comparing `current_state` with `previous_state`.
+    /// Without a previous state the item is treated as new on every replica it exists on.
+    pub fn classify(&self) -> DifferenceKind {
+        let mut created = Vec::new();
+        let mut deleted = Vec::new();
+        let mut modified = false;
+        for (i, current) in self.current_state.iter().enumerate() {
+            let previous = match self.previous_state {
+                Some(ref previous_state) => &previous_state[i],
+                None => &ArchiveEntryPerReplica::Empty,
+            };
+            if current == previous {
+                continue;
+            }
+            match (previous.entry_exists(), current.entry_exists()) {
+                (false, _) => created.push(i),
+                (_, false) => deleted.push(i),
+                _ if !ArchiveEntryPerReplica::equal_ty(previous, current) => {
+                    return DifferenceKind::TypeChanged
+                }
+                _ => modified = true,
+            }
+        }
+
+        match (created.len(), deleted.first()) {
+            (1, None) if !modified => DifferenceKind::Created {
+                replica: created[0],
+            },
+            (0, Some(&replica)) if !modified => DifferenceKind::Deleted { replica },
+            (n, None) if n > 1 && !modified => DifferenceKind::ConflictingCreate,
+            _ => DifferenceKind::Modified,
+        }
+    }
+}
+
+/// What happened to an item since the last sync, as returned by `Difference::classify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DifferenceKind {
+    /// The item was created on this replica, and doesn't exist on any other
+    Created { replica: ReplicaIndex },
+    /// The item was deleted from this replica (and possibly others, in which case this is the first),
+    /// and is unchanged everywhere else
+    Deleted { replica: ReplicaIndex },
+    /// The item was changed without changing its type, or it was changed in several different ways
+    /// (eg: modified on one replica and deleted on another)
+    Modified,
+    /// The item was replaced by one of a different type on at least one replica (eg: a file by a directory)
+    TypeChanged,
+    /// The item was created independently on more than one replica
+    ConflictingCreate,
+}
+
+#[cfg(feature = "json")]
+impl<N: NumRoots> Difference<N> {
+    /// Serializes the difference as JSON, eg: to send it to a web frontend or a structured log.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Reads a difference written by `to_json`.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
 }
 
 /// The result of update detection
@@ -114,24 +268,52 @@ impl SearchDirectories {
             recurse,
         }
     }
+
+    /// Builds a non-recursive search of the directories containing `paths`, which are absolute paths
+    /// inside one of the `roots` (eg: from a filesystem watcher such as the `notify` crate).
+    ///
+    /// Paths outside of every root are skipped.
+    pub fn from_changed_paths<I: IntoIterator<Item = PathBuf>>(
+        paths: I,
+        roots: &[PathBuf],
+    ) -> SearchDirectories {
+        let mut directories: Vec<PathBuf> = Vec::new();
+        let mut seen: FnvHashSet<PathBuf> = Default::default();
+        for path in paths {
+            let relative = match relative_to_roots(&path, roots) {
+                Some(relative) => relative,
+                None => {
+                    debug!("{:?} isn't inside any of the roots", path);
+                    continue;
+                }
+            };
+            // the changed item is checked when its parent is searched
+            let directory = relative.parent().unwrap_or(&relative).to_path_buf();
+            if seen.insert(directory.clone()) {
+                directories.push(directory);
+            }
+        }
+        SearchDirectories::new(directories, false)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 /// Basic statistics about the accuracy of archives during the detection process.
 pub struct DetectionStatistics {
     /// The number of times the archives were up to date and reported no change.
     pub archive_hits: usize,
     /// The number of times the archives had to be added to.
     pub archive_additions: usize,
-}
-
-impl Default for DetectionStatistics {
-    fn default() -> Self {
-        DetectionStatistics {
-            archive_hits: 0,
-            archive_additions: 0,
-        }
-    }
+    /// The number of differences found.
+    pub differences: usize,
+    /// The number of directories which were scanned.
+    pub directories_scanned: usize,
+    /// The total size of the files that would be copied if every difference
+    /// were resolved with `reconcile::guess_operation`.
+    pub bytes_to_transfer: u64,
+    /// Directories which couldn't be read and were skipped, along with the reason.
+    /// Only used when `SyncInfo::continue_on_error` is set.
+    pub skipped_errors: Vec<(PathBuf, SyncError)>,
 }
 
 impl DetectionStatistics {
@@ -143,7 +325,27 @@ impl DetectionStatistics {
 /// Progress reporting for the update detection process.
 pub trait ProgressCallback {
     /// Called when a new directory is being searched.
+    ///
+    /// `remaining` only counts the directories discovered so far, so it grows as subdirectories are found.
+    /// For a total which doesn't change during the scan, see `estimate_directory_count`.
     fn reading_directory(&self, path: &Path, checked: usize, remaining: usize);
+
+    /// Called just after `reading_directory`, to decide whether the directory is scanned (eg: so that a user
+    /// can deselect parts of the tree while the scan is running). Continues by default.
+    fn scan_control(&self, _path: &Path) -> ScanControl {
+        ScanControl::Continue
+    }
+}
+
+/// What `find_updates` should do with a directory, as decided by `ProgressCallback::scan_control`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanControl {
+    Continue,
+    /// Don't look inside the directory: none of its items or subdirectories are compared,
+    /// and its archive entries are left as they are.
+    SkipChildren,
+    /// Stop detection, which fails with `SyncError::Cancelled`
+    Abort,
 }
 
 /// An empty implementation of `ProgressCallback`
@@ -170,93 +372,430 @@ where
     N: NumRoots,
     P: ProgressCallback,
 {
+    let mut result = DetectionResult::new();
+    let mut differences = Vec::new();
+    scan_search_directories(
+        archive,
+        search,
+        config,
+        progress_callback,
+        &mut result.statistics,
+        |batch| {
+            differences.extend(batch);
+            Ok(())
+        },
+    )?;
+    for difference in differences {
+        result.add_difference(difference);
+    }
+
+    if config.detect_renames {
+        pair_renames(&mut result)?;
+    }
+
+    for difference in &result.differences {
+        count_difference(&mut result.statistics, difference);
+    }
+
+    Ok(result)
+}
+
+/// Like `find_updates`, but if it is interrupted (eg: cancelled through `SyncInfo::cancellation`, or by an error),
+/// the directories which haven't been scanned yet are saved to `checkpoint`, along with the differences found so far.
+/// The next call with the same checkpoint carries on from there instead of searching `search`,
+/// so that a huge tree can be scanned over several runs.
+///
+/// The checkpoint is removed once the scan completes. It is only written on the way out of this function,
+/// so if the process is killed the scan starts again (although the archive makes the repeated directories quick).
+pub fn find_updates_resumable<N, P>(
+    archive: &Archive,
+    search: &mut SearchDirectories,
+    config: &SyncInfo<N>,
+    progress_callback: &P,
+    checkpoint: &Path,
+) -> Result<DetectionResult<N>, SyncError>
+where
+    N: NumRoots,
+    P: ProgressCallback,
+{
+    let mut result = DetectionResult::new();
+    let mut differences = Vec::new();
+    let mut previously_scanned = 0;
+    if let Some(saved) = Checkpoint::<N>::read(checkpoint)? {
+        info!(
+            "Resuming from {:?}, with {} directories left to scan",
+            checkpoint,
+            saved.directories.len()
+        );
+        *search = SearchDirectories::new(saved.directories, saved.recurse);
+        differences = saved.differences;
+        result.statistics.archive_hits = saved.archive_hits;
+        result.statistics.archive_additions = saved.archive_additions;
+        previously_scanned = saved.directories_scanned;
+    }
+
+    let scanned = scan_search_directories(
+        archive,
+        search,
+        config,
+        progress_callback,
+        &mut result.statistics,
+        |batch| {
+            differences.extend(batch);
+            Ok(())
+        },
+    );
+    result.statistics.directories_scanned += previously_scanned;
+    if let Err(e) = scanned {
+        info!(
+            "Saving {} remaining directories to {:?}",
+            search.directories.len(),
+            checkpoint
+        );
+        if let Err(checkpoint_error) =
+            Checkpoint::new(search, differences, &result.statistics).write(checkpoint)
+        {
+            warn!("Couldn't save checkpoint: {}", checkpoint_error);
+        }
+        return Err(e);
+    }
+    match fs::remove_file(checkpoint) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        removed => removed.describe(|| format!("while removing checkpoint {:?}", checkpoint))?,
+    }
+
+    for difference in differences {
+        result.add_difference(difference);
+    }
+    if config.detect_renames {
+        pair_renames(&mut result)?;
+    }
+    for difference in &result.differences {
+        count_difference(&mut result.statistics, difference);
+    }
+
+    Ok(result)
+}
+
+/// Like `find_updates`, but hands each difference to `on_difference` as soon as its directory has been scanned,
+/// instead of collecting them all in memory.
+///
+/// Nested differences are only suppressed within a single directory, so if the search directories overlap
+/// a difference may be passed to `on_difference` as well as one of its descendants.
+/// Likewise, renames are only detected when both names are in the same directory.
+pub fn find_updates_streaming<N, P, F>(
+    archive: &Archive,
+    search: &mut SearchDirectories,
+    config: &SyncInfo<N>,
+    progress_callback: &P,
+    mut on_difference: F,
+) -> Result<DetectionStatistics, SyncError>
+where
+    N: NumRoots,
+    P: ProgressCallback,
+    F: FnMut(Difference<N>) -> Result<(), SyncError>,
+{
+    let mut statistics = DetectionStatistics::new();
+    let mut counts = DetectionStatistics::new();
+    scan_search_directories(
+        archive,
+        search,
+        config,
+        progress_callback,
+        &mut statistics,
+        |batch| {
+            let mut result = DetectionResult::new();
+            for difference in batch {
+                result.add_difference(difference);
+            }
+            if config.detect_renames {
+                pair_renames(&mut result)?;
+            }
+            for difference in result.differences {
+                count_difference(&mut counts, &difference);
+                on_difference(difference)?;
+            }
+            Ok(())
+        },
+    )?;
+    statistics.differences = counts.differences;
+    statistics.bytes_to_transfer = counts.bytes_to_transfer;
+
+    Ok(statistics)
+}
+
+/// Counts the directories `find_updates` will scan, so that progress can be shown as a fraction of a fixed total.
+///
+/// This reads every directory in the replicas once more before detection starts (without comparing anything),
+/// which is the price of a progress bar that doesn't jump backwards as `remaining` grows.
+/// It's only an estimate: directories which differ between replicas are counted,
+/// even though `find_updates` doesn't descend into them, and the replicas may change in between.
+pub fn estimate_directory_count<N: NumRoots>(
+    search: &SearchDirectories,
+    config: &SyncInfo<N>,
+) -> Result<usize, SyncError> {
+    let ignore = IgnoreMatcher::new(&config.ignore, &config.roots);
+    let mut directories: FnvHashSet<PathBuf> = Default::default();
+    for directory in &search.directories {
+        let directory = if directory.is_absolute() {
+            relative_to_roots(directory, &config.roots)
+                .ok_or_else(|| SyncError::AbsolutePathProvided(directory.clone()))?
+        } else {
+            directory.clone()
+        };
+        if ignore.is_ignored(&directory, true) {
+            continue;
+        }
+        directories.insert(directory.clone());
+        if !search.recurse {
+            continue;
+        }
+
+        for root in config.roots.iter() {
+            let walker = WalkDir::new(root.join(&directory))
+                .min_depth(1)
+                .follow_links(config.follow_symlinks)
+                .into_iter()
+                .filter_entry(|entry| {
+                    entry.file_type().is_dir()
+                        && !ignore.is_ignored(entry.path().strip_prefix(root).unwrap(), true)
+                });
+            for entry in walker {
+                config.cancellation.check()?;
+                match entry {
+                    Ok(entry) => {
+                        directories.insert(entry.path().strip_prefix(root).unwrap().to_path_buf());
+                    }
+                    // eg: the directory is missing from this replica, or a symlink loop
+                    Err(e) => debug!("Not counting {:?}: {}", e.path(), e),
+                }
+            }
+        }
+    }
+    Ok(directories.len())
+}
+
+fn count_difference<N: NumRoots>(statistics: &mut DetectionStatistics, difference: &Difference<N>) {
+    statistics.differences += 1;
+    statistics.bytes_to_transfer += bytes_to_transfer(difference);
+}
+
+/// Scans every search directory, passing the differences found in each directory to `on_directory`.
+fn scan_search_directories<N, P, F>(
+    archive: &Archive,
+    search: &mut SearchDirectories,
+    config: &SyncInfo<N>,
+    progress_callback: &P,
+    statistics: &mut DetectionStatistics,
+    mut on_directory: F,
+) -> Result<(), SyncError>
+where
+    N: NumRoots,
+    P: ProgressCallback,
+    F: FnMut(Vec<Difference<N>>) -> Result<(), SyncError>,
+{
+    span!(
+        "find_updates",
+        search_directories = search.directories.len()
+    );
     // this is used to keep track of the current items in the current search directory
     let mut current_entries: FnvHashMap<PathBuf, GenericArray<ArchiveEntryPerReplica, N>> =
         Default::default();
-    let mut result = DetectionResult::new();
     let mut read_directories = 0;
+    // the canonical paths of every directory scanned so far, so that symlink loops aren't followed forever
+    let mut visited: FnvHashSet<Vec<Option<PathBuf>>> = Default::default();
+    // the first path seen for each hard-linked file on each replica
+    let mut hard_links: FnvHashMap<(ReplicaIndex, u64, u64), PathBuf> = Default::default();
 
     // warn about non-existent roots early in the processes
-    check_all_roots_exist(config.roots.iter())?;
+    let created_roots = check_all_roots_exist(config.roots.iter(), config.create_missing_roots)?;
+    check_roots_distinct(&config.roots)?;
+    archive.check_roots(&config.roots)?;
+
+    // absolute paths (eg: from a filesystem watcher) are accepted as long as they are inside a root
+    for dir in search.directories.iter_mut() {
+        if dir.is_absolute() {
+            *dir = relative_to_roots(dir, &config.roots)
+                .ok_or_else(|| SyncError::AbsolutePathProvided(dir.clone()))?;
+        }
+    }
 
+    let mut ignore = IgnoreMatcher::new(&config.ignore, &config.roots);
+    for path in archive_paths_in_roots(&archive.directory, &config.roots) {
+        info!("Ignoring {:?}, which is the archive", path);
+        ignore.ignore_path(&path);
+    }
     search
         .directories
-        .retain(|dir| !is_ignored(&config.ignore, &dir));
+        .retain(|dir| !ignore.is_ignored(dir, true));
 
     loop {
+        config.cancellation.check()?;
         current_entries.clear();
 
-        let sd = match search.directories.pop() {
-            Some(d) => d,
+        // the directory stays in `search` until it has been dealt with, so that it is still there if this fails
+        let sd = match search.directories.last() {
+            Some(d) => d.clone(),
             None => break,
         };
+        let mut subdirectories = Vec::new();
 
-        if sd.is_absolute() {
-            return Err(SyncError::AbsolutePathProvided(sd));
+        if !visited.insert(canonical_directories(&config.roots, &sd)) {
+            info!("Skipping {:?}, which has already been scanned", sd);
+            search.directories.pop();
+            continue;
         }
 
+        span!("scan_directory", path = ?sd);
         // creates a list of all the different entries in the directory
         debug!("Reading dir {:?}", sd);
-        progress_callback.reading_directory(&sd, read_directories, search.directories.len());
+        progress_callback.reading_directory(&sd, read_directories, search.directories.len() - 1);
         read_directories += 1;
+        statistics.directories_scanned = read_directories;
+        match progress_callback.scan_control(&sd) {
+            ScanControl::Continue => {}
+            ScanControl::SkipChildren => {
+                info!("Skipping {:?}, as requested by the progress callback", sd);
+                search.directories.pop();
+                continue;
+            }
+            ScanControl::Abort => return Err(SyncError::Cancelled),
+        }
 
         // get the previous entries (a snapshot of what it was like)
         let mut sd_archive_file = archive.for_directory(&sd);
         let mut sd_archive_entries: ArchiveEntries<N> = sd_archive_file.read()?;
 
         // scan the directory contents accross all replicas, adding items to check to `current_entries`
-        scan_directory_contents(&sd, &mut current_entries, config)?;
+        if let Err(e) = scan_directory_contents(&sd, &mut current_entries, config, &ignore) {
+            check_no_roots_disappeared(config.roots.iter())?;
+            match e {
+                // the whole directory is skipped, otherwise items missing from the unreadable replica would look deleted
+                SyncError::IoError(..) if config.continue_on_error => {
+                    warn!("Skipping {:?}: {}", sd, e);
+                    statistics.skipped_errors.push((sd, e));
+                    search.directories.pop();
+                    continue;
+                }
+                e => return Err(e),
+            }
+        }
+        // a missing root would otherwise look like every item had been deleted from it
+        check_no_roots_disappeared(config.roots.iter())?;
+
+        // every item is registered before any differences are made, so that it doesn't matter which name is seen first
+        let mut hard_link_of: FnvHashMap<PathBuf, Vec<Option<PathBuf>>> = Default::default();
+        if config.detect_hard_links {
+            for path in current_entries.keys() {
+                let partners = find_hard_links(path, &config.roots, &mut hard_links);
+                if partners.iter().any(Option::is_some) {
+                    hard_link_of.insert(path.clone(), partners);
+                }
+            }
+        }
 
         // analyses each item in this directory
         debug!("Analysing items in {:?}", sd);
+        let mut items = Vec::with_capacity(current_entries.len());
+        // the changes made to `sd_archive_entries`, so that they can be applied to the latest entries when writing
+        let mut restored = Vec::new();
+        let mut additions = Vec::new();
         for (path, current_entry) in current_entries.iter_mut() {
-            let mut keep_checking = true;
-            if let Some(archive_entry) = sd_archive_entries.get(path) {
-                trace!("Checking archive files");
-                if are_archive_files_identical(archive_entry, current_entry) {
-                    result.statistics.archive_hits += 1;
-                    keep_checking = false;
-                }
+            trace!("Checking archive files");
+            let unchanged = sd_archive_entries.get(path).is_some_and(|archive_entry| {
+                are_archive_files_identical(archive_entry, current_entry)
+            });
+            if unchanged {
+                statistics.archive_hits += 1;
+                sd_archive_entries.restore_path(path);
+                restored.push(path.clone());
             }
+            items.push((path, current_entry, unchanged));
+        }
 
-            if keep_checking {
-                if is_item_in_sync(
-                    path,
-                    current_entry,
-                    config.compare_file_contents,
-                    &config.roots,
-                )? {
+        // everything the archive can't vouch for is compared, possibly on several threads
+        let pending = items
+            .iter_mut()
+            .filter(|&&mut (_, _, unchanged)| !unchanged)
+            .map(|&mut (path, ref mut current_entry, _)| PendingCheck {
+                path,
+                current_entry: &mut **current_entry,
+                previous_state: sd_archive_entries.get(path),
+                without_history: created_roots || sd_archive_entries.get(path).is_none(),
+            })
+            .collect();
+        let mut checks = check_items_in_sync(pending, config).into_iter();
+
+        let mut differences = Vec::new();
+        for (path, current_entry, unchanged) in items {
+            if !unchanged {
+                let check = checks.next().expect("every changed item was checked")?;
+                if check.in_sync {
                     // This item is identical, let's store that in the archive for next time
-                    sd_archive_entries.insert(path, current_entry.clone());
-                    result.statistics.archive_additions += 1;
+                    if !archive.read_only {
+                        sd_archive_entries.insert(path, current_entry.clone());
+                        additions.push((path.clone(), current_entry.clone()));
+                        statistics.archive_additions += 1;
+                    }
                 } else {
                     // the Difference struct encapsulates everything needed to resolve
                     // a conflict independently of any other information.
+                    // a new root is empty, which the archive would take to mean everything was deleted from it
+                    let previous_state = if created_roots {
+                        None
+                    } else {
+                        sd_archive_entries.get(path).cloned()
+                    };
                     let difference = Difference {
                         path: path.to_path_buf(),
                         roots: config.roots.clone(),
-                        previous_state: sd_archive_entries.get(path).cloned(),
+                        previous_state,
                         current_state: current_entry.clone(),
+                        detection_mode: config.detection_mode,
+                        renamed_from: None,
+                        follow_symlinks: config.follow_symlinks,
+                        max_file_size: config.max_file_size,
+                        hard_link_of: hard_link_of.remove(path).unwrap_or_default(),
+                        identical_where_present: check.identical_where_present,
+                        changed_replicas_agree: check.changed_replicas_agree,
                     };
-                    result.add_difference(difference);
+                    differences.push(difference);
                     continue;
                 }
             }
 
             // This item is identical on every replica so if it is a directory we
             // will start looking inside its contents, as long as the user requested it with
-            // the SearchDirectories.recurse option
-            if let Some(root) = config.roots.last() {
-                if search.recurse && root.join(path).is_dir() {
-                    search.directories.push(path.clone());
+            // the SearchDirectories.recurse option.
+            // Symlinked directories are only entered when `follow_symlinks` is set,
+            // as otherwise they are read as `Symlink` entries.
+            if let Some(ArchiveEntryPerReplica::Directory(_)) = current_entry.last() {
+                if search.recurse {
+                    subdirectories.push(path.clone());
                 }
             }
         }
 
-        if sd_archive_entries.is_dirty() {
-            sd_archive_file.write(&mut sd_archive_entries)?;
+        if sd_archive_entries.is_dirty() && !archive.read_only {
+            // the shared lock taken for reading can't be upgraded atomically, so another process may have
+            // written in between: the changes are applied to the entries read again under the exclusive lock
+            sd_archive_file.lock_exclusive()?;
+            let mut latest: ArchiveEntries<N> = sd_archive_file.read()?;
+            for path in &restored {
+                latest.restore_path(path);
+            }
+            for (path, entry) in additions {
+                latest.insert(&path, entry);
+            }
+            sd_archive_file.write(&mut latest)?;
         }
+        // release the lock on the archive file, in case `on_directory` propagates changes in this directory
+        sd_archive_file.unlock();
+
+        on_directory(differences)?;
+        search.directories.pop();
+        search.directories.extend(subdirectories);
     }
 
-    Ok(result)
+    Ok(())
 }
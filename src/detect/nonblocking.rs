@@ -0,0 +1,51 @@
+use std::panic;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::archive::Archive;
+use crate::config::SyncInfo;
+use crate::detect::{
+    find_updates_streaming, DetectionStatistics, Difference, ProgressCallback, SearchDirectories,
+};
+use crate::error::SyncError;
+use crate::NumRoots;
+
+/// An async version of `find_updates_streaming`, which sends each difference to `differences` as soon as
+/// its directory has been scanned.
+///
+/// The scan still uses blocking filesystem calls, but it runs on tokio's blocking thread pool (with `spawn_blocking`),
+/// so it doesn't hold up the async runtime. When the channel is full, the scan waits for the receiver to catch up,
+/// and if the receiver is dropped, detection stops with `SyncError::Cancelled`.
+pub async fn find_updates_async<N, P>(
+    archive: Arc<Archive>,
+    mut search: SearchDirectories,
+    config: Arc<SyncInfo<N>>,
+    progress_callback: P,
+    differences: mpsc::Sender<Difference<N>>,
+) -> Result<DetectionStatistics, SyncError>
+where
+    N: NumRoots,
+    P: ProgressCallback + Send + 'static,
+{
+    let scan = task::spawn_blocking(move || {
+        find_updates_streaming(
+            &archive,
+            &mut search,
+            &config,
+            &progress_callback,
+            |difference| {
+                differences
+                    .blocking_send(difference)
+                    .map_err(|_| SyncError::Cancelled)
+            },
+        )
+    });
+    match scan.await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        // the runtime is shutting down
+        Err(_) => Err(SyncError::Cancelled),
+    }
+}
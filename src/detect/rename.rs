@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::Path;
+
+use crate::compare_files::file_hash;
+use crate::detect::{DetectionResult, Difference};
+use crate::error::{DescribeIoError, SyncError};
+use crate::state::ArchiveEntryPerReplica;
+use crate::NumRoots;
+use crate::ReplicaIndex;
+
+/// Pairs up files which were deleted on one replica with identical files created on the same replica,
+/// replacing each pair with a single difference whose `renamed_from` is set.
+pub fn pair_renames<N: NumRoots>(result: &mut DetectionResult<N>) -> Result<(), SyncError> {
+    let mut deleted: Vec<(usize, ReplicaIndex)> = result
+        .differences
+        .iter()
+        .enumerate()
+        .filter_map(|(i, difference)| deleted_on(difference).map(|replica| (i, replica)))
+        .collect();
+    let mut renamed = Vec::new();
+
+    for (i, created) in result.differences.iter().enumerate() {
+        let replica = match created_on(created) {
+            Some(replica) => replica,
+            None => continue,
+        };
+        let mut found = None;
+        for (position, &(j, deleted_replica)) in deleted.iter().enumerate() {
+            if deleted_replica == replica
+                && have_same_contents(created, &result.differences[j], replica)?
+            {
+                found = Some(position);
+                break;
+            }
+        }
+        if let Some(position) = found {
+            let (j, _) = deleted.remove(position);
+            renamed.push((i, j));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (created, deleted) in renamed {
+        let from = result.differences[deleted].path.clone();
+        info!(
+            "{:?} was renamed to {:?}",
+            from, result.differences[created].path
+        );
+        result.differences[created].renamed_from = Some(from);
+        removed.push(deleted);
+    }
+    let mut index = 0;
+    result.differences.retain(|_| {
+        index += 1;
+        !removed.contains(&(index - 1))
+    });
+
+    Ok(())
+}
+
+/// The replica a file was deleted from, if it is unchanged on every other replica.
+fn deleted_on<N: NumRoots>(difference: &Difference<N>) -> Option<ReplicaIndex> {
+    let previous_state = difference.previous_state.as_ref()?;
+    let mut result = None;
+    for (i, (previous, current)) in previous_state
+        .iter()
+        .zip(difference.current_state.iter())
+        .enumerate()
+    {
+        if !matches!(*previous, ArchiveEntryPerReplica::File(_)) {
+            return None;
+        }
+        if *current == ArchiveEntryPerReplica::Empty {
+            if result.is_some() {
+                return None;
+            }
+            result = Some(i);
+        } else if current != previous {
+            return None;
+        }
+    }
+    result
+}
+
+/// The replica a file was created on, if it doesn't exist on any other replica.
+fn created_on<N: NumRoots>(difference: &Difference<N>) -> Option<ReplicaIndex> {
+    let mut result = None;
+    for (i, current) in difference.current_state.iter().enumerate() {
+        match *current {
+            ArchiveEntryPerReplica::Empty => {}
+            ArchiveEntryPerReplica::File(_) if result.is_none() => result = Some(i),
+            _ => return None,
+        }
+    }
+    result
+}
+
+/// Checks that the file created on `replica` has the same contents as the deleted file
+/// still present on every other replica.
+fn have_same_contents<N: NumRoots>(
+    created: &Difference<N>,
+    deleted: &Difference<N>,
+    replica: ReplicaIndex,
+) -> Result<bool, SyncError> {
+    let created_path = created.absolute_path_for_root(replica);
+    let created_size = file_size(&created_path)?;
+    let mut created_hash = None;
+
+    for i in 0..deleted.roots.len() {
+        if i == replica {
+            continue;
+        }
+        let deleted_path = deleted.absolute_path_for_root(i);
+        if file_size(&deleted_path)? != created_size {
+            return Ok(false);
+        }
+        if created_hash.is_none() {
+            created_hash = Some(
+                file_hash(&created_path)
+                    .describe(|| format!("while hashing {:?}", created_path))?,
+            );
+        }
+        let deleted_hash =
+            file_hash(&deleted_path).describe(|| format!("while hashing {:?}", deleted_path))?;
+        if created_hash != Some(deleted_hash) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn file_size(path: &Path) -> Result<u64, SyncError> {
+    Ok(fs::metadata(path)
+        .describe(|| format!("while reading metadata of {:?}", path))?
+        .len())
+}
@@ -1,25 +1,80 @@
+use std::env;
+use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use generic_array::GenericArray;
+use glob::MatchOptions;
+use regex::{RegexSet, RegexSetBuilder};
+use walkdir::WalkDir;
 
 use crate::config::{Ignore, SyncInfo};
-use crate::error::SyncError;
+use crate::detect::Difference;
+use crate::error::{DescribeIoError, SyncError};
+use crate::propagate::PARTIAL_DIR;
+use crate::reconcile::{guess_operation, Operation};
 use crate::state::ArchiveEntryPerReplica;
-use crate::util::FnvHashMap;
+use crate::util::{hard_link_identity, FnvHashMap, FnvHashSet};
 use crate::NumRoots;
+use crate::ReplicaIndex;
 
+/// Checks that every root exists, creating any which are missing if `create_missing` is true.
+/// Returns true if any roots were created.
 pub fn check_all_roots_exist<'a, I: Iterator<Item = &'a PathBuf>>(
     roots: I,
-) -> Result<(), SyncError> {
+    create_missing: bool,
+) -> Result<bool, SyncError> {
+    let mut created = false;
     for root in roots {
         if !root.exists() {
-            return Err(SyncError::RootDoesntExist(root.to_path_buf()));
+            if !create_missing {
+                return Err(SyncError::RootDoesntExist(root.to_path_buf()));
+            }
+            info!("Creating missing root {:?}", root);
+            fs::create_dir_all(root).describe(|| format!("while creating root {:?}", root))?;
+            created = true;
+        }
+    }
+    Ok(created)
+}
+
+/// Converts an absolute path inside one of the roots into a path relative to that root.
+///
+/// The roots are also canonicalized, so that paths from `fs::canonicalize` can be converted too.
+pub fn relative_to_roots(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    if let Some(relative) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) {
+        return Some(relative.to_path_buf());
+    }
+    roots.iter().find_map(|root| {
+        let root = fs::canonicalize(root).ok()?;
+        path.strip_prefix(root).ok().map(Path::to_path_buf)
+    })
+}
+
+/// Checks that none of the roots have vanished since detection started,
+/// so that an unmounted replica isn't mistaken for one where everything was deleted.
+pub fn check_no_roots_disappeared<'a, I: Iterator<Item = &'a PathBuf>>(
+    roots: I,
+) -> Result<(), SyncError> {
+    for root in roots {
+        if !root.is_dir() {
+            return Err(SyncError::RootDisappeared(root.to_path_buf()));
         }
     }
     Ok(())
 }
 
+/// The canonical path of `directory` inside each root, or `None` where it doesn't exist.
+///
+/// Two search directories with the same canonical paths are the same directories reached through symlinks.
+pub fn canonical_directories(roots: &[PathBuf], directory: &Path) -> Vec<Option<PathBuf>> {
+    roots
+        .iter()
+        .map(|root| fs::canonicalize(root.join(directory)).ok())
+        .collect()
+}
+
 /// checks that all the archive files for this path are identical
 pub fn are_archive_files_identical<N: NumRoots>(
     a: &GenericArray<ArchiveEntryPerReplica, N>,
@@ -33,28 +88,210 @@ pub fn are_archive_files_identical<N: NumRoots>(
     true
 }
 
-/// checks if the path is on the ignore list
-pub fn is_ignored(ignore: &Ignore, path: &Path) -> bool {
-    for ignore in &ignore.paths {
-        //trace!("{:?} starts with {:?} = {}", path, ignore, path.starts_with(ignore));
-        if path.starts_with(ignore) {
-            return true;
+/// An `Ignore` prepared for checking many paths, built once at the start of detection.
+pub struct IgnoreMatcher<'a> {
+    ignore: &'a Ignore,
+    /// `ignore.paths`, lowercased if the match is case-insensitive
+    paths: FnvHashSet<PathBuf>,
+    /// `ignore.regexes`, combined so that a path is matched against all of them at once
+    regexes: RegexSet,
+    /// The absolute paths of the roots, if there are any `ignore.absolute_globs` to check in them
+    roots: Vec<PathBuf>,
+}
+
+impl<'a> IgnoreMatcher<'a> {
+    pub fn new(ignore: &'a Ignore, roots: &[PathBuf]) -> Self {
+        let paths = ignore
+            .paths
+            .iter()
+            .map(|path| {
+                if ignore.case_insensitive {
+                    PathBuf::from(path.to_lowercase())
+                } else {
+                    PathBuf::from(path)
+                }
+            })
+            .collect();
+        let regexes = RegexSetBuilder::new(ignore.regexes.iter().map(|regex| regex.as_str()))
+            .case_insensitive(ignore.case_insensitive)
+            .build()
+            .expect("the regexes have already been compiled once");
+        let roots = if ignore.absolute_globs.is_empty() {
+            Vec::new()
+        } else {
+            let current_dir = env::current_dir().unwrap_or_default();
+            roots.iter().map(|root| current_dir.join(root)).collect()
+        };
+        IgnoreMatcher {
+            ignore,
+            paths,
+            regexes,
+            roots,
         }
     }
-    for ignore in &ignore.regexes {
-        //trace!("{:?} is match {:?} = {}", ignore, path.to_str().unwrap(), ignore.is_match(path.to_str().unwrap()));
-        if ignore.is_match(path.to_str().unwrap()) {
+
+    /// Also ignores `path` and everything inside it, as if it were in `ignore.paths`.
+    pub fn ignore_path(&mut self, path: &Path) {
+        let path = if self.ignore.case_insensitive {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        };
+        self.paths.insert(path);
+    }
+
+    /// checks if the path is on the ignore list. `is_dir` is used by rules which only apply to directories or files.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let ignore = self.ignore;
+        let lowercase;
+        let path = if ignore.case_insensitive {
+            lowercase = PathBuf::from(path.to_string_lossy().to_lowercase());
+            &lowercase
+        } else {
+            path
+        };
+
+        // a path is ignored if it or any of its parents is in `paths`
+        if path
+            .ancestors()
+            .any(|ancestor| self.paths.contains(ancestor))
+        {
+            return true;
+        }
+        if self.regexes.is_match(path.to_str().unwrap()) {
             return true;
         }
+        let options = MatchOptions {
+            case_sensitive: !ignore.case_insensitive,
+            ..MatchOptions::new()
+        };
+        for glob in &ignore.globs {
+            if glob.matches_path_with(path, options) {
+                return true;
+            }
+        }
+        for root in &self.roots {
+            let absolute = root.join(path);
+            if ignore
+                .absolute_globs
+                .iter()
+                .any(|glob| glob.matches_path_with(&absolute, options))
+            {
+                return true;
+            }
+        }
+
+        // the last matching rule wins
+        let mut ignored = false;
+        for rule in &ignore.rules {
+            if rule.matches(path, is_dir, !ignore.case_insensitive) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+/// The paths of the archive directory relative to each root it is stored inside of,
+/// so that its files aren't synced as if they were part of the replica.
+pub fn archive_paths_in_roots(archive_directory: &Path, roots: &[PathBuf]) -> Vec<PathBuf> {
+    let archive_directory = match fs::canonicalize(archive_directory) {
+        Ok(directory) => directory,
+        Err(_) => return Vec::new(),
+    };
+    roots
+        .iter()
+        .filter_map(|root| {
+            let root = fs::canonicalize(root).ok()?;
+            let relative = archive_directory.strip_prefix(root).ok()?;
+            // an archive which is the root itself can't be ignored without ignoring everything
+            if relative.as_os_str().is_empty() {
+                None
+            } else {
+                Some(relative.to_path_buf())
+            }
+        })
+        .collect()
+}
+
+/// For each root, the path of an earlier hard link to the file at `path`, recording `path` in `seen` if it is the first.
+pub fn find_hard_links(
+    path: &Path,
+    roots: &[PathBuf],
+    seen: &mut FnvHashMap<(ReplicaIndex, u64, u64), PathBuf>,
+) -> Vec<Option<PathBuf>> {
+    roots
+        .iter()
+        .enumerate()
+        .map(|(i, root)| {
+            let (dev, ino) = hard_link_identity(&root.join(path))?;
+            let first = seen
+                .entry((i, dev, ino))
+                .or_insert_with(|| path.to_path_buf());
+            if first != path {
+                Some(first.clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The size of the files which would be copied to resolve the difference with `reconcile::guess_operation`.
+///
+/// This is only a statistic, so it never fails: a file's size comes from the detected state,
+/// and anything inside a directory which can no longer be read is left out of the total.
+pub fn bytes_to_transfer<N: NumRoots>(difference: &Difference<N>) -> u64 {
+    let master = match guess_operation(difference) {
+        Operation::PropagateFromMaster(master) => master,
+        // renames don't copy anything, and conflicts can't be resolved automatically
+        _ => return 0,
+    };
+    let entry = &difference.current_state[master];
+    match *entry {
+        ArchiveEntryPerReplica::File(_) => entry.file_size().unwrap_or(0),
+        ArchiveEntryPerReplica::Directory(_) => {
+            let path = difference.absolute_path_for_root(master);
+            let mut total = 0;
+            for entry in WalkDir::new(&path) {
+                let size = entry
+                    .and_then(|entry| Ok((entry.file_type().is_file(), entry.metadata()?.len())));
+                match size {
+                    Ok((true, size)) => total += size,
+                    Ok((false, _)) => {}
+                    Err(e) => debug!("Not counting the size of {:?}: {}", e.path(), e),
+                }
+            }
+            total
+        }
+        ArchiveEntryPerReplica::Symlink(_)
+        | ArchiveEntryPerReplica::Other(_)
+        | ArchiveEntryPerReplica::Empty => 0,
     }
+}
 
-    false
+/// Checks if the item is a file which is larger than `max_file_size` on every replica it exists on.
+fn is_too_large(entry: &[ArchiveEntryPerReplica], max_file_size: u64) -> bool {
+    entry.iter().all(|replica| match *replica {
+        ArchiveEntryPerReplica::Empty => true,
+        ArchiveEntryPerReplica::File(_) => replica.file_size() > Some(max_file_size),
+        _ => false,
+    }) && entry.iter().any(ArchiveEntryPerReplica::entry_exists)
+}
+
+/// Checks if the item is a file which was modified after `settled_before` on any replica.
+fn is_too_new(entry: &[ArchiveEntryPerReplica], settled_before: (i64, i64)) -> bool {
+    entry.iter().any(|replica| match *replica {
+        ArchiveEntryPerReplica::File(_) => replica.modification_time() > Some(settled_before),
+        _ => false,
+    })
 }
 
 pub fn scan_directory_contents<N>(
     directory: &Path,
     current_entries: &mut FnvHashMap<PathBuf, GenericArray<ArchiveEntryPerReplica, N>>,
     config: &SyncInfo<N>,
+    ignore: &IgnoreMatcher,
 ) -> Result<(), SyncError>
 where
     N: NumRoots,
@@ -64,31 +301,92 @@ where
     // of paths to check.
     let mut sd_present_in_all_replicas = true;
 
+    // files modified after this are too new to be synced
+    let settled_before = config.min_age.map(|min_age| {
+        let time = SystemTime::now()
+            .checked_sub(min_age)
+            .unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (time.as_secs() as i64, i64::from(time.subsec_nanos()))
+    });
+
+    // items which were ignored when they were found in an earlier root
+    let mut ignored: FnvHashSet<PathBuf> = Default::default();
+
     // search the contents of this directory, collecting a list of
     // all items across all replicas and storing it inside `current_entries`
     for root in config.roots.iter() {
         let absolute_directory = root.join(directory);
         if absolute_directory.is_dir() {
             // loop through dir
-            for item in fs::read_dir(absolute_directory)? {
+            let items = fs::read_dir(&absolute_directory)
+                .describe(|| format!("while reading directory {:?}", absolute_directory))?;
+            for item in items {
                 let relative_path = item?.path();
                 let relative_path = relative_path.strip_prefix(root).unwrap_or_else(|_| {
                     panic!("couldn't strip prefix {:?} from {:?}", root, relative_path)
                 });
 
-                if is_ignored(&config.ignore, &relative_path) {
+                if current_entries.contains_key(relative_path) || ignored.contains(relative_path) {
+                    continue;
+                }
+                // partial transfers are cleaned up by propagation, not synced.
+                // Detection doesn't know whether `resume_partial` is in use, so the name is always reserved.
+                if relative_path.file_name() == Some(OsStr::new(PARTIAL_DIR)) {
+                    debug!(
+                        "Skipping {:?}, which holds partial transfers",
+                        relative_path
+                    );
+                    continue;
+                }
+
+                // insert current filesystem state
+                let entry = ArchiveEntryPerReplica::from_roots::<N>(
+                    &config.roots,
+                    relative_path,
+                    config.detection_mode,
+                    config.follow_symlinks,
+                );
+                let is_dir = entry
+                    .iter()
+                    .any(|replica| matches!(*replica, ArchiveEntryPerReplica::Directory(_)));
+                if ignore.is_ignored(relative_path, is_dir) {
                     info!("Ignoring entry {:?}", relative_path);
+                    ignored.insert(relative_path.to_path_buf());
+                    continue;
+                }
+                if entry
+                    .iter()
+                    .any(|replica| matches!(*replica, ArchiveEntryPerReplica::Other(_)))
+                {
+                    warn!(
+                        "Skipping {:?}, which is a special file (eg: a named pipe or socket)",
+                        relative_path
+                    );
+                    ignored.insert(relative_path.to_path_buf());
                     continue;
                 }
+                if let Some(settled_before) = settled_before {
+                    if is_too_new(&entry, settled_before) {
+                        info!(
+                            "Skipping {:?}, which was modified too recently",
+                            relative_path
+                        );
+                        ignored.insert(relative_path.to_path_buf());
+                        continue;
+                    }
+                }
+                if let Some(max_file_size) = config.max_file_size {
+                    if is_too_large(&entry, max_file_size) {
+                        info!("Skipping {:?}, which is too large", relative_path);
+                        ignored.insert(relative_path.to_path_buf());
+                        continue;
+                    }
+                }
 
                 trace!("Adding entry {:?}", relative_path);
-
-                // insert current filesystem state
-                current_entries
-                    .entry(relative_path.to_path_buf())
-                    .or_insert_with(|| {
-                        ArchiveEntryPerReplica::from_roots::<N>(&config.roots, &relative_path)
-                    });
+                current_entries.insert(relative_path.to_path_buf(), entry);
             }
         } else {
             sd_present_in_all_replicas = false;
@@ -99,7 +397,14 @@ where
     if !sd_present_in_all_replicas {
         current_entries
             .entry(directory.to_path_buf())
-            .or_insert_with(|| ArchiveEntryPerReplica::from_roots::<N>(&config.roots, &directory));
+            .or_insert_with(|| {
+                ArchiveEntryPerReplica::from_roots::<N>(
+                    &config.roots,
+                    directory,
+                    config.detection_mode,
+                    config.follow_symlinks,
+                )
+            });
     }
 
     Ok(())
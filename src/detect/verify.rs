@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Archive, ArchiveEntries};
+use crate::compare_files::file_hash;
+use crate::config::SyncInfo;
+use crate::error::{DescribeIoError, SyncError};
+use crate::state::ArchiveEntryPerReplica;
+use crate::NumRoots;
+
+/// Compares the contents of every file which the archive records as in sync,
+/// returning the paths whose contents differ between replicas.
+///
+/// Unlike `find_updates`, the `ino` and `ctime` stored in the archive aren't trusted,
+/// and every file is read in full, so this catches corruption which doesn't touch a file's metadata.
+/// Files which no longer exist on every replica are left for `find_updates` to deal with.
+/// Nothing is written to the archive.
+pub fn verify_in_sync<N: NumRoots>(
+    archive: &Archive,
+    config: &SyncInfo<N>,
+) -> Result<Vec<PathBuf>, SyncError> {
+    let mut differing = Vec::new();
+    for hash in archive.hashed_directories()? {
+        let entries: ArchiveEntries<N> = archive.for_hashed_directory(hash).read()?;
+        for (path, replicas) in entries.iter() {
+            config.cancellation.check()?;
+            // entries from very old archives don't know their path
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            if !replicas
+                .iter()
+                .all(|replica| matches!(*replica, ArchiveEntryPerReplica::File(_)))
+            {
+                continue;
+            }
+            if !contents_equal(path, &config.roots)? {
+                warn!("{:?} differs between replicas", path);
+                differing.push(path.to_path_buf());
+            }
+        }
+    }
+    differing.sort();
+    Ok(differing)
+}
+
+/// Checks that the file has the same contents on every replica.
+/// Returns `true` if it's no longer a file on one of them.
+fn contents_equal(path: &Path, roots: &[PathBuf]) -> Result<bool, SyncError> {
+    let absolute_paths: Vec<PathBuf> = roots.iter().map(|root| root.join(path)).collect();
+    if !absolute_paths.iter().all(|path| path.is_file()) {
+        debug!("{:?} is no longer a file on every replica, skipping", path);
+        return Ok(true);
+    }
+
+    let mut first = None;
+    for absolute_path in &absolute_paths {
+        let size = fs::metadata(absolute_path)
+            .describe(|| format!("while reading metadata of {:?}", absolute_path))?
+            .len();
+        let hash =
+            file_hash(absolute_path).describe(|| format!("while hashing {:?}", absolute_path))?;
+        match first {
+            None => first = Some((size, hash)),
+            Some(first) if first != (size, hash) => return Ok(false),
+            Some(_) => {}
+        }
+    }
+    Ok(true)
+}
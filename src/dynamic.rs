@@ -0,0 +1,221 @@
+use generic_array::GenericArray;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use typenum::{U0, U1, U2, U3, U4, U5, U6, U7, U8};
+
+use crate::archive::Archive;
+use crate::config::{DetectionMode, SyncInfo};
+use crate::detect::{self, DetectionStatistics, Difference, SearchDirectories};
+use crate::error::SyncError;
+use crate::propagate::{self, PropagationOptions, PropagationReport};
+use crate::reconcile::{self, Operation};
+use crate::state::ArchiveEntryPerReplica;
+use crate::NumRoots;
+
+/// The largest number of replicas the dynamic API supports.
+pub const MAX_DYNAMIC_ROOTS: usize = 8;
+
+/// Runs `$body` with `$n` set to the `typenum` type for `$count`,
+/// or returns `SyncError::UnsupportedRootCount` if there is no such type.
+macro_rules! with_num_roots {
+    ($count:expr, $n:ident => $body:expr) => {
+        match $count {
+            1 => {
+                type $n = U1;
+                $body
+            }
+            2 => {
+                type $n = U2;
+                $body
+            }
+            3 => {
+                type $n = U3;
+                $body
+            }
+            4 => {
+                type $n = U4;
+                $body
+            }
+            5 => {
+                type $n = U5;
+                $body
+            }
+            6 => {
+                type $n = U6;
+                $body
+            }
+            7 => {
+                type $n = U7;
+                $body
+            }
+            8 => {
+                type $n = U8;
+                $body
+            }
+            count => Err(SyncError::UnsupportedRootCount(count)),
+        }
+    };
+}
+
+/// Like `SyncInfo`, but with a number of roots which is only known at runtime (eg: read from a config file).
+///
+/// Every other setting lives in `settings`, whose own `roots` are ignored.
+#[derive(Debug, Clone)]
+pub struct DynamicSyncInfo {
+    pub roots: Vec<PathBuf>,
+    pub settings: SyncInfo<U0>,
+}
+
+impl DynamicSyncInfo {
+    /// Starts with the same defaults as `SyncInfo::new`.
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        DynamicSyncInfo {
+            roots,
+            settings: SyncInfo::new(GenericArray::default()),
+        }
+    }
+
+    fn to_static<N: NumRoots>(&self) -> Result<SyncInfo<N>, SyncError> {
+        Ok(self.settings.with_roots(to_array(self.roots.clone())?))
+    }
+}
+
+/// Like `Difference`, but with a `Vec` entry for each replica instead of a `GenericArray`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DynamicDifference {
+    /// The path at which the difference occurred
+    pub path: PathBuf,
+    /// The roots of the syncing operation
+    pub roots: Vec<PathBuf>,
+    /// The previous state that may be present from the archive
+    pub previous_state: Option<Vec<ArchiveEntryPerReplica>>,
+    /// The current state of the files
+    pub current_state: Vec<ArchiveEntryPerReplica>,
+    /// How the states were read from the filesystem
+    pub detection_mode: DetectionMode,
+    /// See `Difference::renamed_from`
+    pub renamed_from: Option<PathBuf>,
+    /// See `Difference::follow_symlinks`
+    pub follow_symlinks: bool,
+    /// See `Difference::max_file_size`
+    pub max_file_size: Option<u64>,
+    /// See `Difference::hard_link_of`
+    pub hard_link_of: Vec<Option<PathBuf>>,
+    /// See `Difference::identical_where_present`
+    pub identical_where_present: bool,
+    /// See `Difference::changed_replicas_agree`
+    pub changed_replicas_agree: bool,
+}
+
+impl DynamicDifference {
+    pub fn absolute_path_for_root(&self, index: usize) -> PathBuf {
+        self.roots[index].join(&self.path)
+    }
+
+    /// Converts back into a `Difference`, returning `SyncError::WrongNumberOfRoots`
+    /// if the roots or states don't have exactly `N` entries.
+    pub fn to_static<N: NumRoots>(&self) -> Result<Difference<N>, SyncError> {
+        let previous_state = match self.previous_state {
+            Some(ref previous_state) => Some(to_array(previous_state.clone())?),
+            None => None,
+        };
+        Ok(Difference {
+            path: self.path.clone(),
+            roots: to_array(self.roots.clone())?,
+            previous_state,
+            current_state: to_array(self.current_state.clone())?,
+            detection_mode: self.detection_mode,
+            renamed_from: self.renamed_from.clone(),
+            follow_symlinks: self.follow_symlinks,
+            max_file_size: self.max_file_size,
+            hard_link_of: self.hard_link_of.clone(),
+            identical_where_present: self.identical_where_present,
+            changed_replicas_agree: self.changed_replicas_agree,
+        })
+    }
+}
+
+impl<N: NumRoots> From<Difference<N>> for DynamicDifference {
+    fn from(difference: Difference<N>) -> Self {
+        DynamicDifference {
+            path: difference.path,
+            roots: difference.roots.into_iter().collect(),
+            previous_state: difference
+                .previous_state
+                .map(|state| state.into_iter().collect()),
+            current_state: difference.current_state.into_iter().collect(),
+            detection_mode: difference.detection_mode,
+            renamed_from: difference.renamed_from,
+            follow_symlinks: difference.follow_symlinks,
+            max_file_size: difference.max_file_size,
+            hard_link_of: difference.hard_link_of,
+            identical_where_present: difference.identical_where_present,
+            changed_replicas_agree: difference.changed_replicas_agree,
+        }
+    }
+}
+
+/// The result of `find_updates_dynamic`
+#[derive(Debug)]
+pub struct DynamicDetectionResult {
+    pub differences: Vec<DynamicDifference>,
+    pub statistics: DetectionStatistics,
+}
+
+/// `detect::find_updates` for a number of roots chosen at runtime.
+///
+/// Returns `SyncError::UnsupportedRootCount` unless there are between 1 and `MAX_DYNAMIC_ROOTS` roots.
+pub fn find_updates_dynamic<P>(
+    archive: &Archive,
+    search: &mut SearchDirectories,
+    config: &DynamicSyncInfo,
+    progress_callback: &P,
+) -> Result<DynamicDetectionResult, SyncError>
+where
+    P: detect::ProgressCallback,
+{
+    with_num_roots!(config.roots.len(), N => {
+        let config = config.to_static::<N>()?;
+        let result = detect::find_updates(archive, search, &config, progress_callback)?;
+        Ok(DynamicDetectionResult {
+            differences: result.differences.into_iter().map(DynamicDifference::from).collect(),
+            statistics: result.statistics,
+        })
+    })
+}
+
+/// `propagate::propagate` for a difference found by `find_updates_dynamic`.
+pub fn propagate_dynamic<T, P>(
+    difference: &DynamicDifference,
+    master: usize,
+    archive: &Archive,
+    options: &T,
+    progress: &P,
+) -> Result<PropagationReport, SyncError>
+where
+    T: PropagationOptions,
+    P: propagate::ProgressCallback,
+{
+    with_num_roots!(difference.roots.len(), N => {
+        let difference = difference.to_static::<N>()?;
+        propagate::propagate(&difference, master, archive, options, progress)
+    })
+}
+
+/// `reconcile::guess_operation` for a difference found by `find_updates_dynamic`.
+pub fn guess_operation_dynamic(difference: &DynamicDifference) -> Result<Operation, SyncError> {
+    with_num_roots!(difference.roots.len(), N => {
+        Ok(reconcile::guess_operation(&difference.to_static::<N>()?))
+    })
+}
+
+fn to_array<T, N>(items: Vec<T>) -> Result<GenericArray<T, N>, SyncError>
+where
+    N: generic_array::ArrayLength<T>,
+{
+    let found = items.len();
+    GenericArray::from_exact_iter(items).ok_or(SyncError::WrongNumberOfRoots {
+        expected: N::to_usize(),
+        found,
+    })
+}
@@ -1,7 +1,9 @@
 use crate::archive;
+use std::error::Error;
 use std::fmt;
 use std::io;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 use walkdir::Error as WalkDirError;
 
 #[derive(Debug)]
@@ -10,14 +12,64 @@ pub enum SyncError {
     PathModified(PathBuf),
     IoError(io::Error, Option<String>),
     RootDoesntExist(PathBuf),
+    /// A root which existed when the sync started is no longer accessible (eg: the drive was unmounted)
+    RootDisappeared(PathBuf),
+    /// An absolute search directory wasn't inside any of the roots
     AbsolutePathProvided(PathBuf),
     ArchiveReadError(archive::ReadError),
+    /// Another process is using the archive file at this path (see `archive::LockPolicy`)
+    ArchiveLocked(PathBuf),
     ArchiveWriteError(archive::WriteError),
+    /// The archive in this directory was built for different roots (see `Archive::check_roots`)
+    ArchiveRootMismatch(PathBuf),
+    /// The file is larger than `SyncInfo::max_file_size`, so it wasn't copied
+    FileTooLarge(PathBuf),
+    /// The copy at this path doesn't match the original (see `PropagationOptions::verify_after_copy`)
+    VerificationFailed(PathBuf),
+    /// Both of these roots resolve to the same directory (see `util::check_roots_distinct`)
+    DuplicateRoot {
+        a: PathBuf,
+        b: PathBuf,
+    },
+    /// The item at this path is a named pipe, socket or device node, which can't be propagated
+    UnsupportedFileType(PathBuf),
     /// The requested operation was cancelled before it could be completed.
     Cancelled,
+    /// A replica index (eg: the master passed to `propagate::propagate`) was out of range
+    InvalidReplicaIndex {
+        index: usize,
+        replicas: usize,
+    },
+    /// There were the wrong number of roots (or replica states) for the `SyncInfo` or `Difference` being built,
+    /// eg: by `SyncInfoBuilder::build` or `DynamicDifference::to_static`
+    WrongNumberOfRoots {
+        expected: usize,
+        found: usize,
+    },
+    /// The dynamic API doesn't support this many roots (see `dynamic::MAX_DYNAMIC_ROOTS`)
+    UnsupportedRootCount(usize),
+    /// A regex or glob passed to `SyncInfoBuilder` couldn't be parsed
+    InvalidIgnorePattern {
+        pattern: String,
+        reason: String,
+    },
+    /// The item at this path (relative to the roots) differs on these replicas,
+    /// and reconciliation couldn't tell which is correct (see `reconcile::require_resolution`)
+    UnresolvedConflict {
+        path: PathBuf,
+        replicas: Vec<usize>,
+    },
     WalkDirError(WalkDirError),
     /// The rsync executable wasn't found
     RsyncNotFound(String),
+    /// rsync exited unsuccessfully while copying `source` to `dest`
+    RsyncFailed {
+        status: ExitStatus,
+        source: PathBuf,
+        dest: PathBuf,
+        /// Everything rsync wrote to stderr, which usually explains the failure
+        stderr: String,
+    },
 }
 
 impl From<io::Error> for SyncError {
@@ -34,13 +86,19 @@ impl From<(io::Error, String)> for SyncError {
 
 impl From<archive::ReadError> for SyncError {
     fn from(e: archive::ReadError) -> Self {
-        SyncError::ArchiveReadError(e)
+        match e {
+            archive::ReadError::Locked(path) => SyncError::ArchiveLocked(path),
+            e => SyncError::ArchiveReadError(e),
+        }
     }
 }
 
 impl From<archive::WriteError> for SyncError {
     fn from(e: archive::WriteError) -> Self {
-        SyncError::ArchiveWriteError(e)
+        match e {
+            archive::WriteError::Locked(path) => SyncError::ArchiveLocked(path),
+            e => SyncError::ArchiveWriteError(e),
+        }
     }
 }
 
@@ -57,12 +115,43 @@ impl fmt::Display for SyncError {
             SyncError::IoError(ref io, Some(ref message)) => write!(f, "io error: {}, ({})", io, message),
             SyncError::IoError(ref io, None) => write!(f, "io error: {}", io),
             SyncError::RootDoesntExist(ref root) => write!(f, "root does not exist: {:?}", root),
-            SyncError::AbsolutePathProvided(ref path) => write!(f, "the absolute path {:?} is invalid (hint: search directories must be relative to the replica root)", path),
+            SyncError::RootDisappeared(ref root) => write!(f, "root disappeared during the sync: {:?}", root),
+            SyncError::AbsolutePathProvided(ref path) => write!(f, "the absolute path {:?} is invalid (hint: search directories must be inside one of the replica roots)", path),
             SyncError::ArchiveWriteError(ref e) => write!(f, "archive write error: {:?}", e),
             SyncError::ArchiveReadError(ref e) => write!(f, "archive read error: {:?}", e),
+            SyncError::ArchiveLocked(ref path) => write!(f, "the archive file {:?} is being used by another process", path),
+            SyncError::ArchiveRootMismatch(ref path) => write!(f, "the archive at {:?} was built for different roots", path),
+            SyncError::FileTooLarge(ref path) => write!(f, "the file at {:?} is larger than the maximum file size", path),
+            SyncError::VerificationFailed(ref path) => write!(f, "the copy at {:?} doesn't match the original", path),
+            SyncError::DuplicateRoot { ref a, ref b } => write!(f, "the roots {:?} and {:?} are the same directory", a, b),
+            SyncError::UnsupportedFileType(ref path) => write!(f, "{:?} is a special file (eg: a named pipe or socket), which can't be synced", path),
             SyncError::Cancelled => write!(f, "operation cancelled"),
+            SyncError::InvalidReplicaIndex { index, replicas } => write!(f, "replica index {} is out of range (there are {} replicas)", index, replicas),
+            SyncError::WrongNumberOfRoots { expected, found } => write!(f, "expected {} roots, but {} were given", expected, found),
+            SyncError::UnsupportedRootCount(count) => write!(f, "syncing {} roots isn't supported", count),
+            SyncError::InvalidIgnorePattern { ref pattern, ref reason } => write!(f, "invalid ignore pattern {:?}: {}", pattern, reason),
+            SyncError::UnresolvedConflict { ref path, ref replicas } => write!(f, "{:?} differs on replicas {:?} and can't be resolved automatically", path, replicas),
             SyncError::WalkDirError(ref e) => write!(f, "walk dir error: {:?}", e),
-            SyncError::RsyncNotFound(ref path) => write!(f, "rsync executable not found at: {:?}", path)
+            SyncError::RsyncNotFound(ref path) => write!(f, "rsync executable not found at: {:?}", path),
+            SyncError::RsyncFailed { ref status, ref source, ref dest, ref stderr } => {
+                write!(f, "rsync failed ({}) while copying {:?} to {:?}", status, source, dest)?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SyncError::IoError(ref e, _) => Some(e),
+            SyncError::ArchiveReadError(ref e) => Some(e),
+            SyncError::ArchiveWriteError(ref e) => Some(e),
+            SyncError::WalkDirError(ref e) => Some(e),
+            _ => None,
         }
     }
 }
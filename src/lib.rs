@@ -61,9 +61,19 @@
 #[macro_use]
 extern crate log;
 
+/// Enters a `tracing` span until the end of the enclosing block, when the `tracing` feature is enabled.
+/// Takes the same arguments as `tracing::info_span!`, which aren't evaluated without the feature.
+macro_rules! span {
+    ($($arguments:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($arguments)*).entered();
+    };
+}
+
 use crate::state::ArchiveEntryPerReplica;
 use generic_array::ArrayLength;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 /// Detects differences between replicas
 pub mod detect;
@@ -72,8 +82,13 @@ pub mod propagate;
 /// Makes suggestions on how to resolve differences between replicas
 pub mod reconcile;
 
+/// Detection and propagation for a number of replicas which is only known at runtime
+pub mod dynamic;
+
 /// Handles the serialization and deserialization of archive data
 pub mod archive;
+/// Comparison of file contents
+pub mod compare_files;
 /// Configuration for the whole system
 pub mod config;
 /// Error handling
@@ -81,13 +96,22 @@ pub mod error;
 /// Core structures for representing the state of the filesystem
 pub mod state;
 
-mod compare_files;
 mod util;
 
 /// Trait which encapsulates the length of a `GenericArray<PathBuf>`/`GenericArray<ArchiveEntryPerReplica>`
+/// (and the per-replica modification times from `Difference::replica_mtimes`).
 /// It will be automatically implemented for unsigned integers from the `typenum` crate.
-pub trait NumRoots: ArrayLength<PathBuf> + ArrayLength<ArchiveEntryPerReplica> {}
-impl<T: ArrayLength<PathBuf> + ArrayLength<ArchiveEntryPerReplica>> NumRoots for T {}
+pub trait NumRoots:
+    ArrayLength<PathBuf> + ArrayLength<ArchiveEntryPerReplica> + ArrayLength<Option<SystemTime>>
+{
+}
+impl<
+        T: ArrayLength<PathBuf>
+            + ArrayLength<ArchiveEntryPerReplica>
+            + ArrayLength<Option<SystemTime>>,
+    > NumRoots for T
+{
+}
 
 /// A type representing an index into a list of replicas.
 pub type ReplicaIndex = usize;
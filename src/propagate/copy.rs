@@ -0,0 +1,471 @@
+use filetime::FileTime;
+use fs2::FileExt;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+use crate::compare_files::read_block;
+use crate::error::{DescribeIoError, SyncError};
+use crate::propagate::progress::{ProgressCallback, ToCheck};
+use crate::propagate::{CopyBackend, PropagationOptions};
+use crate::util::{hard_link_identity, FnvHashMap};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keeps track of how much has been copied, so that progress can be reported
+/// through the same callback that rsync's output is parsed into.
+struct CopyProgress<'a, P: ProgressCallback> {
+    callback: &'a P,
+    start: Instant,
+    total_bytes: u64,
+    transferred_bytes: u64,
+    total_files: u32,
+    transferred_files: u32,
+    /// bytes per second, see `PropagationOptions::bandwidth_limit_kbps`
+    bandwidth_limit: Option<f64>,
+}
+
+impl<'a, P: ProgressCallback> CopyProgress<'a, P> {
+    fn new<T: PropagationOptions>(
+        callback: &'a P,
+        total_bytes: u64,
+        total_files: u32,
+        options: &T,
+    ) -> Self {
+        CopyProgress {
+            callback,
+            start: Instant::now(),
+            total_bytes,
+            transferred_bytes: 0,
+            total_files,
+            transferred_files: 0,
+            // like rsync, a limit of zero means no limit
+            bandwidth_limit: options
+                .bandwidth_limit_kbps()
+                .filter(|&limit| limit > 0)
+                .map(|limit| f64::from(limit) * 1024.0),
+        }
+    }
+
+    fn add_bytes(&mut self, bytes: u64) {
+        self.transferred_bytes += bytes;
+        self.report();
+        self.throttle();
+    }
+
+    /// Sleeps until the average speed since the copy started is back under the bandwidth limit.
+    fn throttle(&self) {
+        if let Some(limit) = self.bandwidth_limit {
+            let target = Duration::from_secs_f64(self.transferred_bytes as f64 / limit);
+            let elapsed = self.start.elapsed();
+            if target > elapsed {
+                thread::sleep(target - elapsed);
+            }
+        }
+    }
+
+    fn file_completed(&mut self) {
+        self.transferred_files += 1;
+        self.report();
+    }
+
+    fn report(&self) {
+        let percent = (self.transferred_bytes * 100)
+            .checked_div(self.total_bytes)
+            .map_or(100, |percent| percent.min(100) as u8);
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs();
+        let speed = self.transferred_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.callback.rsync_progress(
+            self.transferred_bytes as usize,
+            percent,
+            &format_speed(speed),
+            &format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60),
+            Some(self.transferred_files),
+            Some(ToCheck {
+                remaining: self.total_files - self.transferred_files,
+                total: self.total_files,
+            }),
+        );
+    }
+}
+
+/// Formats a speed in bytes per second the same way rsync does.
+fn format_speed(bytes_per_sec: f64) -> String {
+    let kb = bytes_per_sec / 1024.0;
+    if kb < 1024.0 {
+        format!("{:.2}kB/s", kb)
+    } else if kb < 1024.0 * 1024.0 {
+        format!("{:.2}MB/s", kb / 1024.0)
+    } else {
+        format!("{:.2}GB/s", kb / (1024.0 * 1024.0))
+    }
+}
+
+/// Copies a single file from `source` to `dest`, overwriting `dest` if it exists
+/// (unless it is renamed out of the way by `PropagationOptions::backup_suffix`).
+/// If `dest` is already the same size, only the blocks which differ are rewritten.
+pub fn copy_file<T, P>(
+    source: &Path,
+    dest: &Path,
+    options: &T,
+    progress: &P,
+) -> Result<(), SyncError>
+where
+    T: PropagationOptions,
+    P: ProgressCallback,
+{
+    back_up(dest, options)?;
+
+    let size = source.metadata()?.len();
+    let mut progress = CopyProgress::new(progress, size, 1, options);
+    progress.callback.current_file(source);
+    copy_file_contents(source, dest, &mut progress)?;
+    if options.preserve_xattrs() {
+        copy_xattrs(source, dest);
+    }
+    if options.preserve_metadata() {
+        copy_metadata(source, dest)?;
+    }
+    progress.file_completed();
+    Ok(())
+}
+
+/// Clones `source` to `dest` without copying its data, on filesystems which support it (eg: btrfs, XFS and APFS),
+/// replacing `dest` if it exists. See `PropagationOptions::prefer_reflink`.
+///
+/// Returns false, leaving `dest` untouched, if the file couldn't be cloned
+/// (eg: because the replicas are on different filesystems), so that it can be copied instead.
+pub fn reflink_file<T, P>(
+    source: &Path,
+    dest: &Path,
+    options: &T,
+    progress: &P,
+) -> Result<bool, SyncError>
+where
+    T: PropagationOptions,
+    P: ProgressCallback,
+{
+    // cloned alongside `dest` and then moved into place, since the clone can't overwrite an existing file
+    let mut name = OsString::from(".");
+    name.push(dest.file_name().unwrap());
+    name.push(".reflink");
+    let temporary = dest.with_file_name(name);
+    if let Err(e) = reflink_copy::reflink(source, &temporary) {
+        debug!("Couldn't clone {:?}, copying it instead: {}", source, e);
+        let _ = fs::remove_file(&temporary);
+        return Ok(false);
+    }
+
+    let size = source.metadata()?.len();
+    let mut progress = CopyProgress::new(progress, size, 1, options);
+    progress.callback.current_file(source);
+    // rsync always preserves metadata
+    if options.preserve_metadata() || options.copy_backend() == CopyBackend::Rsync {
+        copy_metadata(source, &temporary)?;
+    }
+    if options.preserve_xattrs() {
+        copy_xattrs(source, &temporary);
+    }
+    back_up(dest, options)?;
+    fs::rename(&temporary, dest)
+        .describe(|| format!("while moving {:?} to {:?}", temporary, dest))?;
+    progress.add_bytes(size);
+    progress.file_completed();
+    Ok(true)
+}
+
+/// Renames `dest` out of the way if `PropagationOptions::backup_suffix` is set.
+fn back_up<T: PropagationOptions>(dest: &Path, options: &T) -> Result<(), SyncError> {
+    if let Some(suffix) = options.backup_suffix() {
+        if fs::symlink_metadata(dest).is_ok() {
+            let mut backup = dest.as_os_str().to_os_string();
+            backup.push(suffix);
+            debug!("Backing up {:?} to {:?}", dest, backup);
+            fs::rename(dest, &backup)
+                .describe(|| format!("while backing up {:?} to {:?}", dest, backup))?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively copies the contents of `source` into the existing directory `dest`.
+pub fn copy_directory<T, P>(
+    source: &Path,
+    dest: &Path,
+    options: &T,
+    progress: &P,
+) -> Result<(), SyncError>
+where
+    T: PropagationOptions,
+    P: ProgressCallback,
+{
+    // collect everything first, so that progress can be reported as a fraction of the total
+    let mut entries = Vec::new();
+    let mut total_bytes = 0;
+    for entry in WalkDir::new(source).min_depth(1) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            total_bytes += entry.metadata()?.len();
+        }
+        entries.push(entry);
+    }
+
+    let mut progress = CopyProgress::new(progress, total_bytes, entries.len() as u32, options);
+    // the first copy of each hard-linked file, which later links to it are linked to
+    let mut linked: FnvHashMap<(u64, u64), PathBuf> = Default::default();
+    for entry in entries {
+        if options.is_cancelled() {
+            return Err(SyncError::Cancelled);
+        }
+        let target = dest.join(entry.path().strip_prefix(source).unwrap());
+        let ty = entry.file_type();
+        let identity = if options.preserve_hard_links() && ty.is_file() {
+            hard_link_identity(entry.path())
+        } else {
+            None
+        };
+        if ty.is_dir() {
+            fs::create_dir_all(&target)
+                .describe(|| format!("while creating directory {:?}", target))?;
+        } else if ty.is_symlink() {
+            super::transfer_symlink(entry.path(), &target)?;
+        } else if let Some(first) = identity.and_then(|identity| linked.get(&identity)) {
+            fs::hard_link(first, &target)
+                .describe(|| format!("while linking {:?} to {:?}", target, first))?;
+        } else {
+            progress.callback.current_file(entry.path());
+            copy_file_contents(entry.path(), &target, &mut progress)?;
+            if options.preserve_xattrs() {
+                copy_xattrs(entry.path(), &target);
+            }
+            if options.preserve_metadata() {
+                copy_metadata(entry.path(), &target)?;
+            }
+            if let Some(identity) = identity {
+                linked.insert(identity, target);
+            }
+        }
+        progress.file_completed();
+    }
+
+    // directories are done last (deepest first), because creating their contents changes their mtime
+    if options.preserve_metadata() || options.preserve_xattrs() {
+        for entry in WalkDir::new(source).contents_first(true) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                let target = dest.join(entry.path().strip_prefix(source).unwrap());
+                if options.preserve_xattrs() {
+                    copy_xattrs(entry.path(), &target);
+                }
+                if options.preserve_metadata() {
+                    copy_metadata(entry.path(), &target)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the modification time and permissions of `source` onto `dest`.
+fn copy_metadata(source: &Path, dest: &Path) -> Result<(), SyncError> {
+    trace!("Copying metadata from {:?} to {:?}", source, dest);
+    let metadata = source.metadata()?;
+    fs::set_permissions(dest, metadata.permissions())
+        .describe(|| format!("while setting permissions of {:?}", dest))?;
+    filetime::set_file_mtime(dest, FileTime::from_last_modification_time(&metadata))
+        .describe(|| format!("while setting modification time of {:?}", dest))?;
+    Ok(())
+}
+
+/// Copies the extended attributes of `source` onto `dest`.
+/// Not every filesystem supports them, so failures are only logged.
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, dest: &Path) {
+    let names = match xattr::list(source) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!(
+                "Couldn't read the extended attributes of {:?}: {}",
+                source, e
+            );
+            return;
+        }
+    };
+    for name in names {
+        let result = xattr::get(source, &name).and_then(|value| match value {
+            Some(value) => xattr::set(dest, &name, &value),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            warn!(
+                "Couldn't copy the extended attribute {:?} from {:?} to {:?}: {}",
+                name, source, dest, e
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_xattrs(source: &Path, _: &Path) {
+    warn!(
+        "Extended attributes aren't supported on this platform, not copying them from {:?}",
+        source
+    );
+}
+
+fn copy_file_contents<P>(
+    source: &Path,
+    dest: &Path,
+    progress: &mut CopyProgress<P>,
+) -> Result<(), SyncError>
+where
+    P: ProgressCallback,
+{
+    let mut reader =
+        fs::File::open(source).describe(|| format!("while opening {:?} for reading", source))?;
+    let size = reader
+        .metadata()
+        .describe(|| format!("while reading metadata of {:?}", source))?
+        .len();
+    match fs::symlink_metadata(dest) {
+        Ok(ref metadata) if metadata.is_file() && metadata.len() == size => {
+            return update_changed_blocks(&mut reader, source, dest, progress);
+        }
+        _ => {}
+    }
+
+    debug!("Copying {:?} to {:?}", source, dest);
+    let mut writer =
+        fs::File::create(dest).describe(|| format!("while opening {:?} for writing", dest))?;
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err((e, format!("while reading {:?}", source)).into()),
+        };
+        writer
+            .write_all(&buffer[..read])
+            .describe(|| format!("while writing {:?}", dest))?;
+        progress.add_bytes(read as u64);
+    }
+
+    Ok(())
+}
+
+/// Overwrites the blocks of `dest` which differ from `source`, which must be the same size.
+/// This is a simpler version of rsync's delta transfer, which only helps when bytes were changed in place,
+/// but saves rewriting a huge file when a few bytes of it were edited.
+/// Only the rewritten blocks count as transferred (and towards the bandwidth limit).
+fn update_changed_blocks<P>(
+    reader: &mut fs::File,
+    source: &Path,
+    dest: &Path,
+    progress: &mut CopyProgress<P>,
+) -> Result<(), SyncError>
+where
+    P: ProgressCallback,
+{
+    debug!(
+        "Updating the changed blocks of {:?} from {:?}",
+        dest, source
+    );
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dest)
+        .describe(|| format!("while opening {:?} for writing", dest))?;
+
+    let mut source_block = vec![0; BUFFER_SIZE];
+    let mut dest_block = vec![0; BUFFER_SIZE];
+    let mut changed_blocks = 0;
+    loop {
+        let read = read_block(reader, &mut source_block)
+            .describe(|| format!("while reading {:?}", source))?;
+        if read == 0 {
+            break;
+        }
+        let existing = read_block(&mut file, &mut dest_block)
+            .describe(|| format!("while reading {:?}", dest))?;
+        if existing != read || source_block[..read] != dest_block[..read] {
+            file.seek(SeekFrom::Current(-(existing as i64)))
+                .and_then(|_| file.write_all(&source_block[..read]))
+                .describe(|| format!("while writing {:?}", dest))?;
+            progress.add_bytes(read as u64);
+            changed_blocks += 1;
+        }
+    }
+    // in case `source` shrank while it was being read
+    let length = reader.stream_position()?;
+    file.set_len(length)
+        .describe(|| format!("while truncating {:?}", dest))?;
+
+    debug!("Rewrote {} blocks of {:?}", changed_blocks, dest);
+    Ok(())
+}
+
+/// Checks whether another process has the file locked for writing (see `PropagationOptions::skip_locked`).
+///
+/// Locks are advisory on unix, so only writers which lock the files they are writing are noticed. Both kinds
+/// of lock are checked: `flock` (by trying to take a shared lock, which is released straight away),
+/// and `fcntl` record locks on any part of the file, which is what SQLite takes while it writes to a database.
+/// On Windows a file which another process opened without sharing can't even be opened, so that counts as locked too.
+pub fn is_locked(path: &Path) -> Result<bool, SyncError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+        Err(ref e) if cfg!(windows) && matches!(e.raw_os_error(), Some(32) | Some(33)) => {
+            return Ok(true)
+        }
+        Err(e) => return Err((e, format!("while opening {:?}", path)).into()),
+    };
+    if has_record_lock(&file).describe(|| format!("while checking whether {:?} is locked", path))? {
+        return Ok(true);
+    }
+    match FileExt::try_lock_shared(&file) {
+        Ok(()) => {
+            let _ = FileExt::unlock(&file);
+            Ok(false)
+        }
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => Ok(true),
+        Err(e) => Err((e, format!("while checking whether {:?} is locked", path)).into()),
+    }
+}
+
+/// Checks for an `fcntl` lock for writing on any part of the file, which `flock` doesn't see.
+#[cfg(unix)]
+fn has_record_lock(file: &fs::File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // open file description locks also conflict with this process's own record locks
+    #[cfg(target_os = "linux")]
+    const GET_LOCK: libc::c_int = libc::F_OFD_GETLK;
+    #[cfg(not(target_os = "linux"))]
+    const GET_LOCK: libc::c_int = libc::F_GETLK;
+
+    // asks whether the whole file could be locked for reading, which only conflicts with locks for writing
+    // safety: `flock` is plain data, for which all zeroes (with `l_len` 0 meaning the whole file) is valid
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_RDLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // safety: the descriptor is open for as long as `file` is borrowed, and `lock` outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), GET_LOCK, &mut lock) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(lock.l_type != libc::F_UNLCK as libc::c_short)
+}
+
+/// Record locks are only checked on unix.
+#[cfg(not(unix))]
+fn has_record_lock(_file: &fs::File) -> io::Result<bool> {
+    Ok(false)
+}
@@ -1,88 +1,571 @@
+use std::ffi::OsString;
 use std::fs;
 use std::io;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
 use walkdir::WalkDir;
 
 use crate::archive::{Archive, ArchiveEntries};
+use crate::compare_files::file_contents_equal;
+use crate::config::CancellationToken;
 use crate::detect::Difference;
 use crate::error::{DescribeIoError, SyncError};
 use crate::state::ArchiveEntryPerReplica;
+use crate::util::check_roots_distinct;
 use crate::NumRoots;
+use crate::ReplicaIndex;
 
+mod copy;
 mod progress;
-pub use crate::propagate::progress::{EmptyProgressCallback, ProgressCallback, ToCheck};
+mod trash;
+use crate::propagate::progress::OverallProgress;
+pub use crate::propagate::progress::{
+    parse_from_stdout, EmptyProgressCallback, ProgressCallback, ToCheck,
+};
+pub use crate::propagate::trash::TrashPropagationOptions;
 
-/// Propagates a change from `master` to every other replica.
+/// The directory (inside the destination's parent directory) where rsync keeps partially transferred files
+/// when `PropagationOptions::resume_partial` is enabled. Items with this name are never synced.
+pub const PARTIAL_DIR: &str = ".ubiquity-partial";
+
+/// A single step taken by `propagate` to bring one replica in line with the master.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropagationAction {
+    /// Remove the file or symlink at this path
+    DeleteFile(PathBuf),
+    /// Recursively remove the directory at this path
+    DeleteDirectory(PathBuf),
+    /// Copy the file at `source` to `dest`
+    CopyFile { source: PathBuf, dest: PathBuf },
+    /// Recursively copy the directory at `source` to `dest`
+    CopyDirectory { source: PathBuf, dest: PathBuf },
+    /// Recreate the symlink at `source` at `dest`
+    CopySymlink { source: PathBuf, dest: PathBuf },
+    /// Move the file at `from` to `to`
+    Rename { from: PathBuf, to: PathBuf },
+    /// Make `dest` a hard link to `existing`, which is already another name for the master's file
+    HardLink { existing: PathBuf, dest: PathBuf },
+    /// Give `dest` the same permissions as `source`, whose contents it already matches
+    SetPermissions { source: PathBuf, dest: PathBuf },
+    /// Nothing was done to this path, because it was modified after detection
+    /// and `PropagationOptions::on_path_modified` returned `PathModifiedPolicy::Skip`
+    Skipped(PathBuf),
+}
+
+/// What `propagate` does when an item on one of the replicas has changed since it was detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathModifiedPolicy {
+    /// Return `SyncError::PathModified` before anything is changed
+    Abort,
+    /// Leave that replica alone, but update the others.
+    /// The archive isn't updated, so the item will be detected again by the next sync.
+    Skip,
+    /// Re-read the item's current state and propagate over it anyway, losing the change
+    Retry,
+}
+
+/// A summary of what `propagate` did to the replicas.
+///
+/// A copied or deleted directory counts once, however many items are inside it,
+/// but `bytes_transferred` includes every file inside copied directories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PropagationReport {
+    /// The actions that were performed, including any replicas which were `PropagationAction::Skipped`
+    pub actions: Vec<PropagationAction>,
+    pub files_copied: usize,
+    pub dirs_copied: usize,
+    pub symlinks_copied: usize,
+    pub files_deleted: usize,
+    pub dirs_deleted: usize,
+    pub renamed: usize,
+    pub hard_linked: usize,
+    pub permissions_set: usize,
+    /// The number of bytes the copies reported transferring. Files which were already
+    /// up to date at the destination (or, with `CopyBackend::Native`, blocks of them) aren't counted.
+    pub bytes_transferred: u64,
+    /// Files which weren't copied because another process had them locked (see `PropagationOptions::skip_locked`).
+    /// They are left out of the archive, so they will be reported again by the next sync.
+    pub skipped_locked: Vec<PathBuf>,
+}
+
+impl PropagationReport {
+    /// Counts an action once it has been performed, which reported transferring `transferred` bytes.
+    fn record(&mut self, action: &PropagationAction, transferred: u64) {
+        self.bytes_transferred += transferred;
+        match *action {
+            PropagationAction::DeleteFile(_) => self.files_deleted += 1,
+            PropagationAction::DeleteDirectory(_) => self.dirs_deleted += 1,
+            PropagationAction::CopyFile { .. } => self.files_copied += 1,
+            PropagationAction::CopyDirectory { .. } => self.dirs_copied += 1,
+            PropagationAction::CopySymlink { .. } => self.symlinks_copied += 1,
+            PropagationAction::Rename { .. } => self.renamed += 1,
+            PropagationAction::HardLink { .. } => self.hard_linked += 1,
+            PropagationAction::SetPermissions { .. } => self.permissions_set += 1,
+            PropagationAction::Skipped(_) => {}
+        }
+        self.actions.push(action.clone());
+    }
+}
+
+/// Propagates a change from `master` to every other replica, returning a report of what was done.
+///
+/// If `options.dry_run()` is true, nothing is touched (not even the archive),
+/// and the report only lists the actions that would have been performed.
+///
+/// Returns `SyncError::InvalidReplicaIndex` if `master` isn't one of the difference's replicas,
+/// or `SyncError::DuplicateRoot` if two of the roots are the same directory.
 pub fn propagate<T, P, N>(
     difference: &Difference<N>,
     master: usize,
     archive: &Archive,
     options: &T,
     progress: &P,
-) -> Result<(), SyncError>
+) -> Result<PropagationReport, SyncError>
 where
     T: PropagationOptions,
     P: ProgressCallback,
     N: NumRoots,
 {
+    span!("propagate", path = ?difference.path, master);
+    check_roots_distinct(&difference.roots)?;
+    let mut plan = plan_by_replica(difference, master, options.preserve_hard_links(), |path| {
+        options.on_path_modified(path)
+    })?;
+    let mut skipped = plan.iter().any(|(_, actions)| {
+        actions
+            .iter()
+            .any(|action| matches!(*action, PropagationAction::Skipped(_)))
+    });
+
+    // deletions the options don't allow are left for the next sync, by not updating the archive
+    if !difference.current_state[master].entry_exists() {
+        for (_, replica_actions) in plan.iter_mut() {
+            replica_actions.retain(|action| match *action {
+                PropagationAction::DeleteFile(ref path)
+                | PropagationAction::DeleteDirectory(ref path)
+                    if !options.allow_deletions(path) =>
+                {
+                    info!("Not deleting {:?}", path);
+                    skipped = true;
+                    false
+                }
+                _ => true,
+            });
+        }
+        plan.retain(|(_, replica_actions)| !replica_actions.is_empty());
+    }
+
+    if options.dry_run() {
+        let actions: Vec<PropagationAction> =
+            plan.into_iter().flat_map(|(_, actions)| actions).collect();
+        info!("Dry run, not performing {} actions", actions.len());
+        return Ok(PropagationReport {
+            actions,
+            ..Default::default()
+        });
+    }
+
+    let mut report = PropagationReport::default();
+
+    // checked before anything is done, since the item being replaced is deleted before the file is copied
+    if options.skip_locked()
+        && plan.iter().any(|(_, actions)| {
+            actions
+                .iter()
+                .any(|action| matches!(*action, PropagationAction::CopyFile { .. }))
+        })
+    {
+        let source = difference.absolute_path_for_root(master);
+        if copy::is_locked(&source)? {
+            warn!(
+                "Not copying {:?}, which is locked by another process",
+                source
+            );
+            report.skipped_locked.push(source);
+            return Ok(report);
+        }
+    }
+
+    let overall_progress = OverallProgress::new(progress);
+    progress.start_operation(plan.len());
+    // the replicas which now match the master
+    let mut updated = Vec::new();
+    for (i, (replica, replica_actions)) in plan.iter().enumerate() {
+        span!("propagate_to_replica", replica = *replica);
+        for action in replica_actions {
+            if options.is_cancelled() {
+                return Err(SyncError::Cancelled);
+            }
+            perform_action(action, options, &overall_progress)?;
+            let transferred = overall_progress.transfer_completed();
+            if options.verify_after_copy() {
+                verify_copy(action)?;
+            }
+            report.record(action, transferred);
+        }
+        progress.replica_completed(*replica);
+
+        if !replica_actions
+            .iter()
+            .any(|action| matches!(*action, PropagationAction::Skipped(_)))
+        {
+            updated.push(*replica);
+        }
+        // so that a failure on a later replica doesn't lose track of this one
+        if skipped || i + 1 < plan.len() {
+            record_partial_progress(difference, master, &updated, archive)?;
+        }
+    }
+
+    // Update the archives for this path and its children
+    if skipped {
+        return Ok(report);
+    }
+    let permissions_only = !plan.is_empty()
+        && plan.iter().all(|(_, actions)| {
+            actions
+                .iter()
+                .all(|action| matches!(*action, PropagationAction::SetPermissions { .. }))
+        });
+    if permissions_only {
+        // the contents of a directory haven't been compared yet, so only its own entry is recorded
+        update_archive_entry(&difference.path, archive, difference)?;
+        return Ok(report);
+    }
+    update_archive_for_path(&difference.path, archive, difference)?;
+    if let Some(ref from) = difference.renamed_from {
+        update_archive_for_path(from, archive, difference)?;
+    }
+
+    Ok(report)
+}
+
+/// Works out which actions are needed to propagate a change from `master` to every other replica,
+/// without performing any of them. Files are always copied, never hard linked.
+///
+/// Returns `SyncError::PathModified` if a replica no longer matches the state recorded in `difference`,
+/// or `SyncError::InvalidReplicaIndex` if `master` is out of range.
+pub fn propagate_plan<N>(
+    difference: &Difference<N>,
+    master: usize,
+) -> Result<Vec<PropagationAction>, SyncError>
+where
+    N: NumRoots,
+{
+    Ok(
+        plan_by_replica(difference, master, false, |_| PathModifiedPolicy::Abort)?
+            .into_iter()
+            .flat_map(|(_, actions)| actions)
+            .collect(),
+    )
+}
+
+/// Like `propagate_plan`, but keeps the actions for each replica separate.
+/// Replicas which are already up to date are left out.
+///
+/// `on_modified` decides what happens to replicas which have changed since detection.
+/// If `link_hard_links` is true, files are linked to their `Difference::hard_link_of` where possible.
+fn plan_by_replica<N, F>(
+    difference: &Difference<N>,
+    master: usize,
+    link_hard_links: bool,
+    on_modified: F,
+) -> Result<Vec<(ReplicaIndex, Vec<PropagationAction>)>, SyncError>
+where
+    N: NumRoots,
+    F: Fn(&Path) -> PathModifiedPolicy,
+{
+    if master >= N::to_usize() {
+        return Err(SyncError::InvalidReplicaIndex {
+            index: master,
+            replicas: N::to_usize(),
+        });
+    }
     let master_entry = &difference.current_state[master];
     let master_path = difference.absolute_path_for_root(master);
+    let mut plan = Vec::new();
+
+    if let (Some(size), Some(max_file_size)) = (master_entry.file_size(), difference.max_file_size)
+    {
+        if size > max_file_size {
+            return Err(SyncError::FileTooLarge(master_path));
+        }
+    }
+
+    // special files can neither be copied nor safely replaced
+    if let Some(i) = difference
+        .current_state
+        .iter()
+        .position(|replica| matches!(*replica, ArchiveEntryPerReplica::Other(_)))
+    {
+        return Err(SyncError::UnsupportedFileType(
+            difference.absolute_path_for_root(i),
+        ));
+    }
 
     for (i, replica) in difference.current_state.iter().enumerate() {
         // skip the master
         if i == master {
             continue;
         }
+        let mut actions = Vec::new();
 
         let absolute_path = difference.absolute_path_for_root(i);
-        if replica != &ArchiveEntryPerReplica::from(absolute_path.as_ref()) {
-            return Err(SyncError::PathModified(absolute_path));
-        }
-
-        match *master_entry {
-            ArchiveEntryPerReplica::Empty => match *replica {
-                ArchiveEntryPerReplica::Empty => {}
-                ArchiveEntryPerReplica::File(_) => remove_file(&absolute_path, options)?,
-                ArchiveEntryPerReplica::Directory(_) => {
-                    remove_directory_recursive(&absolute_path, options)?
-                }
-                ArchiveEntryPerReplica::Symlink(_) => unimplemented!(),
-            },
-            ArchiveEntryPerReplica::File(_) => match *replica {
-                ArchiveEntryPerReplica::Empty => {
-                    transfer_file(&master_path, &absolute_path, progress)?
-                }
-                ArchiveEntryPerReplica::File(_) => {
-                    transfer_file(&master_path, &absolute_path, progress)?
-                }
-                ArchiveEntryPerReplica::Directory(_) => {
-                    remove_directory_recursive(&absolute_path, options)?;
-                    transfer_file(&master_path, &absolute_path, progress)?;
+        let current = ArchiveEntryPerReplica::from_path(
+            &absolute_path,
+            difference.detection_mode,
+            difference.follow_symlinks,
+        );
+        let replica = if replica != &current {
+            match on_modified(&absolute_path) {
+                PathModifiedPolicy::Abort => return Err(SyncError::PathModified(absolute_path)),
+                PathModifiedPolicy::Skip => {
+                    plan.push((i, vec![PropagationAction::Skipped(absolute_path)]));
+                    continue;
                 }
-                ArchiveEntryPerReplica::Symlink(_) => unimplemented!(),
-            },
-            ArchiveEntryPerReplica::Directory(_) => match *replica {
-                ArchiveEntryPerReplica::Empty => {
-                    transfer_directory(&master_path, &absolute_path, progress)?
+                PathModifiedPolicy::Retry => {
+                    info!("{:?} was modified, replacing it anyway", absolute_path);
+                    &current
                 }
-                ArchiveEntryPerReplica::File(_) => {
-                    remove_file(&absolute_path, options)?;
-                    transfer_directory(&master_path, &absolute_path, progress)?;
+            }
+        } else {
+            replica
+        };
+
+        // renamed files are moved into place rather than copied again
+        if let Some(ref renamed_from) = difference.renamed_from {
+            if replica == &ArchiveEntryPerReplica::Empty {
+                let from = difference.roots[i].join(renamed_from);
+                match ArchiveEntryPerReplica::from_path(
+                    &from,
+                    difference.detection_mode,
+                    difference.follow_symlinks,
+                ) {
+                    ArchiveEntryPerReplica::File(_) => {
+                        actions.push(PropagationAction::Rename {
+                            from,
+                            to: absolute_path,
+                        });
+                        plan.push((i, actions));
+                        continue;
+                    }
+                    _ => match on_modified(&from) {
+                        PathModifiedPolicy::Abort => return Err(SyncError::PathModified(from)),
+                        PathModifiedPolicy::Skip => {
+                            plan.push((i, vec![PropagationAction::Skipped(from)]));
+                            continue;
+                        }
+                        // copy the file from the master instead
+                        PathModifiedPolicy::Retry => {}
+                    },
                 }
-                ArchiveEntryPerReplica::Directory(_) => {
-                    remove_directory_recursive(&absolute_path, options)?;
-                    transfer_directory(&master_path, &absolute_path, progress)?;
+            }
+        }
+
+        // nothing needs to be copied if only the permissions differ (see `SyncInfo::compare_permissions`)
+        if only_permissions_differ(master_entry, replica, &master_path, &absolute_path)? {
+            actions.push(PropagationAction::SetPermissions {
+                source: master_path.clone(),
+                dest: absolute_path,
+            });
+            plan.push((i, actions));
+            continue;
+        }
+
+        // first remove whatever is in the way
+        match (master_entry, replica) {
+            (_, ArchiveEntryPerReplica::Empty) => {}
+            // files are overwritten in place
+            (ArchiveEntryPerReplica::File(_), ArchiveEntryPerReplica::File(_)) => {}
+            (_, ArchiveEntryPerReplica::File(_))
+            | (_, ArchiveEntryPerReplica::Symlink(_))
+            | (_, ArchiveEntryPerReplica::Other(_)) => {
+                actions.push(PropagationAction::DeleteFile(absolute_path.clone()))
+            }
+            (_, ArchiveEntryPerReplica::Directory(_)) => {
+                actions.push(PropagationAction::DeleteDirectory(absolute_path.clone()))
+            }
+        }
+
+        // then copy the master's version across
+        let source = master_path.clone();
+        let dest = absolute_path;
+        match *master_entry {
+            // special files were rejected above
+            ArchiveEntryPerReplica::Empty | ArchiveEntryPerReplica::Other(_) => {}
+            ArchiveEntryPerReplica::File(_) => {
+                let existing = if link_hard_links && replica == &ArchiveEntryPerReplica::Empty {
+                    hard_link_partner(difference, master, i)?
+                } else {
+                    None
+                };
+                match existing {
+                    Some(existing) => actions.push(PropagationAction::HardLink { existing, dest }),
+                    None => actions.push(PropagationAction::CopyFile { source, dest }),
                 }
-                ArchiveEntryPerReplica::Symlink(_) => unimplemented!(),
-            },
-            ArchiveEntryPerReplica::Symlink(_) => unimplemented!(),
-        };
+            }
+            ArchiveEntryPerReplica::Directory(_) => {
+                actions.push(PropagationAction::CopyDirectory { source, dest })
+            }
+            ArchiveEntryPerReplica::Symlink(_) => {
+                actions.push(PropagationAction::CopySymlink { source, dest })
+            }
+        }
+        if !actions.is_empty() {
+            plan.push((i, actions));
+        }
     }
 
-    // Update the archives for this path and its children
-    update_archive_for_path::<N>(&difference.path, archive, &difference.roots)?;
+    Ok(plan)
+}
+
+/// Checks if the replica's item only needs the master's permissions: directories with different permissions
+/// (whose contents are synced separately), or files with the same size, modification time and contents.
+fn only_permissions_differ(
+    master_entry: &ArchiveEntryPerReplica,
+    replica: &ArchiveEntryPerReplica,
+    master_path: &Path,
+    replica_path: &Path,
+) -> Result<bool, SyncError> {
+    match (master_entry, replica) {
+        (ArchiveEntryPerReplica::Directory(_), ArchiveEntryPerReplica::Directory(_)) => {
+            Ok(master_entry.permissions_mode() != replica.permissions_mode())
+        }
+        (ArchiveEntryPerReplica::File(_), ArchiveEntryPerReplica::File(_))
+            if master_entry.permissions_mode() != replica.permissions_mode()
+                && master_entry.file_size() == replica.file_size()
+                && master_entry.modification_time() == replica.modification_time() =>
+        {
+            Ok(file_contents_equal(master_path, replica_path)
+                .describe(|| format!("while comparing {:?} and {:?}", master_path, replica_path))?)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The path on `replica` of a hard link to the master's file, if it has already been synced
+/// so that linking to it gives the same result as a copy.
+fn hard_link_partner<N>(
+    difference: &Difference<N>,
+    master: usize,
+    replica: ReplicaIndex,
+) -> Result<Option<PathBuf>, SyncError>
+where
+    N: NumRoots,
+{
+    let partner = match difference.hard_link_of.get(master) {
+        Some(Some(partner)) => partner,
+        _ => return Ok(None),
+    };
+    let existing = difference.roots[replica].join(partner);
+    match fs::symlink_metadata(&existing) {
+        Ok(ref metadata) if metadata.is_file() => {}
+        _ => return Ok(None),
+    }
+    let master_path = difference.absolute_path_for_root(master);
+    if file_contents_equal(&master_path, &existing)
+        .describe(|| format!("while comparing {:?} and {:?}", master_path, existing))?
+    {
+        Ok(Some(existing))
+    } else {
+        Ok(None)
+    }
+}
+
+fn perform_action<T, P>(
+    action: &PropagationAction,
+    options: &T,
+    progress: &P,
+) -> Result<(), SyncError>
+where
+    T: PropagationOptions,
+    P: ProgressCallback,
+{
+    match *action {
+        PropagationAction::DeleteFile(ref path) => remove_file(path, options),
+        PropagationAction::DeleteDirectory(ref path) => remove_directory_recursive(path, options),
+        PropagationAction::CopyFile {
+            ref source,
+            ref dest,
+        } => transfer_file(source, dest, options, progress),
+        PropagationAction::CopyDirectory {
+            ref source,
+            ref dest,
+        } => transfer_directory(source, dest, options, progress),
+        PropagationAction::CopySymlink {
+            ref source,
+            ref dest,
+        } => transfer_symlink(source, dest),
+        PropagationAction::Rename { ref from, ref to } => rename_file(from, to),
+        PropagationAction::HardLink {
+            ref existing,
+            ref dest,
+        } => {
+            info!("Linking {:?} to {:?}", dest, existing);
+            fs::hard_link(existing, dest)
+                .describe(|| format!("while linking {:?} to {:?}", dest, existing))?;
+            Ok(())
+        }
+        PropagationAction::SetPermissions {
+            ref source,
+            ref dest,
+        } => {
+            info!("Copying the permissions of {:?} to {:?}", source, dest);
+            let permissions = fs::metadata(source)
+                .describe(|| format!("while reading metadata of {:?}", source))?
+                .permissions();
+            fs::set_permissions(dest, permissions)
+                .describe(|| format!("while setting permissions of {:?}", dest))?;
+            Ok(())
+        }
+        PropagationAction::Skipped(_) => Ok(()),
+    }
+}
 
+/// Checks that the files copied by `action` are the same size as the originals.
+fn verify_copy(action: &PropagationAction) -> Result<(), SyncError> {
+    match *action {
+        PropagationAction::CopyFile {
+            ref source,
+            ref dest,
+        } => verify_file_size(source, dest),
+        PropagationAction::CopyDirectory {
+            ref source,
+            ref dest,
+        } => {
+            for entry in WalkDir::new(source).min_depth(1) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    let relative = entry.path().strip_prefix(source).unwrap();
+                    verify_file_size(entry.path(), &dest.join(relative))?;
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn verify_file_size(source: &Path, dest: &Path) -> Result<(), SyncError> {
+    let source_size = fs::metadata(source)
+        .describe(|| format!("while verifying {:?}", source))?
+        .len();
+    let dest_size = match fs::metadata(dest) {
+        Ok(metadata) => metadata.len(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(SyncError::VerificationFailed(dest.to_path_buf()))
+        }
+        Err(e) => return Err((e, format!("while verifying {:?}", dest)).into()),
+    };
+    if source_size != dest_size {
+        warn!(
+            "{:?} is {} bytes, but its copy at {:?} is {} bytes",
+            source, source_size, dest, dest_size
+        );
+        return Err(SyncError::VerificationFailed(dest.to_path_buf()));
+    }
     Ok(())
 }
 
@@ -90,20 +573,43 @@ fn remove_file<T>(path: &Path, options: &T) -> Result<(), SyncError>
 where
     T: PropagationOptions,
 {
-    if !options.should_remove(path) {
+    if !options.should_remove_file(path) {
         return Err(SyncError::Cancelled);
     }
 
     info!("Removing file {:?}", path);
     // delegate the actual removal to a callback function
-    options.remove_file(path)
+    options.remove_file(path)?;
+    if options.resume_partial() {
+        remove_partial_file(path)?;
+    }
+    Ok(())
+}
+
+/// Removes a partial transfer of `path` left behind by rsync, and `PARTIAL_DIR` if nothing else is in it.
+fn remove_partial_file(path: &Path) -> Result<(), SyncError> {
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => return Ok(()),
+    };
+    let partial_dir = parent.join(PARTIAL_DIR);
+    let partial_file = partial_dir.join(name);
+    if partial_file.is_file() {
+        info!("Removing stale partial file {:?}", partial_file);
+        fs::remove_file(&partial_file)
+            .describe(|| format!("while removing partial file {:?}", partial_file))?;
+    }
+    if partial_dir.is_dir() && fs::read_dir(&partial_dir)?.next().is_none() {
+        fs::remove_dir(&partial_dir).describe(|| format!("while removing {:?}", partial_dir))?;
+    }
+    Ok(())
 }
 
 fn remove_directory_recursive<T>(path: &Path, options: &T) -> Result<(), SyncError>
 where
     T: PropagationOptions,
 {
-    if !options.should_remove(path) {
+    if !options.should_remove_dir(path) {
         return Err(SyncError::Cancelled);
     }
 
@@ -112,95 +618,299 @@ where
     options.remove_dir_all(path)
 }
 
-fn transfer_file<P>(source: &Path, dest: &Path, progress: &P) -> Result<(), SyncError>
+fn transfer_file<T, P>(
+    source: &Path,
+    dest: &Path,
+    options: &T,
+    progress: &P,
+) -> Result<(), SyncError>
 where
+    T: PropagationOptions,
     P: ProgressCallback,
 {
+    span!(
+        "transfer_file",
+        source = ?source,
+        dest = ?dest,
+        bytes = source.metadata().map_or(0, |metadata| metadata.len()),
+    );
     let parent = dest.parent().unwrap();
     if !parent.exists() {
         info!("Creating parent directory {:?}", parent);
         fs::create_dir_all(parent)?;
     }
     info!("Transferring file {:?} to {:?}", source, dest);
-    run_rsync(source, dest, progress)
-    //.describe(|| format!("while copying file from {:?} to {:?}", source, dest))?;
+    if options.prefer_reflink() && copy::reflink_file(source, dest, options, progress)? {
+        return Ok(());
+    }
+    match options.copy_backend() {
+        CopyBackend::Rsync => run_rsync(source, dest, options, progress),
+        CopyBackend::Native => copy::copy_file(source, dest, options, progress),
+    }
 }
 
-fn transfer_directory<P>(source: &Path, dest: &Path, progress: &P) -> Result<(), SyncError>
+fn transfer_directory<T, P>(
+    source: &Path,
+    dest: &Path,
+    options: &T,
+    progress: &P,
+) -> Result<(), SyncError>
 where
+    T: PropagationOptions,
     P: ProgressCallback,
 {
-    fs::create_dir_all(dest)?;
+    span!("transfer_directory", source = ?source, dest = ?dest);
+    // created up front, so that an empty directory exists however the backend treats it
+    fs::create_dir_all(dest).describe(|| format!("while creating directory {:?}", dest))?;
 
     info!("Copying directory {:?} to {:?}", source, dest);
-    run_rsync(source, dest, progress)
-    //.describe(|| format!("while copying directory from {:?} to {:?}", source, dest))?;
+    match options.copy_backend() {
+        CopyBackend::Rsync => run_rsync(source, dest, options, progress),
+        CopyBackend::Native => copy::copy_directory(source, dest, options, progress),
+    }
+}
+
+/// Recreates the symlink at `source` so that `dest` points to the same (unmodified) target.
+fn transfer_symlink(source: &Path, dest: &Path) -> Result<(), SyncError> {
+    let parent = dest.parent().unwrap();
+    if !parent.exists() {
+        info!("Creating parent directory {:?}", parent);
+        fs::create_dir_all(parent)?;
+    }
+    let target =
+        fs::read_link(source).describe(|| format!("while reading symlink at {:?}", source))?;
+    info!("Creating symlink {:?} -> {:?}", dest, target);
+    create_symlink(&target, dest).describe(|| format!("while creating symlink at {:?}", dest))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dest)
 }
 
-fn run_rsync<P>(source: &Path, dest: &Path, progress: &P) -> Result<(), SyncError>
+/// Windows distinguishes between symlinks to files and to directories,
+/// so the kind of link is chosen by looking at what the target currently is.
+#[cfg(windows)]
+fn create_symlink(target: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    if dest.parent().unwrap().join(target).is_dir() {
+        symlink_dir(target, dest)
+    } else {
+        symlink_file(target, dest)
+    }
+}
+
+fn run_rsync<T, P>(source: &Path, dest: &Path, options: &T, progress: &P) -> Result<(), SyncError>
 where
+    T: PropagationOptions,
     P: ProgressCallback,
 {
-    let rsync = "rsync";
+    span!("run_rsync", source = ?source, dest = ?dest);
+    let rsync = options.rsync_path();
     let append_slash = source.metadata()?.is_dir();
     let mut source_str = source.to_string_lossy().into_owned();
     if append_slash {
-        source_str.push_str("/");
+        source_str.push('/');
     }
     let mut command = process::Command::new(rsync);
+    let command = command.arg("-a").arg("--info=progress2,name1");
+    if options.preserve_xattrs() {
+        command.arg("-X");
+    }
+    if options.preserve_hard_links() {
+        command.arg("-H");
+    }
+    if let Some(limit) = options.bandwidth_limit_kbps() {
+        command.arg(format!("--bwlimit={}", limit));
+    }
+    if options.resume_partial() {
+        command
+            .arg("--partial")
+            .arg(format!("--partial-dir={}", PARTIAL_DIR));
+    }
+    if let Some(suffix) = options.backup_suffix() {
+        command.arg("--backup").arg(format!("--suffix={}", suffix));
+    }
+    command.args(options.extra_rsync_args());
     let command = command
-        .arg("-a")
-        .arg("--info=progress2")
         .arg(source_str)
         .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
         .arg(dest.to_string_lossy().as_ref());
     let mut command = match command.spawn() {
         Ok(command) => command,
         Err(err) => match err.kind() {
-            io::ErrorKind::NotFound => return Err(SyncError::RsyncNotFound(rsync.to_owned())),
+            io::ErrorKind::NotFound => {
+                return Err(SyncError::RsyncNotFound(
+                    rsync.to_string_lossy().into_owned(),
+                ))
+            }
             _ => return Err(err.into()),
         },
     };
 
+    // stderr is read on another thread, otherwise rsync could block writing to a full stderr pipe
+    // while we wait for more progress on stdout
+    let mut stderr = command.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut output = String::new();
+        stderr.read_to_string(&mut output).map(|_| output)
+    });
+
     {
         let stdout = command.stdout.as_mut().unwrap();
         let reader = io::BufReader::new(stdout);
 
-        progress::parse_from_stdout(reader, progress)?;
+        // rsync prints names relative to the directory being copied, or just the name of a single file
+        let source_directory = if append_slash {
+            source
+        } else {
+            source.parent().unwrap_or(source)
+        };
+        if let Err(e) = progress::parse_transfer_output(reader, progress, source_directory) {
+            // rsync is stopped and reaped, rather than being left running (or as a zombie) without a reader
+            let _ = command.kill();
+            let _ = command.wait();
+            let _ = stderr_reader.join();
+            return Err(e.into());
+        }
     }
 
     let status = command.wait()?;
-    println!("{}", status);
+    let stderr = stderr_reader
+        .join()
+        .map_err(|_| io::Error::other("the thread reading rsync's stderr panicked"))??;
+    debug!("rsync exited with {}", status);
     if !status.success() {
-        panic!("Error in rsync");
+        return Err(SyncError::RsyncFailed {
+            status,
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+            stderr,
+        });
+    }
+    if !stderr.trim().is_empty() {
+        warn!("rsync: {}", stderr.trim());
+    }
+
+    Ok(())
+}
+
+/// Moves `from` to `to`, creating the parent directory of `to` if needed.
+fn rename_file(from: &Path, to: &Path) -> Result<(), SyncError> {
+    let parent = to.parent().unwrap();
+    if !parent.exists() {
+        info!("Creating parent directory {:?}", parent);
+        fs::create_dir_all(parent)?;
+    }
+    info!("Renaming {:?} to {:?}", from, to);
+    fs::rename(from, to).describe(|| format!("while renaming {:?} to {:?}", from, to))?;
+    Ok(())
+}
+
+/// Records which replicas have been brought up to date so far, in case propagation stops before the rest are.
+///
+/// The archive entry is arranged so that the next sync sees the item as changed on the master alone
+/// (and propagates it again), rather than on every updated replica (which would be a conflict):
+/// the master keeps its previous state, the updated replicas get their new state,
+/// and the rest keep the state they were detected in.
+///
+/// Renames aren't recorded until every replica has been renamed.
+fn record_partial_progress<N>(
+    difference: &Difference<N>,
+    master: ReplicaIndex,
+    updated: &[ReplicaIndex],
+    archive: &Archive,
+) -> Result<(), SyncError>
+where
+    N: NumRoots,
+{
+    if difference.renamed_from.is_some() || updated.is_empty() {
+        return Ok(());
+    }
+    let mut replicas = difference.current_state.clone();
+    replicas[master] = difference
+        .previous_state
+        .as_ref()
+        .map_or(ArchiveEntryPerReplica::Empty, |previous| {
+            previous[master].clone()
+        });
+    for &i in updated {
+        replicas[i] = ArchiveEntryPerReplica::from_path(
+            &difference.absolute_path_for_root(i),
+            difference.detection_mode,
+            difference.follow_symlinks,
+        );
     }
 
+    let mut archive_file = archive.for_directory(difference.path.parent().unwrap());
+    // held from reading until writing, so that another process's changes aren't overwritten
+    archive_file.lock_exclusive()?;
+    let mut entries: ArchiveEntries<N> = archive_file.read()?;
+    debug!(
+        "Recording that {:?} was propagated to replicas {:?}",
+        difference.path, updated
+    );
+    entries.insert(&difference.path, replicas);
+    archive_file.write(&mut entries)?;
+    Ok(())
+}
+
+/// Records the current state of `relative_path` on every replica, without touching the entries of its contents.
+fn update_archive_entry<N>(
+    relative_path: &Path,
+    archive: &Archive,
+    difference: &Difference<N>,
+) -> Result<(), SyncError>
+where
+    N: NumRoots,
+{
+    let mut archive_file = archive.for_directory(relative_path.parent().unwrap());
+    archive_file.lock_exclusive()?;
+    let mut entries: ArchiveEntries<N> = archive_file.read()?;
+    let replicas = ArchiveEntryPerReplica::from_roots::<N>(
+        &difference.roots,
+        relative_path,
+        difference.detection_mode,
+        difference.follow_symlinks,
+    );
+    entries.insert(relative_path, replicas);
+    archive_file.write(&mut entries)?;
     Ok(())
 }
 
 /// Look at the archives in this path, and if it is a directory remove all descendants.
+/// Then record the new state of `relative_path` (and its contents) on every replica.
+///
+/// This is done once every replica has been updated. Until then, `record_partial_progress` keeps track of
+/// the replicas which have been, so that a failure part way through leaves an accurate archive behind.
 fn update_archive_for_path<N>(
     relative_path: &Path,
     archive: &Archive,
-    roots: &[PathBuf],
+    difference: &Difference<N>,
 ) -> Result<(), SyncError>
 where
     N: NumRoots,
 {
+    let roots = &difference.roots;
+    let mode = difference.detection_mode;
+    let follow_symlinks = difference.follow_symlinks;
     let directory = relative_path.parent().unwrap();
     let mut archive_file = archive.for_directory(directory);
+    archive_file.lock_exclusive()?;
     let mut entries: ArchiveEntries<N> = archive_file.read()?;
 
     // remove old archive information (only needed when `relative_path` is a directory)
     {
         let replicas = entries.get(relative_path);
         if let Some(replicas) = replicas {
-            let is_dir = any_directories_in(&replicas);
+            let is_dir = any_directories_in(replicas);
 
             if is_dir {
                 debug!("There are descendant directories inside {:?} that need to be cleared from the archive", relative_path);
                 let mut stack = Vec::new();
-                stack.push(Archive::hash(relative_path));
+                stack.push(archive.hash(relative_path));
                 while let Some(item) = stack.pop() {
                     trace!(
                         "Scanning archive file {:?} for descendant directories",
@@ -210,9 +920,9 @@ where
                     let entries: ArchiveEntries<N> = archive_file.read()?;
 
                     let dirs = entries
-                        .iter()
-                        .filter(|&(_, replicas)| any_directories_in(&replicas))
-                        .map(|(hash, _)| *hash);
+                        .iter_hashed()
+                        .filter(|&(_, replicas)| any_directories_in(replicas))
+                        .map(|(hash, _)| hash);
                     for dir in dirs {
                         stack.push(dir);
                     }
@@ -233,15 +943,24 @@ where
     info!("Updating {:?} in {}", relative_path, archive_file);
 
     // update archives for this exact path
-    let replicas = ArchiveEntryPerReplica::from_roots::<N>(&roots, relative_path);
+    let replicas =
+        ArchiveEntryPerReplica::from_roots::<N>(roots, relative_path, mode, follow_symlinks);
     entries.insert(relative_path, replicas);
     archive_file.write(&mut entries)?;
 
     // update archives for children of this path, only if it is a directory
     let first_root = roots[0].join(relative_path);
     if first_root.is_dir() {
-        for entry in WalkDir::new(&first_root) {
-            let entry = entry?;
+        for entry in WalkDir::new(&first_root).follow_links(follow_symlinks) {
+            let entry = match entry {
+                // walkdir keeps track of the directories it has visited, so a symlink loop is reported
+                // rather than followed forever
+                Err(ref e) if e.loop_ancestor().is_some() => {
+                    info!("Not following symlink loop: {}", e);
+                    continue;
+                }
+                entry => entry?,
+            };
             if entry.metadata()?.is_dir() {
                 let dir_relative_path =
                     relative_path.join(entry.path().strip_prefix(&first_root).unwrap().as_os_str());
@@ -249,10 +968,20 @@ where
 
                 for entry in entry.path().read_dir()? {
                     let entry = entry?;
-                    if !entry.metadata()?.is_dir() {
+                    let is_dir = if follow_symlinks {
+                        entry.path().is_dir()
+                    } else {
+                        entry.metadata()?.is_dir()
+                    };
+                    if !is_dir {
                         let child_path = relative_path
                             .join(entry.path().strip_prefix(&first_root).unwrap().as_os_str());
-                        let replicas = ArchiveEntryPerReplica::from_roots::<N>(&roots, &child_path);
+                        let replicas = ArchiveEntryPerReplica::from_roots::<N>(
+                            roots,
+                            &child_path,
+                            mode,
+                            follow_symlinks,
+                        );
                         entries.insert(&child_path, replicas)
                     }
                 }
@@ -269,20 +998,36 @@ where
 
 /// Searches to see if a directory exists at any of the replicas
 fn any_directories_in(replicas: &[ArchiveEntryPerReplica]) -> bool {
-    replicas.iter().any(|replica| {
-        if let ArchiveEntryPerReplica::Directory(_) = *replica {
-            true
-        } else {
-            false
-        }
-    })
+    replicas
+        .iter()
+        .any(|replica| matches!(*replica, ArchiveEntryPerReplica::Directory(_)))
+}
+
+/// The mechanism used to copy files and directories between replicas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyBackend {
+    /// Spawn the `rsync` executable (see `PropagationOptions::rsync_path`).
+    Rsync,
+    /// Copy in-process using `std::fs`, for systems without rsync.
+    Native,
 }
 
 /// PropagationOptions allow the client to customize how files are transferred/deleted.
 pub trait PropagationOptions {
-    /// return false to cancel deleting a directory
+    /// return false to cancel deleting a file or directory.
+    /// This is used by the default implementations of `should_remove_file` and `should_remove_dir`.
     fn should_remove(&self, _: &Path) -> bool;
 
+    /// return false to cancel deleting a file or symlink
+    fn should_remove_file(&self, path: &Path) -> bool {
+        self.should_remove(path)
+    }
+
+    /// return false to cancel recursively deleting a directory
+    fn should_remove_dir(&self, path: &Path) -> bool {
+        self.should_remove(path)
+    }
+
     /// return `SyncError::Cancelled` to cancel deleting the file,
     /// otherwise delete the file/move it to the trash.
     /// This must return an error if the file was not removed successfully.
@@ -293,6 +1038,119 @@ pub trait PropagationOptions {
     /// Ignoring errors will mean that  writes to the archive files when
     /// the replicas are still out of sync, resulting in an inconsistent state.
     fn remove_dir_all(&self, _: &Path) -> Result<(), SyncError>;
+
+    /// The rsync executable used to transfer files.
+    /// Defaults to `rsync`, which is looked up in `PATH`.
+    fn rsync_path(&self) -> &Path {
+        Path::new("rsync")
+    }
+
+    /// How files are copied to other replicas. Defaults to `CopyBackend::Rsync`.
+    fn copy_backend(&self) -> CopyBackend {
+        CopyBackend::Rsync
+    }
+
+    /// Whether modification times and permissions are copied along with file contents.
+    /// rsync always preserves these (`-a`), so this only affects `CopyBackend::Native`.
+    fn preserve_metadata(&self) -> bool {
+        true
+    }
+
+    /// Whether extended attributes (eg: macOS Finder tags) are copied along with file contents.
+    /// This is passed to rsync as `-X`. Filesystems which don't support them only log a warning.
+    /// Defaults to false.
+    fn preserve_xattrs(&self) -> bool {
+        false
+    }
+
+    /// Whether files which are hard links to each other stay linked on the other replicas.
+    /// Inside copied directories this is passed to rsync as `-H`, and individual files are linked to
+    /// their `Difference::hard_link_of` (see `SyncInfo::detect_hard_links`) instead of being copied again.
+    /// Defaults to false.
+    fn preserve_hard_links(&self) -> bool {
+        false
+    }
+
+    /// When true, `propagate` only reports the actions it would perform,
+    /// without touching the replicas or the archive.
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    /// Checked by `propagate` before each action (and by the native copy backend before each file),
+    /// return true to stop with `SyncError::Cancelled`. See `config::CancellationToken`.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+
+    /// The maximum transfer speed in KiB per second, or `None` (the default) for no limit.
+    /// This is passed to rsync as `--bwlimit`, and the native copy backend throttles itself to match.
+    fn bandwidth_limit_kbps(&self) -> Option<u32> {
+        None
+    }
+
+    /// Called when the item at `path` has changed since it was detected. Defaults to `PathModifiedPolicy::Abort`.
+    fn on_path_modified(&self, _: &Path) -> PathModifiedPolicy {
+        PathModifiedPolicy::Abort
+    }
+
+    /// When true, the sizes of copied files are compared with the originals before the archive is updated,
+    /// returning `SyncError::VerificationFailed` if they differ. Defaults to false.
+    fn verify_after_copy(&self) -> bool {
+        false
+    }
+
+    /// Return false to keep the item at `path` instead of deleting it, because it was deleted on the master replica.
+    /// The item is left out of the archive, so it will be reported again by the next sync.
+    ///
+    /// Unlike `should_remove`, this doesn't apply to items which are replaced by the master's version.
+    fn allow_deletions(&self, _: &Path) -> bool {
+        true
+    }
+
+    /// When true, rsync keeps partially transferred files in `PARTIAL_DIR` (`--partial --partial-dir`),
+    /// so that copying a large file can resume after an interruption instead of starting again.
+    /// Partial files left behind by an item which is later deleted are cleaned up along with it.
+    ///
+    /// `CopyBackend::Native` doesn't resume transfers yet. It should only do so when it can tell the existing part
+    /// of the destination is a prefix of the source, eg: by comparing its size and contents.
+    fn resume_partial(&self) -> bool {
+        false
+    }
+
+    /// Extra arguments for rsync (eg: `--checksum`), placed before the source and destination.
+    /// Only used by `CopyBackend::Rsync`.
+    fn extra_rsync_args(&self) -> Vec<OsString> {
+        Vec::new()
+    }
+
+    /// When set (eg: to `Some("~".into())`), a file which is overwritten by the master's version is first kept
+    /// alongside it, with this suffix appended to its name. This is passed to rsync as `--backup --suffix`.
+    ///
+    /// The backups are ordinary files, so they will be synced like any other unless they are ignored.
+    /// Defaults to `None`.
+    fn backup_suffix(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether files are cloned with a reflink (copy-on-write), which shares their data instead of copying it,
+    /// before falling back to the `copy_backend`. This only works when the replicas are on the same filesystem,
+    /// and it supports reflinks (eg: btrfs, XFS or APFS), and it doesn't apply to the contents of copied directories.
+    /// Defaults to false.
+    fn prefer_reflink(&self) -> bool {
+        false
+    }
+
+    /// When true, a file which another process has locked for writing (eg: a database) isn't propagated,
+    /// so that a half-written copy doesn't end up on the other replicas. None of the replicas are touched:
+    /// the file is listed in `PropagationReport::skipped_locked` instead, and picked up again by the next sync.
+    ///
+    /// This only applies to individual files, not the contents of copied directories. Except on Windows,
+    /// locks are advisory, so only writers which take `flock` or `fcntl` locks (as SQLite does) are noticed.
+    /// Defaults to false.
+    fn skip_locked(&self) -> bool {
+        false
+    }
 }
 
 /// A zero-sized struct with a simple implementation of PropagationOptions.
@@ -311,3 +1169,120 @@ impl PropagationOptions for DefaultPropagationOptions {
         Ok(())
     }
 }
+
+/// An implementation of `PropagationOptions` which is configured through its fields,
+/// for clients who don't need to customize how files are removed.
+#[derive(Debug, Clone)]
+pub struct BasicPropagationOptions {
+    pub rsync_path: PathBuf,
+    pub copy_backend: CopyBackend,
+    pub preserve_metadata: bool,
+    /// See `PropagationOptions::preserve_xattrs`
+    pub preserve_xattrs: bool,
+    /// See `PropagationOptions::preserve_hard_links`
+    pub preserve_hard_links: bool,
+    pub dry_run: bool,
+    pub cancellation: CancellationToken,
+    /// See `PropagationOptions::bandwidth_limit_kbps`
+    pub bandwidth_limit_kbps: Option<u32>,
+    /// See `PropagationOptions::extra_rsync_args`
+    pub extra_rsync_args: Vec<OsString>,
+    /// See `PropagationOptions::verify_after_copy`
+    pub verify_after_copy: bool,
+    /// See `PropagationOptions::on_path_modified`
+    pub on_path_modified: PathModifiedPolicy,
+    /// See `PropagationOptions::resume_partial`
+    pub resume_partial: bool,
+    /// If false, items deleted from the master replica are kept on the others (see `PropagationOptions::allow_deletions`)
+    pub allow_deletions: bool,
+    /// See `PropagationOptions::backup_suffix`
+    pub backup_suffix: Option<String>,
+    /// See `PropagationOptions::prefer_reflink`
+    pub prefer_reflink: bool,
+    /// See `PropagationOptions::skip_locked`
+    pub skip_locked: bool,
+}
+
+impl Default for BasicPropagationOptions {
+    fn default() -> Self {
+        BasicPropagationOptions {
+            rsync_path: PathBuf::from("rsync"),
+            copy_backend: CopyBackend::Rsync,
+            preserve_metadata: true,
+            preserve_xattrs: false,
+            preserve_hard_links: false,
+            dry_run: false,
+            cancellation: CancellationToken::new(),
+            bandwidth_limit_kbps: None,
+            extra_rsync_args: Vec::new(),
+            verify_after_copy: false,
+            on_path_modified: PathModifiedPolicy::Abort,
+            resume_partial: false,
+            allow_deletions: true,
+            backup_suffix: None,
+            prefer_reflink: false,
+            skip_locked: false,
+        }
+    }
+}
+
+impl PropagationOptions for BasicPropagationOptions {
+    fn should_remove(&self, path: &Path) -> bool {
+        DefaultPropagationOptions.should_remove(path)
+    }
+    fn remove_file(&self, path: &Path) -> Result<(), SyncError> {
+        DefaultPropagationOptions.remove_file(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> Result<(), SyncError> {
+        DefaultPropagationOptions.remove_dir_all(path)
+    }
+    fn rsync_path(&self) -> &Path {
+        &self.rsync_path
+    }
+    fn copy_backend(&self) -> CopyBackend {
+        self.copy_backend
+    }
+    fn preserve_metadata(&self) -> bool {
+        self.preserve_metadata
+    }
+    fn preserve_xattrs(&self) -> bool {
+        self.preserve_xattrs
+    }
+
+    fn preserve_hard_links(&self) -> bool {
+        self.preserve_hard_links
+    }
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+    fn bandwidth_limit_kbps(&self) -> Option<u32> {
+        self.bandwidth_limit_kbps
+    }
+    fn extra_rsync_args(&self) -> Vec<OsString> {
+        self.extra_rsync_args.clone()
+    }
+    fn on_path_modified(&self, _: &Path) -> PathModifiedPolicy {
+        self.on_path_modified
+    }
+    fn verify_after_copy(&self) -> bool {
+        self.verify_after_copy
+    }
+    fn allow_deletions(&self, _: &Path) -> bool {
+        self.allow_deletions
+    }
+    fn resume_partial(&self) -> bool {
+        self.resume_partial
+    }
+    fn backup_suffix(&self) -> Option<String> {
+        self.backup_suffix.clone()
+    }
+    fn prefer_reflink(&self) -> bool {
+        self.prefer_reflink
+    }
+    fn skip_locked(&self) -> bool {
+        self.skip_locked
+    }
+}
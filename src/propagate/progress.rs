@@ -1,5 +1,7 @@
+use std::cell::Cell;
 use std::io;
 use std::io::BufRead;
+use std::path::{Path, PathBuf};
 
 /// Handles progress updates for the propagation step.
 pub trait ProgressCallback {
@@ -13,6 +15,19 @@ pub trait ProgressCallback {
         transferred: Option<u32>,
         to_check: Option<ToCheck>,
     );
+
+    /// Called with the path of each file (on the master replica) just before it is copied.
+    fn current_file(&self, _path: &Path) {}
+
+    /// Called by `propagate` before it starts updating `total_replicas` replicas.
+    fn start_operation(&self, _total_replicas: usize) {}
+
+    /// Called by `propagate` once the replica at `index` has been updated.
+    fn replica_completed(&self, _index: usize) {}
+
+    /// The number of bytes transferred so far by the current call to `propagate`,
+    /// which (unlike `rsync_progress`) doesn't reset between rsync invocations.
+    fn overall_progress(&self, _transferred_bytes: u64) {}
 }
 
 /// A zero-sized struct with an empty implementation of ProgressCallback
@@ -31,6 +46,60 @@ impl ProgressCallback for EmptyProgressCallback {
     }
 }
 
+/// Forwards progress to another callback, keeping a running total of the bytes transferred
+/// across several transfers for `ProgressCallback::overall_progress`.
+pub(crate) struct OverallProgress<'a, P: ProgressCallback> {
+    inner: &'a P,
+    completed_bytes: Cell<u64>,
+    current_bytes: Cell<u64>,
+}
+
+impl<'a, P: ProgressCallback> OverallProgress<'a, P> {
+    pub fn new(inner: &'a P) -> Self {
+        OverallProgress {
+            inner,
+            completed_bytes: Cell::new(0),
+            current_bytes: Cell::new(0),
+        }
+    }
+
+    /// Adds the bytes of the transfer that just finished to the running total, and returns them.
+    pub fn transfer_completed(&self) -> u64 {
+        let transferred = self.current_bytes.replace(0);
+        self.completed_bytes
+            .set(self.completed_bytes.get() + transferred);
+        transferred
+    }
+}
+
+impl<'a, P: ProgressCallback> ProgressCallback for OverallProgress<'a, P> {
+    fn rsync_progress(
+        &self,
+        transferred_bytes: usize,
+        progress: u8,
+        speed: &str,
+        elapsed_time: &str,
+        transferred: Option<u32>,
+        to_check: Option<ToCheck>,
+    ) {
+        self.inner.rsync_progress(
+            transferred_bytes,
+            progress,
+            speed,
+            elapsed_time,
+            transferred,
+            to_check,
+        );
+        self.current_bytes.set(transferred_bytes as u64);
+        self.inner
+            .overall_progress(self.completed_bytes.get() + transferred_bytes as u64);
+    }
+
+    fn current_file(&self, path: &Path) {
+        self.inner.current_file(path);
+    }
+}
+
 #[derive(Debug)]
 /// The amount of files left to check.
 pub struct ToCheck {
@@ -38,43 +107,123 @@ pub struct ToCheck {
     pub total: u32,
 }
 
+/// Parses rsync's `--info=progress2` output, passing each progress update to `progress`.
+/// File names printed by `--info=name` are passed to `ProgressCallback::current_file` as they are,
+/// even if they aren't valid UTF-8.
+///
+/// Other lines are skipped, so that differences between rsync versions
+/// (or unexpected messages) don't interrupt the transfer.
 pub fn parse_from_stdout<B: BufRead, P: ProgressCallback>(
     reader: B,
     progress: &P,
+) -> io::Result<()> {
+    parse_transfer_output(reader, progress, Path::new(""))
+}
+
+/// Like `parse_from_stdout`, but file names are joined onto `source_directory`.
+pub(crate) fn parse_transfer_output<B: BufRead, P: ProgressCallback>(
+    reader: B,
+    progress: &P,
+    source_directory: &Path,
 ) -> io::Result<()> {
     // blocks until subrocess finishes
     for text in reader.split(b'\r') {
         let text = text?;
-        let text = String::from_utf8(text).unwrap();
 
-        if text == "" {
-            continue;
+        for bytes in text.split(|&byte| byte == b'\n') {
+            // file names needn't be valid UTF-8, so they are taken from the raw bytes
+            let line = String::from_utf8_lossy(bytes);
+            if line.trim().is_empty() {
+                continue;
+            }
+            trace!("rsync: {}", line);
+            match parse_line(&line) {
+                Some(update) => progress.rsync_progress(
+                    update.bytes,
+                    update.percent,
+                    update.speed,
+                    update.elapsed_time,
+                    update.transferred,
+                    update.to_check,
+                ),
+                // progress lines are indented, whereas file names start at the beginning of the line.
+                // directories (including `./`) end with a slash, and aren't reported
+                None if !line.starts_with(char::is_whitespace) && !line.ends_with('/') => {
+                    progress.current_file(&source_directory.join(path_from_bytes(bytes)))
+                }
+                None => debug!("Couldn't parse rsync output {:?}", line),
+            }
         }
+    }
 
-        let mut iter = text.split_whitespace();
-
-        let bytes: usize = iter.next().unwrap().replace(",", "").parse().unwrap();
-        let percent = iter.next().unwrap();
-        let percent: u8 = (&percent[0..percent.len() - 1]).parse().unwrap();
-        let speed = iter.next().unwrap();
-        let elapsed_time = iter.next().unwrap();
-
-        let transferred: Option<u32> = iter
-            .next()
-            .map(|string| string[5..string.len() - 1].parse().unwrap());
-        let to_check = iter.next().map(|string| {
-            let slice = &string[7..string.len() - 1];
-            let mut split = slice.split('/');
-            ToCheck {
-                remaining: split.next().unwrap().parse().unwrap(),
-                total: split.next().unwrap().parse().unwrap(),
-            }
-        });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
 
-        println!("rsync: {}", text);
+/// Elsewhere paths aren't arbitrary bytes, so the names are decoded lossily.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
 
-        progress.rsync_progress(bytes, percent, speed, elapsed_time, transferred, to_check);
+struct ProgressUpdate<'a> {
+    bytes: usize,
+    percent: u8,
+    speed: &'a str,
+    elapsed_time: &'a str,
+    transferred: Option<u32>,
+    to_check: Option<ToCheck>,
+}
+
+/// Parses a line such as `1,234,567  45%  1.23MB/s  0:00:01 (xfr#1, to-chk=0/1)`.
+///
+/// rsync 3.0 writes `xfer#` and `to-check=` instead, and rsync 3.1+ writes `ir-chk=`
+/// while it is still building the file list.
+fn parse_line(line: &str) -> Option<ProgressUpdate<'_>> {
+    let mut iter = line.split_whitespace();
+
+    // the thousands separator depends on the locale
+    let bytes: String = iter
+        .next()?
+        .chars()
+        .filter(|c| *c != ',' && *c != '.')
+        .collect();
+    let bytes = bytes.parse().ok()?;
+    let percent = iter.next()?.strip_suffix('%')?.parse().ok()?;
+    let speed = iter.next()?;
+    let elapsed_time = iter.next()?;
+
+    let mut transferred = None;
+    let mut to_check = None;
+    for field in iter {
+        let field = field.trim_matches(|c| c == '(' || c == ')' || c == ',');
+        if let Some(count) = field
+            .strip_prefix("xfr#")
+            .or_else(|| field.strip_prefix("xfer#"))
+        {
+            transferred = count.parse().ok();
+        } else if let Some((_, counts)) = field.split_once('=') {
+            let (remaining, total) = counts.split_once('/')?;
+            to_check = Some(ToCheck {
+                remaining: remaining.parse().ok()?,
+                total: total.parse().ok()?,
+            });
+        }
     }
 
-    Ok(())
+    Some(ProgressUpdate {
+        bytes,
+        percent,
+        speed,
+        elapsed_time,
+        transferred,
+        to_check,
+    })
 }
@@ -0,0 +1,103 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{DescribeIoError, SyncError};
+use crate::propagate::{
+    copy, DefaultPropagationOptions, EmptyProgressCallback, PropagationOptions,
+};
+
+/// An implementation of `PropagationOptions` which moves deleted files and directories
+/// into a trash directory instead of permanently removing them.
+///
+/// Trashed items keep their original path (relative to the filesystem root or the current directory) inside
+/// the trash directory, and have a timestamp appended to their name so that repeated deletions don't collide.
+/// For example, deleting `/home/bob/a/notes.txt` might move it to `<trash>/home/bob/a/notes.txt.1586044800123`.
+#[derive(Debug, Clone)]
+pub struct TrashPropagationOptions {
+    pub directory: PathBuf,
+}
+
+impl TrashPropagationOptions {
+    pub fn new(directory: PathBuf) -> Self {
+        TrashPropagationOptions { directory }
+    }
+
+    /// Moves `path` into the trash, returning where it ended up.
+    pub fn move_to_trash(&self, path: &Path) -> Result<PathBuf, SyncError> {
+        let dest = self.trash_path_for(path)?;
+        info!("Moving {:?} to the trash at {:?}", path, dest);
+
+        if let Err(e) = fs::rename(path, &dest) {
+            // most likely the trash is on another filesystem, so move it the slow way
+            debug!("Couldn't rename {:?} ({}), copying instead", path, e);
+            if path.symlink_metadata()?.is_dir() {
+                fs::create_dir(&dest).describe(|| format!("while creating {:?}", dest))?;
+                copy::copy_directory(
+                    path,
+                    &dest,
+                    &DefaultPropagationOptions,
+                    &EmptyProgressCallback,
+                )?;
+                fs::remove_dir_all(path).describe(|| format!("when removing {:?}", path))?;
+            } else {
+                copy::copy_file(
+                    path,
+                    &dest,
+                    &DefaultPropagationOptions,
+                    &EmptyProgressCallback,
+                )?;
+                fs::remove_file(path).describe(|| format!("when removing {:?}", path))?;
+            }
+        }
+
+        Ok(dest)
+    }
+
+    /// Builds a unique, timestamped path inside the trash directory, creating its parent directory.
+    fn trash_path_for(&self, path: &Path) -> Result<PathBuf, SyncError> {
+        let relative: PathBuf = path
+            .components()
+            .filter(|c| matches!(*c, Component::Normal(_)))
+            .collect();
+        let file_name = relative
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_else(|| OsString::from("root"));
+        let parent = self
+            .directory
+            .join(relative.parent().unwrap_or_else(|| Path::new("")));
+        fs::create_dir_all(&parent).describe(|| format!("while creating {:?}", parent))?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let mut counter = 0;
+        loop {
+            let mut name = file_name.clone();
+            name.push(format!(".{}", timestamp));
+            if counter > 0 {
+                name.push(format!("-{}", counter));
+            }
+            let candidate = parent.join(name);
+            if candidate.symlink_metadata().is_err() {
+                return Ok(candidate);
+            }
+            counter += 1;
+        }
+    }
+}
+
+impl PropagationOptions for TrashPropagationOptions {
+    fn should_remove(&self, _: &Path) -> bool {
+        true
+    }
+    fn remove_file(&self, path: &Path) -> Result<(), SyncError> {
+        self.move_to_trash(path).map(|_| ())
+    }
+    fn remove_dir_all(&self, path: &Path) -> Result<(), SyncError> {
+        self.move_to_trash(path).map(|_| ())
+    }
+}
@@ -1,21 +1,133 @@
-use crate::detect::Difference;
+use crate::detect::{DetectionResult, Difference};
+use crate::error::SyncError;
+use crate::state::ArchiveEntryPerReplica;
 use crate::NumRoots;
 use crate::ReplicaIndex;
+use std::path::PathBuf;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 /// The result of `reconciliation`.
 pub enum Operation {
     /// the provided replica was correct
     PropagateFromMaster(ReplicaIndex),
+    /// the item was renamed on the `master` replica, which `propagate` will repeat on the other replicas
+    Rename {
+        master: ReplicaIndex,
+        from: PathBuf,
+        to: PathBuf,
+    },
     /// the item was changed on multiple replicas and we don't know which
     ItemChangedOnMultipleReplicas,
     /// the item differs, but there was no previous state in the archives so we don't know which replica is 'correct'
     ItemDiffersBetweenReplicasAndNoArchive,
 }
 
+impl Operation {
+    /// Returns true if the operation needs the user to decide which replica is correct.
+    pub fn is_conflict(&self) -> bool {
+        !matches!(
+            *self,
+            Operation::PropagateFromMaster(_) | Operation::Rename { .. }
+        )
+    }
+}
+
+/// Returns the master chosen by `operation`, or `SyncError::UnresolvedConflict` if it is a conflict,
+/// so that conflicts can be treated as hard errors with `?`.
+///
+/// The error lists the replicas the item changed on since the last sync,
+/// or every replica it exists on if there is no previous state.
+pub fn require_resolution<N: NumRoots>(
+    operation: Operation,
+    difference: &Difference<N>,
+) -> Result<ReplicaIndex, SyncError> {
+    match operation {
+        Operation::PropagateFromMaster(master) | Operation::Rename { master, .. } => Ok(master),
+        Operation::ItemChangedOnMultipleReplicas
+        | Operation::ItemDiffersBetweenReplicasAndNoArchive => {
+            let replicas = difference
+                .current_state
+                .iter()
+                .enumerate()
+                .filter(|&(i, current)| match difference.previous_state {
+                    Some(ref previous_state) => *current != previous_state[i],
+                    None => current.entry_exists(),
+                })
+                .map(|(i, _)| i)
+                .collect();
+            Err(SyncError::UnresolvedConflict {
+                path: difference.path.clone(),
+                replicas,
+            })
+        }
+    }
+}
+
+/// Runs `guess_operation` on every difference, pairing each operation with the index of its difference.
+pub fn reconcile_all<N: NumRoots>(result: &DetectionResult<N>) -> Vec<(usize, Operation)> {
+    result
+        .differences
+        .iter()
+        .map(guess_operation)
+        .enumerate()
+        .collect()
+}
+
+/// Returns the differences which `guess_operation` couldn't resolve automatically.
+pub fn unresolved<N: NumRoots>(result: &DetectionResult<N>) -> Vec<&Difference<N>> {
+    result
+        .differences
+        .iter()
+        .filter(|difference| guess_operation(*difference).is_conflict())
+        .collect()
+}
+
+/// Picks a master replica for every difference, ready to be passed to `propagate`.
+///
+/// Each difference is reconciled with `strategy` (eg: `guess_operation` or `newest_wins`).
+/// When that results in a conflict, `on_conflict` is asked to pick the master instead,
+/// and differences for which it returns `None` are left out.
+pub fn resolve_all_with<'a, N, S, C>(
+    result: &'a DetectionResult<N>,
+    strategy: S,
+    on_conflict: &mut C,
+) -> Vec<(&'a Difference<N>, ReplicaIndex)>
+where
+    N: NumRoots,
+    S: Fn(&Difference<N>) -> Operation,
+    C: FnMut(&Difference<N>) -> Option<ReplicaIndex>,
+{
+    result
+        .differences
+        .iter()
+        .filter_map(|difference| {
+            let master = match strategy(difference) {
+                Operation::PropagateFromMaster(master) | Operation::Rename { master, .. } => {
+                    Some(master)
+                }
+                Operation::ItemChangedOnMultipleReplicas
+                | Operation::ItemDiffersBetweenReplicasAndNoArchive => on_conflict(difference),
+            };
+            master.map(|master| (difference, master))
+        })
+        .collect()
+}
+
 /// Determines which replica (if any), has the most up-to-date copy of the item.
 pub fn guess_operation<N: NumRoots>(difference: &Difference<N>) -> Operation {
     info!("Reconciling difference at {:?}", difference.path);
+    let operation = guess_master(difference);
+    match (operation, &difference.renamed_from) {
+        (Operation::PropagateFromMaster(master), Some(from)) => Operation::Rename {
+            master,
+            from: from.clone(),
+            to: difference.path.clone(),
+        },
+        (operation, _) => operation,
+    }
+}
+
+fn guess_master<N: NumRoots>(difference: &Difference<N>) -> Operation {
     match difference.previous_state {
         Some(ref previous_state) => {
             debug!("Previous state: {:?}", previous_state);
@@ -30,9 +142,15 @@ pub fn guess_operation<N: NumRoots>(difference: &Difference<N>) -> Operation {
                         "Item was changed in replica {}: was {:?}, now {:?}",
                         i, previous_state[i], replica
                     );
-                    if let Operation::PropagateFromMaster(_) = result {
-                        // it has changed on multiple replicas so we don't know which one is correct
-                        return Operation::ItemChangedOnMultipleReplicas;
+                    if let Operation::PropagateFromMaster(master) = result {
+                        // if it was deleted from (or changed the same way on) every replica that changed, they all agree
+                        if (difference.current_state[master].entry_exists()
+                            || replica.entry_exists())
+                            && !difference.changed_replicas_agree
+                        {
+                            // it has changed on multiple replicas so we don't know which one is correct
+                            return Operation::ItemChangedOnMultipleReplicas;
+                        }
                     } else {
                         // this is (so far) the 'master' replica
                         result = Operation::PropagateFromMaster(i);
@@ -43,6 +161,16 @@ pub fn guess_operation<N: NumRoots>(difference: &Difference<N>) -> Operation {
         }
         None => {
             debug!("No previous state from the archive");
+            if difference.identical_where_present {
+                // it is only missing from some replicas, so any copy will do
+                if let Some(master) = difference
+                    .current_state
+                    .iter()
+                    .position(ArchiveEntryPerReplica::entry_exists)
+                {
+                    return Operation::PropagateFromMaster(master);
+                }
+            }
             let mut result = Operation::ItemDiffersBetweenReplicasAndNoArchive;
             for (i, replica) in difference.current_state.iter().enumerate() {
                 if replica.entry_exists() {
@@ -60,3 +188,48 @@ pub fn guess_operation<N: NumRoots>(difference: &Difference<N>) -> Operation {
         }
     }
 }
+
+/// Like `guess_operation`, but resolves conflicts by picking the replica with the most recently modified item.
+///
+/// Replicas where the item has been deleted never win a conflict. If several replicas share the newest
+/// modification time, the result of `guess_operation` is returned unchanged.
+pub fn newest_wins<N: NumRoots>(difference: &Difference<N>) -> Operation {
+    let operation = guess_operation(difference);
+    match operation {
+        Operation::PropagateFromMaster(_) | Operation::Rename { .. } => operation,
+        Operation::ItemChangedOnMultipleReplicas
+        | Operation::ItemDiffersBetweenReplicasAndNoArchive => {
+            let mut newest: Option<(ReplicaIndex, (i64, i64))> = None;
+            let mut tied = false;
+            for (i, replica) in difference.current_state.iter().enumerate() {
+                if let Some(mtime) = replica.modification_time() {
+                    match newest {
+                        Some((_, newest_mtime)) if mtime < newest_mtime => {}
+                        Some((_, newest_mtime)) if mtime == newest_mtime => tied = true,
+                        _ => {
+                            newest = Some((i, mtime));
+                            tied = false;
+                        }
+                    }
+                }
+            }
+            match newest {
+                Some((i, _)) if !tied => {
+                    debug!("Replica {} has the newest copy of {:?}", i, difference.path);
+                    Operation::PropagateFromMaster(i)
+                }
+                _ => operation,
+            }
+        }
+    }
+}
+
+/// Always propagates from `master`, for a one-way mirror (eg: a backup) of that replica.
+///
+/// Unlike `guess_operation`, the archive isn't consulted, so changes made on the other replicas are overwritten,
+/// and **files which don't exist on `master` are deleted from the other replicas**.
+/// `SyncInfo::detect_renames` should be disabled when mirroring, because a rename on the mirror would
+/// hide the deletion of the original path.
+pub fn mirror<N: NumRoots>(_difference: &Difference<N>, master: ReplicaIndex) -> Operation {
+    Operation::PropagateFromMaster(master)
+}
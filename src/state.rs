@@ -1,19 +1,24 @@
 use generic_array::GenericArray;
 use serde::{Deserialize, Serialize};
 use std::convert::From;
+use std::fs;
 use std::iter::FromIterator;
-use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::config::DetectionMode;
 use crate::NumRoots;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// Mirrors the state of a path on the filesystem.
 pub enum ArchiveEntryPerReplica {
     Empty,
     Directory(ArchiveEntryExists),
     File(ArchiveEntryExists),
-    Symlink(ArchiveEntryExists),
+    Symlink(ArchiveSymlinkEntry),
+    /// A named pipe, socket or device node, which can't be synced.
+    /// Detection skips items which are one of these on any replica, so they are never stored in the archive.
+    Other(ArchiveEntryExists),
 }
 
 /// TODO: This is potentialy dodgy, and has just been implemented to satisfy generic bounds for
@@ -30,79 +35,299 @@ impl ArchiveEntryPerReplica {
     pub fn from_roots<N: NumRoots>(
         roots: &[PathBuf],
         path: &Path,
+        mode: DetectionMode,
+        follow_symlinks: bool,
     ) -> GenericArray<ArchiveEntryPerReplica, N> {
-        GenericArray::from_iter(
-            roots
-                .iter()
-                .map(|root: &PathBuf| ArchiveEntryPerReplica::from(root.join(path).as_ref())),
-        )
+        GenericArray::from_iter(roots.iter().map(|root: &PathBuf| {
+            ArchiveEntryPerReplica::from_path(&root.join(path), mode, follow_symlinks)
+        }))
+    }
+
+    /// Reads the current state of `path`, recording the metadata needed by `mode`.
+    ///
+    /// If `follow_symlinks` is true, a symlink is represented by whatever it points to
+    /// (so a broken symlink is `Empty`), otherwise it is represented as a `Symlink`.
+    pub fn from_path(
+        path: &Path,
+        mode: DetectionMode,
+        follow_symlinks: bool,
+    ) -> ArchiveEntryPerReplica {
+        // `symlink_metadata` doesn't traverse symlinks, so that they can be represented as such
+        let metadata = if follow_symlinks {
+            path.metadata()
+        } else {
+            path.symlink_metadata()
+        };
+        if let Ok(metadata) = metadata {
+            let (mtime, mtime_nsec) = modification_time(&metadata);
+            let mut entry = ArchiveEntryExists {
+                ino: 0,
+                ctime: 0,
+                mtime,
+                mtime_nsec,
+                size: metadata.len(),
+                hash: None,
+                mode: None,
+            };
+            // unused fields are left as zero so they don't take part in comparisons
+            if mode == DetectionMode::InoCtime {
+                let (ino, ctime) = file_identity(&metadata);
+                entry.ino = ino;
+                entry.ctime = ctime;
+            }
+            let ty = metadata.file_type();
+            // a symlink's own permissions are meaningless
+            if !ty.is_symlink() {
+                entry.mode = permissions_mode(&metadata);
+            }
+            if ty.is_file() {
+                ArchiveEntryPerReplica::File(entry)
+            } else if ty.is_dir() {
+                ArchiveEntryPerReplica::Directory(entry)
+            } else if ty.is_symlink() {
+                ArchiveEntryPerReplica::Symlink(ArchiveSymlinkEntry {
+                    entry,
+                    target: fs::read_link(path).unwrap_or_default(),
+                })
+            } else {
+                ArchiveEntryPerReplica::Other(entry)
+            }
+        } else {
+            ArchiveEntryPerReplica::Empty
+        }
     }
 
     /// Returns true if the entries are equal in type but not necessarily in content.
     pub fn equal_ty(a: &ArchiveEntryPerReplica, b: &ArchiveEntryPerReplica) -> bool {
-        match *a {
-            ArchiveEntryPerReplica::Empty => match *b {
-                ArchiveEntryPerReplica::Empty => true,
-                _ => false,
-            },
-            ArchiveEntryPerReplica::File(_) => match *b {
-                ArchiveEntryPerReplica::File(_) => true,
-                _ => false,
-            },
-            ArchiveEntryPerReplica::Directory(_) => match *b {
-                ArchiveEntryPerReplica::Directory(_) => true,
-                _ => false,
-            },
-            ArchiveEntryPerReplica::Symlink(_) => match *b {
-                ArchiveEntryPerReplica::Symlink(_) => true,
-                _ => false,
-            },
-        }
+        matches!(
+            (a, b),
+            (ArchiveEntryPerReplica::Empty, ArchiveEntryPerReplica::Empty)
+                | (
+                    ArchiveEntryPerReplica::File(_),
+                    ArchiveEntryPerReplica::File(_)
+                )
+                | (
+                    ArchiveEntryPerReplica::Directory(_),
+                    ArchiveEntryPerReplica::Directory(_)
+                )
+                | (
+                    ArchiveEntryPerReplica::Symlink(_),
+                    ArchiveEntryPerReplica::Symlink(_)
+                )
+        )
     }
 
     /// Returns true if the entry is a file or a symlink
     pub fn is_file_or_symlink(&self) -> bool {
-        match *self {
-            ArchiveEntryPerReplica::File(_) | ArchiveEntryPerReplica::Symlink(_) => true,
-            _ => false,
-        }
+        matches!(
+            *self,
+            ArchiveEntryPerReplica::File(_) | ArchiveEntryPerReplica::Symlink(_)
+        )
     }
 
     /// Returns true if the entry is present (ie: it is not empty)
     pub fn entry_exists(&self) -> bool {
+        !matches!(*self, ArchiveEntryPerReplica::Empty)
+    }
+
+    /// The modification time as `(seconds, nanoseconds)` since the unix epoch, or `None` if the entry is empty.
+    pub fn modification_time(&self) -> Option<(i64, i64)> {
+        match *self {
+            ArchiveEntryPerReplica::Empty => None,
+            ArchiveEntryPerReplica::Directory(ref entry)
+            | ArchiveEntryPerReplica::File(ref entry)
+            | ArchiveEntryPerReplica::Other(ref entry) => Some((entry.mtime, entry.mtime_nsec)),
+            ArchiveEntryPerReplica::Symlink(ref symlink) => {
+                Some((symlink.entry.mtime, symlink.entry.mtime_nsec))
+            }
+        }
+    }
+
+    /// Like `modification_time`, but as a `SystemTime`.
+    pub fn modified(&self) -> Option<SystemTime> {
+        let (secs, nsecs) = self.modification_time()?;
+        // times before the epoch are stored with both parts negative
+        if secs < 0 || nsecs < 0 {
+            UNIX_EPOCH.checked_sub(Duration::new(
+                secs.unsigned_abs(),
+                nsecs.unsigned_abs() as u32,
+            ))
+        } else {
+            UNIX_EPOCH.checked_add(Duration::new(secs as u64, nsecs as u32))
+        }
+    }
+
+    /// The unix permission bits of a file or directory, or `None` if they weren't recorded
+    /// (eg: for symlinks, on Windows, or in entries migrated from older archives).
+    pub fn permissions_mode(&self) -> Option<u32> {
+        match *self {
+            ArchiveEntryPerReplica::Directory(ref entry)
+            | ArchiveEntryPerReplica::File(ref entry) => entry.mode,
+            _ => None,
+        }
+    }
+
+    /// The size of the file in bytes, or `None` if the entry isn't a file.
+    pub fn file_size(&self) -> Option<u64> {
         match *self {
-            ArchiveEntryPerReplica::Empty => false,
-            _ => true,
+            ArchiveEntryPerReplica::File(ref entry) => Some(entry.size),
+            _ => None,
+        }
+    }
+
+    /// The cached hash of the file's contents, if it has been computed.
+    pub fn content_hash(&self) -> Option<u64> {
+        match *self {
+            ArchiveEntryPerReplica::File(ref entry) => entry.hash,
+            _ => None,
+        }
+    }
+
+    /// Caches the hash of the file's contents. This has no effect if the entry isn't a file.
+    pub fn set_content_hash(&mut self, hash: u64) {
+        if let ArchiveEntryPerReplica::File(ref mut entry) = *self {
+            entry.hash = Some(hash);
+        }
+    }
+}
+
+/// The inode number and change time of a file.
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> (u64, i64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.ino(), metadata.ctime())
+}
+
+/// Windows doesn't expose file indices or change times on stable Rust,
+/// so the creation time and last write time stand in for the inode number and change time.
+#[cfg(windows)]
+fn file_identity(metadata: &fs::Metadata) -> (u64, i64) {
+    use std::os::windows::fs::MetadataExt;
+    (metadata.creation_time(), metadata.last_write_time() as i64)
+}
+
+/// The permission bits (including setuid, setgid and sticky) of a file or directory.
+#[cfg(unix)]
+fn permissions_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Windows only has a read-only flag, which isn't compared.
+#[cfg(windows)]
+fn permissions_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// The modification time as seconds and nanoseconds relative to the unix epoch.
+fn modification_time(metadata: &fs::Metadata) -> (i64, i64) {
+    match metadata
+        .modified()
+        .map(|time| time.duration_since(UNIX_EPOCH))
+    {
+        Ok(Ok(after)) => (after.as_secs() as i64, i64::from(after.subsec_nanos())),
+        Ok(Err(before)) => {
+            let before = before.duration();
+            (
+                -(before.as_secs() as i64),
+                -i64::from(before.subsec_nanos()),
+            )
         }
+        Err(_) => (0, 0),
     }
 }
 
 impl<'a> From<&'a Path> for ArchiveEntryPerReplica {
     fn from(path: &'a Path) -> ArchiveEntryPerReplica {
-        if !path.exists() {
-            ArchiveEntryPerReplica::Empty
-        } else {
-            let metadata = path.metadata().unwrap();
-            let entry = ArchiveEntryExists {
-                ino: metadata.ino(),
-                ctime: metadata.ctime(),
-            };
-            let ty = metadata.file_type();
-            if ty.is_file() {
-                ArchiveEntryPerReplica::File(entry)
-            } else if ty.is_dir() {
-                ArchiveEntryPerReplica::Directory(entry)
-            } else if ty.is_symlink() {
-                ArchiveEntryPerReplica::Symlink(entry)
-            } else {
-                unreachable!()
-            }
-        }
+        ArchiveEntryPerReplica::from_path(path, DetectionMode::InoCtime, false)
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct ArchiveEntryExists {
     ino: u64,
     ctime: i64,
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    /// A cache of the file's content hash, only used when `SyncInfo::compare_by_hash` is set
+    hash: Option<u64>,
+    /// The unix permission bits, see `ArchiveEntryPerReplica::permissions_mode`
+    mode: Option<u32>,
+}
+
+/// Marks entries migrated from archives which didn't record modification times or sizes.
+const UNKNOWN_MTIME: i64 = i64::MIN;
+
+impl ArchiveEntryExists {
+    /// An entry migrated from an older archive, which only recorded the inode number and change time.
+    pub(crate) fn from_ino_ctime(ino: u64, ctime: i64, hash: Option<u64>) -> Self {
+        ArchiveEntryExists {
+            ino,
+            ctime,
+            mtime: UNKNOWN_MTIME,
+            mtime_nsec: 0,
+            size: 0,
+            hash,
+            mode: None,
+        }
+    }
+
+    /// An entry migrated from a version 7 archive, which didn't record permissions.
+    pub(crate) fn without_mode(
+        ino: u64,
+        ctime: i64,
+        mtime: i64,
+        mtime_nsec: i64,
+        size: u64,
+        hash: Option<u64>,
+    ) -> Self {
+        ArchiveEntryExists {
+            ino,
+            ctime,
+            mtime,
+            mtime_nsec,
+            size,
+            hash,
+            mode: None,
+        }
+    }
+}
+
+/// The content hash is only a cache, so it is ignored when comparing entries:
+/// a freshly read entry (without a hash) is equal to an archived one (with a hash)
+/// as long as the file hasn't changed.
+/// Likewise, the modification time and size (or permissions) are ignored if either entry was migrated without them.
+///
+/// These wildcards make the comparison intransitive (a migrated entry equals two different current ones),
+/// so `Eq` isn't implemented, here or for the entries which contain one.
+impl PartialEq for ArchiveEntryExists {
+    fn eq(&self, other: &Self) -> bool {
+        self.ino == other.ino
+            && self.ctime == other.ctime
+            && (self.mode.is_none() || other.mode.is_none() || self.mode == other.mode)
+            && (self.mtime == UNKNOWN_MTIME
+                || other.mtime == UNKNOWN_MTIME
+                || (self.mtime == other.mtime
+                    && self.mtime_nsec == other.mtime_nsec
+                    && self.size == other.size))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// The state of a symlink, including the path it points to.
+pub struct ArchiveSymlinkEntry {
+    entry: ArchiveEntryExists,
+    target: PathBuf,
+}
+
+impl ArchiveSymlinkEntry {
+    pub(crate) fn new(entry: ArchiveEntryExists, target: PathBuf) -> Self {
+        ArchiveSymlinkEntry { entry, target }
+    }
+
+    /// The target of the symlink, exactly as it was read with `fs::read_link`.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
 }
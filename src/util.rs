@@ -1,12 +1,49 @@
 use fnv::FnvHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::hash::BuildHasherDefault;
-use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
-pub fn hash_value<T: Hash + ?Sized>(object: &T) -> u64 {
-    let mut hasher: FnvHasher = Default::default();
-    object.hash(&mut hasher);
-    hasher.finish()
-}
+use crate::error::SyncError;
 
 pub type FnvHashMap<K, T> = HashMap<K, T, BuildHasherDefault<FnvHasher>>;
+pub type FnvHashSet<K> = HashSet<K, BuildHasherDefault<FnvHasher>>;
+
+/// Checks that no two roots are the same directory (eg: one is a symlink to the other),
+/// since syncing a directory with itself would delete files while "copying" them.
+/// Roots which don't exist yet can't be the same as any other, so they are skipped.
+pub fn check_roots_distinct(roots: &[PathBuf]) -> Result<(), SyncError> {
+    let canonical: Vec<Option<PathBuf>> = roots
+        .iter()
+        .map(|root| fs::canonicalize(root).ok())
+        .collect();
+    for (i, a) in canonical.iter().enumerate() {
+        for (j, b) in canonical.iter().enumerate().skip(i + 1) {
+            if a.is_some() && a == b {
+                return Err(SyncError::DuplicateRoot {
+                    a: roots[i].clone(),
+                    b: roots[j].clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The device and inode of the file at `path`, if it is a regular file with more than one hard link.
+#[cfg(unix)]
+pub fn hard_link_identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if metadata.is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+/// Hard links aren't detected on this platform.
+#[cfg(not(unix))]
+pub fn hard_link_identity(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
@@ -1,4 +1,5 @@
 extern crate env_logger;
+extern crate glob;
 extern crate regex;
 extern crate ubiquity;
 #[macro_use]
@@ -7,7 +8,7 @@ extern crate log;
 extern crate generic_array;
 extern crate typenum;
 
-use typenum::U2;
+use typenum::{U2, U3};
 
 use regex::Regex;
 
@@ -16,11 +17,18 @@ use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use ubiquity::archive::Archive;
+use ubiquity::archive::{Archive, ReadError};
+use ubiquity::compare_files::{
+    file_contents_equal, file_contents_equal_cmd, file_contents_equal_in_blocks,
+    file_contents_equal_with,
+};
 use ubiquity::config::*;
 use ubiquity::detect;
+use ubiquity::error::SyncError;
 use ubiquity::propagate;
+use ubiquity::propagate::{CopyBackend, PropagationAction, PropagationOptions};
 use ubiquity::reconcile;
+use ubiquity::NumRoots;
 
 fn set_up(name: &'static str) -> (Archive, SyncInfo) {
     let _ = env_logger::init();
@@ -37,7 +45,19 @@ fn set_up(name: &'static str) -> (Archive, SyncInfo) {
 
     let archive = Archive::new(archive_path).unwrap();
 
-    return (archive, config);
+    (archive, config)
+}
+
+fn set_up_three(name: &'static str) -> (Archive, SyncInfo<U3>) {
+    let (archive, config) = set_up(name);
+
+    let c_path = PathBuf::from(format!("tests/replicas/{}/c", name));
+    clean_directory(&c_path).unwrap();
+
+    let [a_path, b_path]: [PathBuf; 2] = config.roots.into();
+    let config = SyncInfo::new(arr![PathBuf; a_path, b_path, c_path]);
+
+    (archive, config)
 }
 
 #[test]
@@ -114,7 +134,7 @@ fn test_nested_differences_are_removed() {
 #[test]
 fn test_differences_are_resolved() {
     let (archive, config) = set_up("differences_are_resolved");
-    let ref sd = detect::SearchDirectories::from_root();
+    let sd = &detect::SearchDirectories::from_root();
 
     detect_and_resolve(&archive, &config, sd);
 
@@ -155,40 +175,4489 @@ fn test_differences_are_resolved() {
 }
 
 #[test]
-fn test_regex_forward_slash() {
-    let r = regex::Regex::new(r"/target/").unwrap();
-    assert!(r.is_match("/Users/bob/awesome/target/foo"));
-    assert!(!r.is_match("/Users/bob/awesome/target"));
+#[cfg(unix)]
+fn test_symlinks_are_propagated() {
+    let (archive, config) = set_up("symlinks_are_propagated");
+    let sd = &detect::SearchDirectories::from_root();
+
+    let mut target = fs::File::create(config.roots[0].join("target")).unwrap();
+    write!(target, "Hello World").unwrap();
+    std::os::unix::fs::symlink("target", config.roots[0].join("link")).unwrap();
+
+    detect_and_resolve(&archive, &config, sd);
+
+    let link = config.roots[1].join("link");
+    assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_link(link).unwrap(), Path::new("target"));
+
+    let result = detect::find_updates(
+        &archive,
+        &mut sd.clone(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
 }
 
-fn detect_and_resolve(
-    archive: &Archive,
-    config: &SyncInfo<U2>,
-    search_directories: &detect::SearchDirectories,
-) {
+#[test]
+#[cfg(unix)]
+fn test_symlink_targets_are_compared() {
+    let (archive, config) = set_up("symlink_targets_are_compared");
+
+    std::os::unix::fs::symlink("foo", config.roots[0].join("link")).unwrap();
+    std::os::unix::fs::symlink("bar", config.roots[1].join("link")).unwrap();
+
     let result = detect::find_updates(
-        archive,
-        &mut search_directories.clone(),
-        config,
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
         &detect::EmptyProgressCallback,
     )
     .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(&result.differences[0].path, Path::new("link"));
+}
 
-    info!("{} differences", result.differences.len());
-    for difference in result.differences {
-        let operation = reconcile::guess_operation(&difference);
-        info!("difference {:?}: {:?}", difference.path, operation);
-        if let reconcile::Operation::PropagateFromMaster(master) = operation {
-            propagate::propagate(
-                &difference,
-                master,
-                &archive,
-                &propagate::DefaultPropagationOptions,
-                &propagate::EmptyProgressCallback,
-            )
-            .unwrap();
+/// Propagation options whose behaviour can be tweaked per-test.
+struct TestOptions {
+    rsync: &'static str,
+    backend: CopyBackend,
+    dry_run: bool,
+    cancellation: CancellationToken,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            rsync: "rsync",
+            backend: CopyBackend::Rsync,
+            dry_run: false,
+            cancellation: CancellationToken::new(),
+        }
+    }
+}
+
+impl PropagationOptions for TestOptions {
+    fn should_remove(&self, _: &Path) -> bool {
+        true
+    }
+    fn remove_file(&self, path: &Path) -> Result<(), SyncError> {
+        propagate::DefaultPropagationOptions.remove_file(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> Result<(), SyncError> {
+        propagate::DefaultPropagationOptions.remove_dir_all(path)
+    }
+    fn rsync_path(&self) -> &Path {
+        Path::new(self.rsync)
+    }
+    fn copy_backend(&self) -> CopyBackend {
+        self.backend
+    }
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+}
+
+#[test]
+fn test_custom_rsync_path() {
+    let (archive, config) = set_up("custom_rsync_path");
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+
+    match propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &TestOptions {
+            rsync: "/nonexistent/rsync",
+            ..Default::default()
+        },
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::RsyncNotFound(path)) => assert_eq!(path, "/nonexistent/rsync"),
+        other => panic!("expected RsyncNotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rsync_failure_is_an_error() {
+    let (archive, config) = set_up("rsync_failure_is_an_error");
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+
+    match propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &TestOptions {
+            rsync: "false",
+            ..Default::default()
+        },
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::RsyncFailed { status, dest, .. }) => {
+            assert!(!status.success());
+            assert_eq!(dest, config.roots[1].join("foo"));
         }
+        other => panic!("expected RsyncFailed, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_native_copy_backend() {
+    let (archive, config) = set_up("native_copy_backend");
+    let sd = &detect::SearchDirectories::from_root();
+    let options = TestOptions {
+        rsync: "/nonexistent/rsync",
+        backend: CopyBackend::Native,
+        ..Default::default()
+    };
+
+    fs::create_dir_all(config.roots[0].join("baz/qux")).unwrap();
+    let mut file = fs::File::create(config.roots[0].join("baz/qux/cub")).unwrap();
+    write!(file, "Hello World").unwrap();
+    let mut file = fs::File::create(config.roots[0].join("foo")).unwrap();
+    write!(file, "Foo").unwrap();
+
+    detect_and_resolve_with(&archive, &config, sd, &options);
+
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("baz/qux/cub")).unwrap(),
+        "Hello World"
+    );
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Foo"
+    );
+
+    let result = detect::find_updates(
+        &archive,
+        &mut sd.clone(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_native_copy_preserves_metadata() {
+    use filetime::FileTime;
+    use std::os::unix::fs::PermissionsExt;
+
+    let (archive, config) = set_up("native_copy_preserves_metadata");
+    let options = TestOptions {
+        backend: CopyBackend::Native,
+        ..Default::default()
+    };
+
+    let source = config.roots[0].join("foo");
+    fs::File::create(&source).unwrap();
+    let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(&source, mtime).unwrap();
+    fs::set_permissions(&source, fs::Permissions::from_mode(0o640)).unwrap();
+
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+
+    let metadata = config.roots[1].join("foo").metadata().unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+}
+
+#[test]
+fn test_dry_run() {
+    let (archive, config) = set_up("dry_run");
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+    fs::create_dir(config.roots[1].join("foo")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    let difference = &result.differences[0];
+
+    let expected = vec![
+        PropagationAction::DeleteDirectory(config.roots[1].join("foo")),
+        PropagationAction::CopyFile {
+            source: config.roots[0].join("foo"),
+            dest: config.roots[1].join("foo"),
+        },
+    ];
+    assert_eq!(propagate::propagate_plan(difference, 0).unwrap(), expected);
+
+    let options = TestOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let actions = propagate::propagate(
+        difference,
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap()
+    .actions;
+    assert_eq!(actions, expected);
+
+    // nothing should have changed
+    assert!(config.roots[1].join("foo").is_dir());
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+}
+
+#[test]
+fn test_deletions_are_moved_to_trash() {
+    let (archive, config) = set_up("deletions_are_moved_to_trash");
+    let trash = PathBuf::from("tests/replicas/deletions_are_moved_to_trash/trash");
+    clean_directory(&trash).unwrap();
+    let sd = &detect::SearchDirectories::from_root();
+
+    let mut file = fs::File::create(config.roots[0].join("foo")).unwrap();
+    write!(file, "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, sd);
+
+    fs::remove_file(config.roots[1].join("foo")).unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        sd,
+        &propagate::TrashPropagationOptions::new(trash.clone()),
+    );
+    assert!(!config.roots[0].join("foo").exists());
+
+    let trashed_dir = trash.join(&config.roots[0]);
+    let trashed: Vec<_> = fs::read_dir(&trashed_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(trashed.len(), 1);
+    assert!(trashed[0]
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("foo."));
+    assert_eq!(fs::read_to_string(&trashed[0]).unwrap(), "Hello World");
+}
+
+#[test]
+fn test_file_contents_equal() {
+    let dir = PathBuf::from("tests/replicas/file_contents_equal");
+    clean_directory(&dir).unwrap();
+
+    // spans multiple blocks
+    let contents: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut last_byte_differs = contents.clone();
+    *last_byte_differs.last_mut().unwrap() ^= 1;
+
+    fs::write(dir.join("a"), &contents).unwrap();
+    fs::write(dir.join("b"), &contents).unwrap();
+    fs::write(dir.join("last_byte_differs"), &last_byte_differs).unwrap();
+    fs::write(dir.join("shorter"), &contents[..contents.len() - 1]).unwrap();
+
+    assert!(file_contents_equal(&dir.join("a"), &dir.join("b")).unwrap());
+    assert!(!file_contents_equal(&dir.join("a"), &dir.join("last_byte_differs")).unwrap());
+    assert!(!file_contents_equal(&dir.join("a"), &dir.join("shorter")).unwrap());
+    assert!(!file_contents_equal(&dir.join("shorter"), &dir.join("a")).unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_file_contents_equal_without_cmp() {
+    use std::ffi::OsStr;
+
+    let dir = PathBuf::from("tests/replicas/file_contents_equal_without_cmp");
+    clean_directory(&dir).unwrap();
+    fs::write(dir.join("a"), "Hello World").unwrap();
+    fs::write(dir.join("b"), "Hello World").unwrap();
+    fs::write(dir.join("c"), "Goodbye World").unwrap();
+
+    // a missing program falls back to comparing the files directly
+    let missing = OsStr::new("ubiquity-cmp-does-not-exist");
+    assert!(file_contents_equal_with(missing, &dir.join("a"), &dir.join("b")).unwrap());
+    assert!(!file_contents_equal_with(missing, &dir.join("a"), &dir.join("c")).unwrap());
+
+    // a program killed by a signal is an error rather than a panic
+    let killed = write_script(
+        "tests/replicas/file_contents_equal_without_cmp",
+        "cmp",
+        "#!/bin/sh\nkill -9 $$\n",
+    );
+    assert!(file_contents_equal_with(killed.as_os_str(), &dir.join("a"), &dir.join("b")).is_err());
+
+    // so is cmp's "trouble" exit code
+    assert!(file_contents_equal_cmd(&dir.join("a"), &dir.join("missing")).is_err());
+}
+
+#[test]
+fn test_compare_by_hash() {
+    let (archive, mut config) = set_up("compare_by_hash");
+    config.compare_by_hash = true;
+
+    fs::write(config.roots[0].join("same"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("same"), "Hello World").unwrap();
+    fs::write(config.roots[0].join("different"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("different"), "Hello Earth").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(&result.differences[0].path, Path::new("different"));
+
+    // the hashes of the identical files are cached in the archive
+    let entries = archive.for_directory(Path::new("")).read::<U2>().unwrap();
+    let same = entries.get(Path::new("same")).unwrap();
+    assert!(same[0].content_hash().is_some());
+    assert_eq!(same[0].content_hash(), same[1].content_hash());
+}
+
+#[test]
+fn test_mtime_size_detection_mode() {
+    use filetime::FileTime;
+
+    let (archive, mut config) = set_up("mtime_size_detection_mode");
+    config.detection_mode = DetectionMode::MtimeSize;
+    let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+
+    for root in config.roots.iter() {
+        fs::write(root.join("foo"), "Hello World").unwrap();
+        filetime::set_file_mtime(root.join("foo"), mtime).unwrap();
     }
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
+    assert_eq!(result.statistics.archive_additions, 1);
+
+    // replace the file with an identical copy, which gets a new inode
+    let foo = config.roots[1].join("foo");
+    let tmp = config.roots[1].join("tmp");
+    fs::rename(&foo, &tmp).unwrap();
+    fs::copy(&tmp, &foo).unwrap();
+    fs::remove_file(&tmp).unwrap();
+    filetime::set_file_mtime(&foo, mtime).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
+    assert_eq!(result.statistics.archive_hits, 1);
+    assert_eq!(result.statistics.archive_additions, 0);
+}
+
+#[test]
+fn test_sync_error_source() {
+    use std::error::Error;
+
+    let error = SyncError::from(io::Error::other("disk on fire"));
+    assert_eq!(error.source().unwrap().to_string(), "disk on fire");
+    assert!(SyncError::Cancelled.source().is_none());
+
+    // can be boxed into a `dyn Error` like any other error
+    let boxed: Box<dyn Error> = Box::new(SyncError::Cancelled);
+    assert_eq!(boxed.to_string(), "operation cancelled");
+}
+
+#[test]
+fn test_newest_wins() {
+    use filetime::FileTime;
+
+    let (archive, config) = set_up("newest_wins");
+
+    fs::write(config.roots[0].join("foo"), "older").unwrap();
+    fs::write(config.roots[1].join("foo"), "newer").unwrap();
+    filetime::set_file_mtime(
+        config.roots[0].join("foo"),
+        FileTime::from_unix_time(1_000_000_000, 0),
+    )
+    .unwrap();
+    filetime::set_file_mtime(
+        config.roots[1].join("foo"),
+        FileTime::from_unix_time(1_000_000_100, 0),
+    )
+    .unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    let difference = &result.differences[0];
+    assert!(matches!(
+        reconcile::guess_operation(difference),
+        reconcile::Operation::ItemDiffersBetweenReplicasAndNoArchive
+    ));
+    assert!(matches!(
+        reconcile::newest_wins(difference),
+        reconcile::Operation::PropagateFromMaster(1)
+    ));
+
+    // equal modification times fall back to `guess_operation`
+    filetime::set_file_mtime(
+        config.roots[0].join("foo"),
+        FileTime::from_unix_time(1_000_000_100, 0),
+    )
+    .unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(matches!(
+        reconcile::newest_wins(&result.differences[0]),
+        reconcile::Operation::ItemDiffersBetweenReplicasAndNoArchive
+    ));
+}
+
+#[test]
+fn test_reconcile_all() {
+    let (archive, config) = set_up("reconcile_all");
+
+    fs::write(config.roots[0].join("only_a"), "Hello World").unwrap();
+    fs::write(config.roots[0].join("both"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("both"), "Hello Earth").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+
+    let operations = reconcile::reconcile_all(&result);
+    assert_eq!(operations.len(), 2);
+    for (i, operation) in operations {
+        if result.differences[i].path == Path::new("only_a") {
+            assert!(matches!(
+                operation,
+                reconcile::Operation::PropagateFromMaster(0)
+            ));
+        } else {
+            assert!(operation.is_conflict());
+        }
+    }
+
+    let unresolved = reconcile::unresolved(&result);
+    assert_eq!(unresolved.len(), 1);
+    assert_eq!(unresolved[0].path, Path::new("both"));
+}
+
+#[test]
+fn test_resolve_all_with() {
+    let (archive, config) = set_up("resolve_all_with");
+
+    fs::write(config.roots[0].join("only_a"), "Hello World").unwrap();
+    fs::write(config.roots[0].join("both"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("both"), "Hello Earth").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    let mut conflicts = Vec::new();
+    let resolved =
+        reconcile::resolve_all_with(&result, reconcile::guess_operation, &mut |difference| {
+            conflicts.push(difference.path.clone());
+            Some(1)
+        });
+    assert_eq!(conflicts, vec![PathBuf::from("both")]);
+    assert_eq!(resolved.len(), 2);
+    for (difference, master) in resolved {
+        propagate::propagate(
+            difference,
+            master,
+            &archive,
+            &propagate::DefaultPropagationOptions,
+            &propagate::EmptyProgressCallback,
+        )
+        .unwrap();
+    }
+    assert_eq!(
+        fs::read_to_string(config.roots[0].join("both")).unwrap(),
+        "Hello Earth"
+    );
+    assert!(config.roots[1].join("only_a").exists());
+
+    // conflicts can also be skipped
+    fs::write(config.roots[0].join("both"), "Hello Mars").unwrap();
+    fs::write(config.roots[1].join("both"), "Hello Venus").unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert!(
+        reconcile::resolve_all_with(&result, reconcile::guess_operation, &mut |_| None).is_empty()
+    );
+}
+
+#[test]
+fn test_mirror() {
+    let (archive, config) = set_up("mirror");
+
+    fs::write(config.roots[0].join("both"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("both"), "Hello Earth").unwrap();
+    fs::write(config.roots[1].join("only_b"), "Hello World").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+    for difference in &result.differences {
+        match reconcile::mirror(difference, 0) {
+            reconcile::Operation::PropagateFromMaster(master) => {
+                propagate::propagate(
+                    difference,
+                    master,
+                    &archive,
+                    &propagate::DefaultPropagationOptions,
+                    &propagate::EmptyProgressCallback,
+                )
+                .unwrap();
+            }
+            other => panic!("expected PropagateFromMaster, got {:?}", other),
+        }
+    }
+
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("both")).unwrap(),
+        "Hello World"
+    );
+    assert!(!config.roots[1].join("only_b").exists());
+}
+
+#[test]
+fn test_difference_from_paths() {
+    let (archive, config) = set_up("difference_from_paths");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+
+    let difference =
+        detect::Difference::from_paths(PathBuf::from("foo"), config.roots.clone(), None);
+    assert!(difference.current_state[0].is_file_or_symlink());
+    assert!(!difference.current_state[1].entry_exists());
+    assert!(matches!(
+        reconcile::guess_operation(&difference),
+        reconcile::Operation::PropagateFromMaster(0)
+    ));
+
+    propagate::propagate(
+        &difference,
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Hello World"
+    );
+}
+
+#[test]
+fn test_search_directories_from_changed_paths() {
+    let (archive, config) = set_up("search_directories_from_changed_paths");
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+    }
+    fs::File::create(config.roots[0].join("dir/foo")).unwrap();
+    fs::File::create(config.roots[1].join("dir/bar")).unwrap();
+    fs::File::create(config.roots[0].join("top")).unwrap();
+
+    let mut search = detect::SearchDirectories::from_changed_paths(
+        vec![
+            config.roots[0].join("dir/foo"),
+            config.roots[1].join("dir/bar"),
+            config.roots[0].join("top"),
+            PathBuf::from("/somewhere/else"),
+        ],
+        &config.roots,
+    );
+    assert_eq!(
+        search.directories,
+        vec![PathBuf::from("dir"), PathBuf::from("")]
+    );
+    assert!(!search.recurse);
+
+    let result = detect::find_updates(
+        &archive,
+        &mut search,
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut paths: Vec<PathBuf> = result.differences.into_iter().map(|d| d.path).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("dir/bar"),
+            PathBuf::from("dir/foo"),
+            PathBuf::from("top")
+        ]
+    );
+}
+
+#[test]
+fn test_absolute_search_directories() {
+    let (archive, config) = set_up("absolute_search_directories");
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+    }
+    fs::File::create(config.roots[0].join("dir/foo")).unwrap();
+
+    let absolute = fs::canonicalize(&config.roots[0]).unwrap().join("dir");
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::new(vec![absolute], false),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("dir/foo"));
+
+    match detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::new(vec![PathBuf::from("/somewhere/else")], false),
+        &config,
+        &detect::EmptyProgressCallback,
+    ) {
+        Err(SyncError::AbsolutePathProvided(path)) => {
+            assert_eq!(path, Path::new("/somewhere/else"))
+        }
+        other => panic!(
+            "expected AbsolutePathProvided, got {:?}",
+            other.map(|r| r.differences.len())
+        ),
+    }
+}
+
+#[test]
+fn test_files_are_ignored_by_glob() {
+    let (archive, mut config) = set_up("files_are_ignored_by_glob");
+    config
+        .ignore
+        .globs
+        .push(glob::Pattern::new("*.log").unwrap());
+    config
+        .ignore
+        .globs
+        .push(glob::Pattern::new("**/node_modules").unwrap());
+
+    fs::File::create(config.roots[0].join("debug.log")).unwrap();
+    fs::create_dir_all(config.roots[0].join("app/node_modules")).unwrap();
+    fs::create_dir(config.roots[0].join("node_modules")).unwrap();
+    // only whole names are matched, so these shouldn't be ignored
+    fs::File::create(config.roots[0].join("catalog")).unwrap();
+    fs::File::create(config.roots[0].join("debug.logger")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut paths: Vec<&Path> = result
+        .differences
+        .iter()
+        .map(|d| d.path.as_path())
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            Path::new("app"),
+            Path::new("catalog"),
+            Path::new("debug.logger")
+        ]
+    );
+}
+
+#[test]
+fn test_ignore_file() {
+    let (archive, mut config) = set_up("ignore_file");
+    let ignore_file = Path::new("tests/replicas/ignore_file/.syncignore");
+    fs::write(
+        ignore_file,
+        "# logs\n*.log\n!important.log\n\n/build/\nsrc/*.tmp\n",
+    )
+    .unwrap();
+    config.ignore = Ignore::from_ignore_file(ignore_file).unwrap();
+    assert_eq!(config.ignore.rules.len(), 4);
+
+    for root in config.roots.iter() {
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+    }
+    fs::create_dir_all(config.roots[0].join("build")).unwrap();
+    for name in &[
+        "debug.log",
+        "src/nested/debug.log",
+        "important.log",
+        "src/a.tmp",
+        "src/nested/b.tmp",
+    ] {
+        fs::File::create(config.roots[0].join(name)).unwrap();
+    }
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut paths: Vec<&Path> = result
+        .differences
+        .iter()
+        .map(|d| d.path.as_path())
+        .collect();
+    paths.sort();
+    // `src/*.tmp` is anchored and `*` doesn't match a slash, so only `src/a.tmp` is ignored
+    assert_eq!(
+        paths,
+        vec![Path::new("important.log"), Path::new("src/nested/b.tmp")]
+    );
+}
+
+#[test]
+fn test_case_insensitive_ignore() {
+    let (archive, mut config) = set_up("case_insensitive_ignore");
+    config.ignore.paths.push("Thumbs.db".to_owned());
+    config
+        .ignore
+        .regexes
+        .push(Regex::new(r"\.DS_Store$").unwrap());
+    config
+        .ignore
+        .globs
+        .push(glob::Pattern::new("*.TMP").unwrap());
+
+    for name in &["thumbs.db", ".ds_store", "notes.tmp", "Other"] {
+        fs::File::create(config.roots[0].join(name)).unwrap();
+    }
+
+    let find_paths = |config: &SyncInfo| {
+        let result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        let mut paths: Vec<PathBuf> = result.differences.into_iter().map(|d| d.path).collect();
+        paths.sort();
+        paths
+    };
+
+    // exact-case matching is the default
+    assert_eq!(find_paths(&config).len(), 4);
+
+    config.ignore.case_insensitive = true;
+    assert_eq!(find_paths(&config), vec![PathBuf::from("Other")]);
+}
+
+#[test]
+fn test_renames_are_detected() {
+    let (archive, mut config) = set_up("renames_are_detected");
+    config.detect_renames = true;
+
+    for root in config.roots.iter() {
+        fs::write(root.join("video"), "lots of frames").unwrap();
+        fs::write(root.join("other"), "Hello World").unwrap();
+    }
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    fs::rename(
+        config.roots[0].join("video"),
+        config.roots[0].join("renamed video"),
+    )
+    .unwrap();
+    // a same-sized file with different contents shouldn't be mistaken for a rename
+    fs::rename(config.roots[0].join("other"), config.roots[0].join("new")).unwrap();
+    fs::write(config.roots[0].join("new"), "Hello Earth").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 3);
+
+    let renames: Vec<_> = result
+        .differences
+        .iter()
+        .filter_map(|difference| match reconcile::guess_operation(difference) {
+            reconcile::Operation::Rename { master, from, to } => {
+                Some((difference, master, from, to))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(renames.len(), 1);
+    let (difference, master, ref from, ref to) = renames[0];
+    assert_eq!(master, 0);
+    assert_eq!(from, Path::new("video"));
+    assert_eq!(to, Path::new("renamed video"));
+
+    let actions = propagate::propagate(
+        difference,
+        master,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap()
+    .actions;
+    assert_eq!(
+        actions,
+        vec![PropagationAction::Rename {
+            from: config.roots[1].join("video"),
+            to: config.roots[1].join("renamed video"),
+        }]
+    );
+    assert!(!config.roots[1].join("video").exists());
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("renamed video")).unwrap(),
+        "lots of frames"
+    );
+
+    // the rename is recorded in the archive, so only the unrelated change is left
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+}
+
+#[test]
+fn test_cancellation() {
+    let (archive, config) = set_up("cancellation");
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+
+    let options = TestOptions::default();
+    options.cancellation.cancel();
+    match propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::Cancelled) => {}
+        other => panic!("expected the propagation to be cancelled, got {:?}", other),
+    }
+    assert!(!config.roots[1].join("foo").exists());
+
+    // clones share the same flag
+    let token = config.cancellation.clone();
+    token.cancel();
+    assert!(matches!(
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        ),
+        Err(SyncError::Cancelled)
+    ));
+}
+
+#[test]
+fn test_detection_statistics() {
+    let (archive, config) = set_up("detection_statistics");
+
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::create_dir(config.roots[1].join("dir")).unwrap();
+    fs::write(config.roots[0].join("dir/foo"), "Hello World").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.statistics.differences, 1);
+    assert_eq!(result.statistics.directories_scanned, 2);
+    assert_eq!(
+        result.statistics.bytes_to_transfer,
+        fs::metadata(config.roots[0].join("dir/foo")).unwrap().len()
+    );
+}
+
+#[test]
+fn test_find_updates_streaming() {
+    let (archive, config) = set_up("find_updates_streaming");
+
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::create_dir(config.roots[1].join("dir")).unwrap();
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("dir/bar"), "Hello Earth").unwrap();
+
+    let mut paths = Vec::new();
+    let statistics = detect::find_updates_streaming(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+        |difference| {
+            paths.push(difference.path.clone());
+            // differences can be resolved as soon as they are found
+            if let reconcile::Operation::PropagateFromMaster(master) =
+                reconcile::guess_operation(&difference)
+            {
+                propagate::propagate(
+                    &difference,
+                    master,
+                    &archive,
+                    &propagate::DefaultPropagationOptions,
+                    &propagate::EmptyProgressCallback,
+                )?;
+            }
+            Ok(())
+        },
+    )
+    .unwrap();
+    paths.sort();
+    assert_eq!(paths, vec![PathBuf::from("dir/bar"), PathBuf::from("foo")]);
+    assert_eq!(statistics.differences, 2);
+    assert_eq!(statistics.directories_scanned, 2);
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(result.differences.is_empty());
+}
+
+#[test]
+fn test_strict_archive_version() {
+    let (mut archive, config) = set_up("strict_archive_version");
+
+    // an archive file for the root directory, written by some other version
+    let archive_file = archive
+        .directory
+        .join(archive.hash(Path::new("")).to_string());
+    fs::write(&archive_file, [99, 0, 0, 0]).unwrap();
+
+    let find = |archive: &Archive| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+    };
+
+    // by default the contents are discarded
+    assert!(find(&archive).is_ok());
+
+    fs::write(&archive_file, [99, 0, 0, 0]).unwrap();
+    archive.strict_version = true;
+    match find(&archive) {
+        Err(SyncError::ArchiveReadError(ReadError::InvalidArchiveVersion(99))) => {}
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected the invalid archive version to be an error"),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_archive_migration() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (archive, config) = set_up("archive_migration");
+    for root in config.roots.iter() {
+        fs::write(root.join("foo"), "Hello World").unwrap();
+    }
+
+    // a version 5 archive of the root directory, which only records the inode number and change time
+    let mut data = Vec::new();
+    data.extend_from_slice(&5u32.to_le_bytes());
+    data.extend_from_slice(&1u64.to_le_bytes());
+    data.extend_from_slice(&archive.hash(Path::new("foo")).to_le_bytes());
+    for root in config.roots.iter() {
+        let metadata = fs::metadata(root.join("foo")).unwrap();
+        data.extend_from_slice(&2u32.to_le_bytes()); // File
+        data.extend_from_slice(&metadata.ino().to_le_bytes());
+        data.extend_from_slice(&metadata.ctime().to_le_bytes());
+        data.push(0); // no hash
+    }
+    let archive_file = archive
+        .directory
+        .join(archive.hash(Path::new("")).to_string());
+    fs::write(&archive_file, &data).unwrap();
+
+    archive.migrate_all::<U2>().unwrap();
+    assert_eq!(&fs::read(&archive_file).unwrap()[..4], &8u32.to_le_bytes());
+
+    // the migrated entries are still up to date
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(result.differences.is_empty());
+    assert_eq!(result.statistics.archive_hits, 1);
+
+    // older versions didn't store paths, so they are filled in as entries are seen
+    let dump = archive.dump_directory::<U2>(Path::new("")).unwrap();
+    assert_eq!(dump[0].0, Path::new("foo"));
+}
+
+#[test]
+fn test_archive_wire_format() {
+    let (archive, config) = set_up("archive_wire_format");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // a little-endian version header, followed by bincode with fixed-size integers
+    let data = fs::read(
+        archive
+            .directory
+            .join(archive.hash(Path::new("")).to_string()),
+    )
+    .unwrap();
+    assert_eq!(&data[..4], &8u32.to_le_bytes());
+    assert_eq!(&data[4..12], &1u64.to_le_bytes()); // one entry
+    assert_eq!(&data[12..20], &archive.hash(Path::new("foo")).to_le_bytes());
+}
+
+#[test]
+fn test_dump_archive_directory() {
+    let (archive, config) = set_up("dump_archive_directory");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    let dump = archive.dump_directory::<U2>(Path::new("")).unwrap();
+    assert_eq!(dump.len(), 1);
+    let (ref path, ref replicas) = dump[0];
+    assert_eq!(path, Path::new("foo"));
+    assert_eq!(replicas.len(), 2);
+    assert!(replicas.iter().all(|replica| replica.is_file_or_symlink()));
+
+    assert!(archive
+        .dump_directory::<U2>(Path::new("nonexistent"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_interrupted_archive_write() {
+    let (archive, config) = set_up("interrupted_archive_write");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // simulate being killed part way through writing the archive file
+    let archive_file = archive
+        .directory
+        .join(archive.hash(Path::new("")).to_string());
+    let contents = fs::read(&archive_file).unwrap();
+    fs::write(
+        archive_file.with_extension("tmp"),
+        &contents[..contents.len() / 2],
+    )
+    .unwrap();
+
+    // the previous archive is untouched
+    let dump = archive.dump_directory::<U2>(Path::new("")).unwrap();
+    assert_eq!(dump.len(), 1);
+    assert_eq!(dump[0].0, Path::new("foo"));
+
+    fs::write(config.roots[0].join("bar"), "Hello Earth").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert_eq!(
+        archive.dump_directory::<U2>(Path::new("")).unwrap().len(),
+        2
+    );
+}
+
+#[test]
+fn test_archive_file_locking() {
+    use ubiquity::state::ArchiveEntryPerReplica;
+
+    let (archive, config) = set_up("archive_file_locking");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // the lock is held after reading, until it is released
+    let mut archive_file = archive.for_directory(Path::new(""));
+    let mut entries = archive_file.read::<U2>().unwrap();
+    archive_file.unlock();
+    // this would block forever if the lock were still held
+    assert_eq!(
+        archive
+            .for_directory(Path::new(""))
+            .read::<U2>()
+            .unwrap()
+            .iter()
+            .count(),
+        1
+    );
+
+    // dropping an archive file after its entries were removed doesn't panic
+    entries.insert(
+        Path::new("foo"),
+        arr![ArchiveEntryPerReplica; ArchiveEntryPerReplica::Empty, ArchiveEntryPerReplica::Empty],
+    );
+    archive_file.write(&mut entries).unwrap();
+    drop(archive_file);
+    assert!(archive
+        .dump_directory::<U2>(Path::new(""))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_archive_shared_locks() {
+    let (archive, config) = set_up("archive_shared_locks");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // several readers can hold the lock at once (this would block forever with exclusive locks)
+    let mut first = archive.for_directory(Path::new(""));
+    let mut second = archive.for_directory(Path::new(""));
+    let mut entries = first.read::<U2>().unwrap();
+    assert_eq!(second.read::<U2>().unwrap().iter().count(), 1);
+    drop(second);
+
+    // once the other reader is gone, the lock can be upgraded for writing
+    entries.insert(
+        Path::new("bar"),
+        entries.get(Path::new("foo")).unwrap().clone(),
+    );
+    first.write(&mut entries).unwrap();
+    drop(first);
+    assert_eq!(
+        archive.dump_directory::<U2>(Path::new("")).unwrap().len(),
+        2
+    );
+}
+
+#[test]
+fn test_archive_lock_failure() {
+    let (archive, config) = set_up("archive_lock_failure");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // the lock file can't be opened if there is a directory in the way
+    let lock_file = archive
+        .directory
+        .join(format!("{}.lock", archive.hash(Path::new(""))));
+    fs::remove_file(&lock_file).unwrap();
+    fs::create_dir(&lock_file).unwrap();
+
+    match detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    ) {
+        Err(SyncError::ArchiveReadError(ReadError::LockError(path, _))) => {
+            assert_eq!(path, lock_file.with_extension(""))
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected locking the archive file to fail"),
+    }
+    fs::remove_dir(&lock_file).unwrap();
+}
+
+#[test]
+fn test_archive_lock_policy() {
+    use std::time::{Duration, Instant};
+    use ubiquity::archive::{ArchiveEntries, LockPolicy};
+
+    let (mut archive, config) = set_up("archive_lock_policy");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // another "process" holds an exclusive lock on the root's archive file
+    let mut held = archive.for_directory(Path::new(""));
+    let mut entries: ArchiveEntries<U2> = held.read().unwrap();
+    held.write(&mut entries).unwrap();
+
+    let find = |archive: &Archive| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+    };
+    archive.lock_policy = LockPolicy::FailFast;
+    match find(&archive) {
+        Err(SyncError::ArchiveLocked(path)) => {
+            assert_eq!(
+                path,
+                archive
+                    .directory
+                    .join(archive.hash(Path::new("")).to_string())
+            )
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected the archive file to be locked"),
+    }
+
+    archive.lock_policy = LockPolicy::Timeout(Duration::from_millis(200));
+    let start = Instant::now();
+    assert!(matches!(find(&archive), Err(SyncError::ArchiveLocked(_))));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    held.unlock();
+    assert!(find(&archive).unwrap().differences.is_empty());
+}
+
+#[test]
+fn test_failed_archive_lock_isnt_held() {
+    use ubiquity::archive::{ArchiveEntries, LockPolicy};
+
+    let (mut archive, config) = set_up("failed_archive_lock_isnt_held");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    archive.lock_policy = LockPolicy::FailFast;
+
+    // another "process" holds an exclusive lock, so retrying mustn't succeed either
+    let mut held = archive.for_directory(Path::new(""));
+    held.lock_exclusive().unwrap();
+    let mut archive_file = archive.for_directory(Path::new(""));
+    assert!(archive_file.lock_exclusive().is_err());
+    assert!(archive_file.lock_exclusive().is_err());
+    assert!(archive_file.read::<U2>().is_err());
+    assert!(archive_file.read::<U2>().is_err());
+    held.unlock();
+
+    // a failed upgrade keeps the shared lock, but doesn't pretend to be exclusive
+    held.lock_shared().unwrap();
+    let mut entries: ArchiveEntries<U2> = archive_file.read().unwrap();
+    assert!(archive_file.lock_exclusive().is_err());
+    assert!(archive_file.lock_exclusive().is_err());
+    assert!(archive_file.write(&mut entries).is_err());
+    assert!(archive_file.read::<U2>().is_ok());
+    held.unlock();
+    archive_file.lock_exclusive().unwrap();
+    archive_file.write(&mut entries).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_archive_lock_files_are_removed() {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use ubiquity::archive::LockPolicy;
+
+    let (archive, config) = set_up("archive_lock_files_are_removed");
+    let lock_files = || {
+        fs::read_dir(&archive.directory)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("lock".as_ref()))
+            .count()
+    };
+
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::write(config.roots[0].join("dir/foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    let before = lock_files();
+    assert!(before >= 2);
+
+    // another "process" is waiting for the lock when the archive file (and its lock file) is removed
+    let mut fail_fast = Archive::new(archive.directory.clone()).unwrap();
+    fail_fast.lock_policy = LockPolicy::FailFast;
+    let mut held = archive.for_directory(Path::new("dir"));
+    held.lock_exclusive().unwrap();
+    thread::scope(|scope| {
+        let (locked, wait_for_lock) = mpsc::channel();
+        let (release, wait_for_release) = mpsc::channel::<()>();
+        let archive = &archive;
+        scope.spawn(move || {
+            let mut waiting = archive.for_directory(Path::new("dir"));
+            waiting.lock_exclusive().unwrap();
+            locked.send(()).unwrap();
+            let _ = wait_for_release.recv();
+        });
+        thread::sleep(Duration::from_millis(100));
+        held.remove_all().unwrap();
+        assert_eq!(lock_files(), before - 1);
+
+        // the waiter locked a new lock file, which still excludes everyone else
+        wait_for_lock.recv().unwrap();
+        assert_eq!(lock_files(), before);
+        assert!(fail_fast
+            .for_directory(Path::new("dir"))
+            .lock_exclusive()
+            .is_err());
+        release.send(()).unwrap();
+    });
+
+    // writing no entries removes the lock file too
+    let mut archive_file = archive.for_directory(Path::new("dir"));
+    archive_file
+        .write(&mut ubiquity::archive::ArchiveEntries::<U2>::empty())
+        .unwrap();
+    assert_eq!(lock_files(), before - 1);
+}
+
+#[test]
+fn test_three_replicas() {
+    let (archive, config) = set_up_three("three_replicas");
+
+    let sync = |config: &SyncInfo<U3>| {
+        let result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        let mut operations = Vec::new();
+        for difference in result.differences {
+            let operation = reconcile::guess_operation(&difference);
+            if let reconcile::Operation::PropagateFromMaster(master) = operation {
+                propagate::propagate(
+                    &difference,
+                    master,
+                    &archive,
+                    &propagate::DefaultPropagationOptions,
+                    &propagate::EmptyProgressCallback,
+                )
+                .unwrap();
+            }
+            operations.push(operation);
+        }
+        operations
+    };
+
+    // a new file is copied to both other replicas
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let operations = sync(&config);
+    assert!(matches!(
+        operations[..],
+        [reconcile::Operation::PropagateFromMaster(0)]
+    ));
+    for root in config.roots.iter() {
+        assert_eq!(fs::read_to_string(root.join("foo")).unwrap(), "Hello World");
+    }
+
+    // deleting it from two replicas isn't a conflict
+    fs::remove_file(config.roots[1].join("foo")).unwrap();
+    fs::remove_file(config.roots[2].join("foo")).unwrap();
+    let operations = sync(&config);
+    assert!(matches!(
+        operations[..],
+        [reconcile::Operation::PropagateFromMaster(1)]
+    ));
+    assert!(!config.roots[0].join("foo").exists());
+
+    // a directory replaces a file on one replica, and nothing on another
+    fs::create_dir(config.roots[0].join("bar")).unwrap();
+    fs::write(config.roots[0].join("bar/baz"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("bar"), "Hello Earth").unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    for root in config.roots.iter() {
+        assert_eq!(
+            fs::read_to_string(root.join("bar/baz")).unwrap(),
+            "Hello World"
+        );
+    }
+    assert!(sync(&config).is_empty());
+}
+
+struct RemoveRootCallback {
+    root: PathBuf,
+    when_reading: PathBuf,
+}
+
+impl detect::ProgressCallback for RemoveRootCallback {
+    fn reading_directory(&self, path: &Path, _: usize, _: usize) {
+        if path == self.when_reading {
+            fs::remove_dir_all(&self.root).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_root_disappears_during_detection() {
+    let (archive, config) = set_up("root_disappears");
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::File::create(root.join("dir").join("foo")).unwrap();
+    }
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
+    let before = archive.dump_directory::<U2>(Path::new("dir")).unwrap();
+    assert_eq!(before.len(), 1);
+
+    let callback = RemoveRootCallback {
+        root: config.roots[1].clone(),
+        when_reading: PathBuf::from("dir"),
+    };
+    match detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &callback,
+    ) {
+        Err(SyncError::RootDisappeared(ref root)) if *root == config.roots[1] => {}
+        other => panic!(
+            "expected the root to have disappeared, got {:?}",
+            other.map(|r| r.differences.len())
+        ),
+    }
+
+    // the vanished replica mustn't have been recorded as empty
+    assert_eq!(
+        archive.dump_directory::<U2>(Path::new("dir")).unwrap(),
+        before
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_follow_symlinks() {
+    let (archive, mut config) = set_up("follow_symlinks");
+    // the link target is ignored, so its contents can only be reached through the link
+    config.ignore.paths.push("hidden".to_owned());
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("hidden")).unwrap();
+        std::os::unix::fs::symlink("hidden", root.join("link")).unwrap();
+    }
+    fs::File::create(config.roots[0].join("hidden").join("foo")).unwrap();
+
+    let paths = |config: &SyncInfo| {
+        let result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        result
+            .differences
+            .into_iter()
+            .map(|d| d.path)
+            .collect::<Vec<_>>()
+    };
+
+    // by default the link is preserved, and never descended into
+    assert_eq!(paths(&config), Vec::<PathBuf>::new());
+
+    config.follow_symlinks = true;
+    assert_eq!(paths(&config), vec![PathBuf::from("link/foo")]);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_symlink_loops_terminate() {
+    let (archive, mut config) = set_up("symlink_loops_terminate");
+    config.follow_symlinks = true;
+
+    for root in config.roots.iter() {
+        std::os::unix::fs::symlink(".", root.join("loop")).unwrap();
+    }
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 0);
+    assert_eq!(result.statistics.directories_scanned, 1);
+
+    // propagating a directory containing a loop updates the archive without following it forever
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    std::os::unix::fs::symlink("..", config.roots[0].join("dir").join("up")).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &TestOptions::default(),
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_bandwidth_limit() {
+    let (archive, config) = set_up("bandwidth_limit");
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        bandwidth_limit_kbps: Some(128),
+        ..Default::default()
+    };
+
+    fs::write(config.roots[0].join("foo"), vec![0u8; 64 * 1024]).unwrap();
+
+    let start = std::time::Instant::now();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    // 64KiB at 128KiB/s takes half a second
+    assert!(start.elapsed() >= std::time::Duration::from_millis(450));
+    assert_eq!(
+        fs::read(config.roots[1].join("foo")).unwrap().len(),
+        64 * 1024
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_extra_rsync_args() {
+    let (archive, config) = set_up("extra_rsync_args");
+
+    let (wrapper, args_path) = recording_rsync("tests/replicas/extra_rsync_args");
+
+    let options = propagate::BasicPropagationOptions {
+        rsync_path: wrapper,
+        extra_rsync_args: vec!["--checksum".into()],
+        ..Default::default()
+    };
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+
+    assert!(config.roots[1].join("foo").exists());
+    let args = fs::read_to_string(args_path).unwrap();
+    assert!(
+        args.starts_with("-a --info=progress2,name1 --checksum "),
+        "unexpected arguments: {}",
+        args
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_resume_partial() {
+    let (archive, config) = set_up("resume_partial");
+
+    let (wrapper, args_path) = recording_rsync("tests/replicas/resume_partial");
+
+    let options = propagate::BasicPropagationOptions {
+        rsync_path: wrapper,
+        resume_partial: true,
+        ..Default::default()
+    };
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    let args = fs::read_to_string(args_path).unwrap();
+    assert!(
+        args.contains("--partial --partial-dir=.ubiquity-partial "),
+        "unexpected arguments: {}",
+        args
+    );
+
+    // an interrupted transfer, and then the file is deleted on the master replica
+    let partial_dir = config.roots[1].join(propagate::PARTIAL_DIR);
+    fs::create_dir(&partial_dir).unwrap();
+    fs::write(partial_dir.join("foo"), "Hello").unwrap();
+    fs::remove_file(config.roots[0].join("foo")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, PathBuf::from("foo"));
+    propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    assert!(!config.roots[1].join("foo").exists());
+    assert!(!partial_dir.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rsync_failure_includes_stderr() {
+    let (archive, config) = set_up("rsync_failure_includes_stderr");
+
+    let rsync = write_script(
+        "tests/replicas/rsync_failure_includes_stderr",
+        "rsync",
+        "#!/bin/sh\necho 'No space left on device' >&2\nexit 11\n",
+    );
+
+    fs::File::create(config.roots[0].join("foo")).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    let options = propagate::BasicPropagationOptions {
+        rsync_path: rsync,
+        ..Default::default()
+    };
+    let error = propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap_err();
+    match error {
+        SyncError::RsyncFailed { ref stderr, .. } => {
+            assert_eq!(stderr, "No space left on device\n")
+        }
+        ref other => panic!("expected RsyncFailed, got {:?}", other),
+    }
+    assert!(error.to_string().ends_with(": No space left on device"));
+}
+
+/// The transferred bytes, percentage, files transferred and files to check of an rsync progress update
+type ProgressUpdate = (usize, u8, Option<u32>, Option<(u32, u32)>);
+
+#[derive(Default)]
+struct RecordingProgress {
+    updates: std::cell::RefCell<Vec<ProgressUpdate>>,
+}
+
+impl propagate::ProgressCallback for RecordingProgress {
+    fn rsync_progress(
+        &self,
+        transferred_bytes: usize,
+        progress: u8,
+        _: &str,
+        _: &str,
+        transferred: Option<u32>,
+        to_check: Option<propagate::ToCheck>,
+    ) {
+        self.updates.borrow_mut().push((
+            transferred_bytes,
+            progress,
+            transferred,
+            to_check.map(|c| (c.remaining, c.total)),
+        ));
+    }
+}
+
+#[test]
+fn test_rsync_progress_parsing() {
+    let output = concat!(
+        // rsync 3.0
+        "     32768  50%   31.25MB/s    0:00:00\r",
+        "     65536 100%   62.50MB/s    0:00:00 (xfer#1, to-check=0/1)\n",
+        // rsync 3.1
+        "          1,234  10%    0.00kB/s    0:00:00 (xfr#1, to-chk=9/10)\r",
+        // rsync 3.2, with a locale which uses dots as thousands separators
+        "      1.048.576  50%    1,00MB/s    0:00:01 (xfr#2, ir-chk=1000/1234)\r",
+        // lines which aren't progress updates
+        "sending incremental file list\n",
+        "12 abc% 0.00kB/s 0:00:00\r",
+        "\r",
+    );
+    let progress = RecordingProgress::default();
+    propagate::parse_from_stdout(output.as_bytes(), &progress).unwrap();
+    assert_eq!(
+        progress.updates.into_inner(),
+        vec![
+            (32768, 50, None, None),
+            (65536, 100, Some(1), Some((0, 1))),
+            (1234, 10, Some(1), Some((9, 10))),
+            (1048576, 50, Some(2), Some((1000, 1234))),
+        ]
+    );
+
+    // invalid UTF-8 doesn't interrupt the transfer
+    let invalid_utf8: &[u8] = b"  100 100% \xff 0:00:00\r";
+    let progress = RecordingProgress::default();
+    propagate::parse_from_stdout(invalid_utf8, &progress).unwrap();
+    assert_eq!(progress.updates.into_inner(), vec![(100, 100, None, None)]);
+}
+
+#[derive(Default)]
+struct OverallProgressRecorder {
+    events: std::cell::RefCell<Vec<String>>,
+    overall_bytes: std::cell::Cell<u64>,
+}
+
+impl propagate::ProgressCallback for OverallProgressRecorder {
+    fn rsync_progress(
+        &self,
+        _: usize,
+        _: u8,
+        _: &str,
+        _: &str,
+        _: Option<u32>,
+        _: Option<propagate::ToCheck>,
+    ) {
+    }
+
+    fn start_operation(&self, total_replicas: usize) {
+        self.events
+            .borrow_mut()
+            .push(format!("start {}", total_replicas));
+    }
+
+    fn replica_completed(&self, index: usize) {
+        self.events
+            .borrow_mut()
+            .push(format!("completed {}", index));
+    }
+
+    fn overall_progress(&self, transferred_bytes: u64) {
+        assert!(transferred_bytes >= self.overall_bytes.get());
+        self.overall_bytes.set(transferred_bytes);
+    }
+}
+
+#[test]
+fn test_overall_progress() {
+    let (archive, config) = set_up_three("overall_progress");
+
+    fs::write(config.roots[0].join("foo"), vec![0u8; 1000]).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        ..Default::default()
+    };
+    let progress = OverallProgressRecorder::default();
+    propagate::propagate(&result.differences[0], 0, &archive, &options, &progress).unwrap();
+
+    assert_eq!(
+        progress.events.into_inner(),
+        vec!["start 2", "completed 1", "completed 2"]
+    );
+    assert_eq!(progress.overall_bytes.get(), 2000);
+}
+
+#[derive(Default)]
+struct CurrentFileRecorder {
+    files: std::cell::RefCell<Vec<PathBuf>>,
+}
+
+impl propagate::ProgressCallback for CurrentFileRecorder {
+    fn rsync_progress(
+        &self,
+        _: usize,
+        _: u8,
+        _: &str,
+        _: &str,
+        _: Option<u32>,
+        _: Option<propagate::ToCheck>,
+    ) {
+    }
+
+    fn current_file(&self, path: &Path) {
+        self.files.borrow_mut().push(path.to_path_buf());
+    }
+}
+
+#[test]
+fn test_current_file() {
+    let (archive, config) = set_up("current_file");
+
+    fs::create_dir_all(config.roots[0].join("dir/sub")).unwrap();
+    fs::File::create(config.roots[0].join("dir/foo")).unwrap();
+    fs::File::create(config.roots[0].join("dir/sub/bar")).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        ..Default::default()
+    };
+    let progress = CurrentFileRecorder::default();
+    propagate::propagate(&result.differences[0], 0, &archive, &options, &progress).unwrap();
+    let mut files = progress.files.into_inner();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            config.roots[0].join("dir/foo"),
+            config.roots[0].join("dir/sub/bar")
+        ]
+    );
+
+    // rsync's `--info=name` output
+    let output =
+        "./\nsub/\nsub/bar\n              0 100%    0.00kB/s    0:00:00 (xfr#1, to-chk=0/4)\r";
+    let progress = CurrentFileRecorder::default();
+    propagate::parse_from_stdout(output.as_bytes(), &progress).unwrap();
+    assert_eq!(progress.files.into_inner(), vec![PathBuf::from("sub/bar")]);
+
+    // names are passed on even if they aren't valid UTF-8
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let output: &[u8] =
+            b"caf\xe9\n              0 100%    0.00kB/s    0:00:00 (xfr#1, to-chk=0/1)\r";
+        let progress = CurrentFileRecorder::default();
+        propagate::parse_from_stdout(output, &progress).unwrap();
+        assert_eq!(
+            progress.files.into_inner(),
+            vec![PathBuf::from(OsStr::from_bytes(b"caf\xe9"))]
+        );
+    }
+}
+
+#[test]
+fn test_directory_only_ignore_rules() {
+    let (archive, mut config) = set_up("directory_only_ignore_rules");
+    config
+        .ignore
+        .rules
+        .push(IgnoreRule::parse("cache/").unwrap().unwrap());
+    let mut data = IgnoreRule::parse("data").unwrap().unwrap();
+    data.applies_to = IgnoreTarget::FileOnly;
+    config.ignore.rules.push(data);
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("sub")).unwrap();
+    }
+    fs::create_dir(config.roots[0].join("cache")).unwrap();
+    fs::File::create(config.roots[0].join("cache/foo")).unwrap();
+    fs::File::create(config.roots[0].join("sub/cache")).unwrap();
+    fs::File::create(config.roots[0].join("data")).unwrap();
+    fs::create_dir(config.roots[0].join("sub/data")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut paths: Vec<PathBuf> = result.differences.into_iter().map(|d| d.path).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![PathBuf::from("sub/cache"), PathBuf::from("sub/data")]
+    );
+}
+
+#[test]
+fn test_max_file_size() {
+    let (archive, mut config) = set_up("max_file_size");
+    config.max_file_size = Some(100);
+
+    fs::write(config.roots[0].join("big"), vec![0u8; 200]).unwrap();
+    fs::write(config.roots[0].join("small"), vec![0u8; 10]).unwrap();
+    fs::write(config.roots[0].join("mixed"), vec![0u8; 200]).unwrap();
+    fs::write(config.roots[1].join("mixed"), vec![1u8; 10]).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut differences = result.differences;
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+    let paths: Vec<&Path> = differences.iter().map(|d| d.path.as_path()).collect();
+    assert_eq!(paths, vec![Path::new("mixed"), Path::new("small")]);
+
+    // the oversized version is never copied, but the smaller one can be
+    match propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::FileTooLarge(path)) => assert_eq!(path, config.roots[0].join("mixed")),
+        other => panic!("expected FileTooLarge, got {:?}", other),
+    }
+    assert_eq!(fs::read(config.roots[1].join("mixed")).unwrap().len(), 10);
+    propagate::propagate(
+        &differences[0],
+        1,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(fs::read(config.roots[0].join("mixed")).unwrap().len(), 10);
+}
+
+#[test]
+fn test_min_age() {
+    let (archive, mut config) = set_up("min_age");
+    config.min_age = Some(std::time::Duration::from_secs(60));
+
+    fs::File::create(config.roots[0].join("new")).unwrap();
+    let old = config.roots[0].join("old");
+    fs::File::create(&old).unwrap();
+    filetime::set_file_mtime(&old, filetime::FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let paths: Vec<&Path> = result
+        .differences
+        .iter()
+        .map(|d| d.path.as_path())
+        .collect();
+    assert_eq!(paths, vec![Path::new("old")]);
+
+    // once it has settled, the new file is picked up
+    config.min_age = None;
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+}
+
+#[test]
+fn test_deletions_can_be_disallowed() {
+    let (archive, config) = set_up("deletions_can_be_disallowed");
+    let options = propagate::BasicPropagationOptions {
+        allow_deletions: false,
+        ..Default::default()
+    };
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    assert!(config.roots[1].join("foo").exists());
+
+    fs::remove_file(config.roots[0].join("foo")).unwrap();
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    let actions = propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap()
+    .actions;
+    assert!(actions.is_empty());
+    assert!(config.roots[1].join("foo").exists());
+
+    // the deletion is reported again by the next sync
+    assert_eq!(find().differences.len(), 1);
+}
+
+struct KeepDirectoriesOptions;
+
+impl PropagationOptions for KeepDirectoriesOptions {
+    fn should_remove(&self, _: &Path) -> bool {
+        true
+    }
+    fn should_remove_dir(&self, _: &Path) -> bool {
+        false
+    }
+    fn remove_file(&self, path: &Path) -> Result<(), SyncError> {
+        propagate::DefaultPropagationOptions.remove_file(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> Result<(), SyncError> {
+        propagate::DefaultPropagationOptions.remove_dir_all(path)
+    }
+}
+
+#[test]
+fn test_file_and_directory_removal_are_confirmed_separately() {
+    let (archive, config) = set_up("file_and_directory_removal_are_confirmed_separately");
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::File::create(root.join("dir/foo")).unwrap();
+        fs::File::create(root.join("file")).unwrap();
+    }
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    fs::remove_dir_all(config.roots[0].join("dir")).unwrap();
+    fs::remove_file(config.roots[0].join("file")).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+
+    for difference in &result.differences {
+        let result = propagate::propagate(
+            difference,
+            0,
+            &archive,
+            &KeepDirectoriesOptions,
+            &propagate::EmptyProgressCallback,
+        );
+        if difference.path == Path::new("dir") {
+            assert!(matches!(result, Err(SyncError::Cancelled)));
+        } else {
+            result.unwrap();
+        }
+    }
+    assert!(config.roots[1].join("dir/foo").exists());
+    assert!(!config.roots[1].join("file").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_verify_after_copy() {
+    let (archive, config) = set_up("verify_after_copy");
+
+    // a wrapper around rsync which truncates the copy
+    let rsync = write_script(
+        "tests/replicas/verify_after_copy",
+        "rsync",
+        "#!/bin/sh\nrsync \"$@\"\nfor arg; do dest=\"$arg\"; done\n: > \"$dest\"\n",
+    );
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let result = find();
+    let options = propagate::BasicPropagationOptions {
+        rsync_path: rsync,
+        verify_after_copy: true,
+        ..Default::default()
+    };
+    match propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::VerificationFailed(path)) => assert_eq!(path, config.roots[1].join("foo")),
+        other => panic!("expected VerificationFailed, got {:?}", other),
+    }
+
+    // the archive wasn't updated, so the difference is still there
+    assert_eq!(find().differences.len(), 1);
+}
+
+#[test]
+fn test_path_modified_policy() {
+    let (archive, config) = set_up_three("path_modified_policy");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+
+    // modified after detection
+    fs::write(config.roots[2].join("foo"), "Hello Mars").unwrap();
+
+    let mut options = propagate::BasicPropagationOptions {
+        on_path_modified: propagate::PathModifiedPolicy::Skip,
+        ..Default::default()
+    };
+    let actions = propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap()
+    .actions;
+    assert!(actions.contains(&PropagationAction::Skipped(config.roots[2].join("foo"))));
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Hello World"
+    );
+    assert_eq!(
+        fs::read_to_string(config.roots[2].join("foo")).unwrap(),
+        "Hello Mars"
+    );
+    // the skipped replica is picked up again
+    assert_eq!(find().differences.len(), 1);
+
+    options.on_path_modified = propagate::PathModifiedPolicy::Retry;
+    fs::write(config.roots[2].join("foo"), "Hello Venus").unwrap();
+    propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(config.roots[2].join("foo")).unwrap(),
+        "Hello World"
+    );
+}
+
+#[test]
+fn test_verify_in_sync() {
+    let (archive, config) = set_up("verify_in_sync");
+
+    for root in config.roots.iter() {
+        fs::write(root.join("foo"), "Hello World").unwrap();
+        fs::write(root.join("bar"), "Hello World").unwrap();
+    }
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(result.differences.is_empty());
+    assert!(detect::verify_in_sync(&archive, &config)
+        .unwrap()
+        .is_empty());
+
+    // same size, different contents
+    fs::write(config.roots[1].join("foo"), "Hello Mars!").unwrap();
+    assert_eq!(
+        detect::verify_in_sync(&archive, &config).unwrap(),
+        vec![PathBuf::from("foo")]
+    );
+}
+
+#[test]
+fn test_size_and_mtime_content_comparison() {
+    use filetime::FileTime;
+
+    let (archive, mut config) = set_up("size_and_mtime_content_comparison");
+    config.content_comparison = ContentComparison::SizeAndMtime;
+    let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+
+    // same size and modification time, but different contents
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("foo"), "Hello Mars!").unwrap();
+    for root in config.roots.iter() {
+        filetime::set_file_mtime(root.join("foo"), mtime).unwrap();
+    }
+
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    // intentionally treated as in sync
+    assert_eq!(find().differences.len(), 0);
+
+    // once the modification times differ, the contents are compared
+    filetime::set_file_mtime(
+        config.roots[1].join("foo"),
+        FileTime::from_unix_time(1_000_000_001, 0),
+    )
+    .unwrap();
+    assert_eq!(find().differences.len(), 1);
+}
+
+#[test]
+fn test_propagation_report() {
+    let (archive, config) = set_up_three("propagation_report");
+
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::write(config.roots[0].join("dir").join("x"), "Hello").unwrap();
+    fs::write(config.roots[0].join("dir").join("y"), "World!").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    // the native backend reports exactly what it copied
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        ..Default::default()
+    };
+    let report = propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    assert_eq!(report.actions.len(), 2);
+    assert_eq!(report.dirs_copied, 2);
+    assert_eq!(report.files_copied, 0);
+    assert_eq!(report.files_deleted + report.dirs_deleted, 0);
+    assert_eq!(report.bytes_transferred, 22);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_continue_on_error() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (archive, mut config) = set_up("continue_on_error");
+    config.continue_on_error = true;
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("locked")).unwrap();
+        fs::write(root.join("locked").join("foo"), "Hello World").unwrap();
+    }
+    fs::write(config.roots[0].join("bar"), "Hello World").unwrap();
+    let locked = config.roots[1].join("locked");
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    if fs::read_dir(&locked).is_ok() {
+        // permissions aren't enforced (eg: running as root)
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        return;
+    }
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    );
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    let result = result.unwrap();
+
+    // `locked/foo` isn't reported as deleted
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, PathBuf::from("bar"));
+    assert_eq!(result.statistics.skipped_errors.len(), 1);
+    assert_eq!(
+        result.statistics.skipped_errors[0].0,
+        PathBuf::from("locked")
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_native_copy_preserves_xattrs() {
+    let (archive, config) = set_up("native_copy_preserves_xattrs");
+
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::write(config.roots[0].join("dir").join("foo"), "Hello World").unwrap();
+    fs::write(config.roots[0].join("bar"), "Hello World").unwrap();
+    for path in &["dir", "dir/foo", "bar"] {
+        if xattr::set(config.roots[0].join(path), "user.ubiquity", b"tag").is_err() {
+            // the filesystem doesn't support extended attributes
+            return;
+        }
+    }
+
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        preserve_xattrs: true,
+        ..Default::default()
+    };
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+
+    for path in &["dir", "dir/foo", "bar"] {
+        assert_eq!(
+            xattr::get(config.roots[1].join(path), "user.ubiquity").unwrap(),
+            Some(b"tag".to_vec()),
+            "{} is missing its extended attribute",
+            path
+        );
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_file_and_symlink_with_same_contents_differ() {
+    use std::os::unix::fs::symlink;
+
+    let (archive, mut config) = set_up("file_and_symlink_with_same_contents_differ");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("target"), "Hello World").unwrap();
+    symlink("target", config.roots[1].join("foo")).unwrap();
+
+    for &comparison in &[
+        ContentComparison::Full,
+        ContentComparison::SizeOnly,
+        ContentComparison::SizeAndMtime,
+    ] {
+        config.content_comparison = comparison;
+        let result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        let paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+        assert!(
+            paths.contains(&PathBuf::from("foo")),
+            "{:?} didn't report foo: {:?}",
+            comparison,
+            paths
+        );
+    }
+}
+
+/// A hash with a fixed key, which gives the same hashes in every process.
+#[derive(Debug)]
+struct KeyedHasher(u64);
+
+impl std::hash::BuildHasher for KeyedHasher {
+    type Hasher = twox_hash::XxHash64;
+    fn build_hasher(&self) -> twox_hash::XxHash64 {
+        twox_hash::XxHash64::with_seed(self.0)
+    }
+}
+
+#[test]
+fn test_archive_with_hasher() {
+    let (archive, config) = set_up("archive_with_hasher");
+    let directory = archive.directory;
+    let archive = Archive::with_hasher(directory.clone(), KeyedHasher(42)).unwrap();
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert!(archive
+        .directory
+        .join(archive.hash(Path::new("")).to_string())
+        .exists());
+    assert_ne!(
+        archive.hash(Path::new("")),
+        Archive::new(directory.clone()).unwrap().hash(Path::new(""))
+    );
+    drop(archive);
+
+    // reopened with a new hasher instance, as another process would
+    let find_updates = |archive: &Archive| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let reopened = Archive::with_hasher(directory.clone(), KeyedHasher(42)).unwrap();
+    let result = find_updates(&reopened);
+    assert!(result.differences.is_empty());
+    assert_eq!(result.statistics.archive_hits, 1);
+
+    // so does a read-only archive with the same key
+    let read_only = Archive::read_only_with_hasher(directory.clone(), KeyedHasher(42));
+    let result = find_updates(&read_only);
+    assert!(result.differences.is_empty());
+    assert_eq!(result.statistics.archive_hits, 1);
+
+    // a different key misses every entry
+    let other_key = Archive::with_hasher(directory, KeyedHasher(43)).unwrap();
+    let result = find_updates(&other_key);
+    assert_eq!(result.statistics.archive_hits, 0);
+}
+
+struct DirectoryCounter(std::cell::Cell<usize>);
+
+impl detect::ProgressCallback for DirectoryCounter {
+    fn reading_directory(&self, _: &Path, _: usize, _: usize) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn test_estimate_directory_count() {
+    let (archive, mut config) = set_up("estimate_directory_count");
+    config.ignore.paths.push("hidden".to_owned());
+
+    for root in config.roots.iter() {
+        fs::create_dir_all(root.join("x").join("y")).unwrap();
+        fs::create_dir_all(root.join("z")).unwrap();
+        fs::create_dir_all(root.join("hidden").join("w")).unwrap();
+        fs::write(root.join("x").join("foo"), "Hello World").unwrap();
+    }
+
+    let search = detect::SearchDirectories::from_root();
+    let estimate = detect::estimate_directory_count(&search, &config).unwrap();
+    assert_eq!(estimate, 4);
+
+    let counter = DirectoryCounter(Default::default());
+    detect::find_updates(&archive, &mut search.clone(), &config, &counter).unwrap();
+    assert_eq!(counter.0.get(), estimate);
+}
+
+#[test]
+fn test_create_missing_roots() {
+    let (archive, mut config) = set_up("create_missing_roots");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    fs::remove_dir(&config.roots[1]).unwrap();
+    match detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    ) {
+        Err(SyncError::RootDoesntExist(_)) => {}
+        other => panic!("unexpected result: {:?}", other.map(|r| r.differences)),
+    }
+
+    config.create_missing_roots = true;
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert!(config.roots[1].join("foo").exists());
+
+    // the root disappears after it has been synced, but isn't treated as if everything was deleted
+    fs::remove_dir_all(&config.roots[1]).unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert!(config.roots[0].join("foo").exists());
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Hello World"
+    );
+}
+
+#[test]
+fn test_prune_orphaned_archive_files() {
+    let (archive, config) = set_up("prune_orphaned_archive_files");
+
+    for root in config.roots.iter() {
+        fs::create_dir_all(root.join("dir").join("sub")).unwrap();
+        fs::write(root.join("dir").join("sub").join("foo"), "Hello World").unwrap();
+        fs::write(root.join("bar"), "Hello World").unwrap();
+    }
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert_eq!(archive.prune_orphans(&config).unwrap(), 0);
+
+    // deleted behind ubiquity's back, so the archive isn't updated
+    for root in config.roots.iter() {
+        fs::remove_dir_all(root.join("dir")).unwrap();
+    }
+    assert_eq!(archive.prune_orphans(&config).unwrap(), 2);
+    assert!(!archive
+        .directory
+        .join(archive.hash(Path::new("dir")).to_string())
+        .exists());
+    assert!(archive
+        .directory
+        .join(archive.hash(Path::new("")).to_string())
+        .exists());
+    #[cfg(unix)]
+    {
+        let lock_file = |path: &str| {
+            archive
+                .directory
+                .join(format!("{}.lock", archive.hash(Path::new(path))))
+        };
+        assert!(!lock_file("dir").exists());
+        assert!(lock_file("").exists());
+
+        // a lock file without an archive file, as left behind by older versions
+        fs::write(lock_file("baz"), "").unwrap();
+        assert_eq!(archive.prune_orphans(&config).unwrap(), 0);
+        assert!(!lock_file("baz").exists());
+        assert!(lock_file("").exists());
+    }
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_difference_json_round_trip() {
+    let (archive, config) = set_up("difference_json_round_trip");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let difference = &result.differences[0];
+
+    let json = difference.to_json().unwrap();
+    assert!(
+        json.contains("\"path\":\"foo\""),
+        "unexpected json: {}",
+        json
+    );
+    let parsed: detect::Difference<U2> = detect::Difference::from_json(&json).unwrap();
+    assert_eq!(parsed.path, difference.path);
+    assert_eq!(parsed.roots, difference.roots);
+    assert_eq!(parsed.previous_state, difference.previous_state);
+    assert_eq!(parsed.current_state, difference.current_state);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_partial_propagation_is_recorded() {
+    let (archive, config) = set_up_three("partial_propagation_is_recorded");
+
+    // rsync fails when copying to the last replica
+    let wrapper = write_script(
+        "tests/replicas/partial_propagation_is_recorded",
+        "rsync",
+        "#!/bin/sh\ncase \"$*\" in\n  *partial_propagation_is_recorded/c*) echo 'Connection reset' >&2; exit 12 ;;\nesac\nexec rsync \"$@\"\n",
+    );
+    let failing = propagate::BasicPropagationOptions {
+        rsync_path: wrapper,
+        ..Default::default()
+    };
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    match propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &failing,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::RsyncFailed { .. }) => {}
+        other => panic!(
+            "unexpected result: {:?}",
+            other.map(|report| report.actions)
+        ),
+    }
+    assert!(config.roots[1].join("foo").exists());
+
+    // the copy to the second replica is recorded, so the item isn't mistaken for a conflict
+    let dump = archive.dump_directory::<U3>(Path::new("")).unwrap();
+    assert_eq!(dump.len(), 1);
+    assert_eq!(dump[0].0, PathBuf::from("foo"));
+    assert!(!dump[0].1[0].entry_exists());
+    assert!(dump[0].1[1].entry_exists());
+    assert!(!dump[0].1[2].entry_exists());
+
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    assert!(matches!(
+        reconcile::guess_operation(&result.differences[0]),
+        reconcile::Operation::PropagateFromMaster(0)
+    ));
+    propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(find().differences.is_empty());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_preserve_hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (archive, mut config) = set_up("preserve_hard_links");
+    config.detect_hard_links = true;
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    fs::hard_link(config.roots[0].join("foo"), config.roots[0].join("bar")).unwrap();
+    fs::create_dir(config.roots[0].join("dir")).unwrap();
+    fs::write(config.roots[0].join("dir").join("x"), "Goodbye World").unwrap();
+    fs::hard_link(
+        config.roots[0].join("dir").join("x"),
+        config.roots[0].join("dir").join("y"),
+    )
+    .unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let linked: Vec<_> = result
+        .differences
+        .iter()
+        .filter_map(|d| d.hard_link_of.first().cloned().flatten())
+        .collect();
+    assert_eq!(linked.len(), 1);
+    assert!(linked[0] == Path::new("foo") || linked[0] == Path::new("bar"));
+
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        preserve_hard_links: true,
+        ..Default::default()
+    };
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+
+    let ino = |path: &str| fs::metadata(config.roots[1].join(path)).unwrap().ino();
+    assert_eq!(ino("foo"), ino("bar"));
+    assert_eq!(ino("dir/x"), ino("dir/y"));
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("bar")).unwrap(),
+        "Hello World"
+    );
+    assert!(detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap()
+    .differences
+    .is_empty());
+}
+
+#[test]
+fn test_empty_directories_are_propagated() {
+    for &(name, backend) in &[
+        ("empty_directories_are_propagated_rsync", CopyBackend::Rsync),
+        (
+            "empty_directories_are_propagated_native",
+            CopyBackend::Native,
+        ),
+    ] {
+        let (archive, config) = set_up(name);
+        fs::create_dir(config.roots[0].join("empty")).unwrap();
+        fs::create_dir_all(config.roots[0].join("parent").join("placeholder")).unwrap();
+
+        let options = propagate::BasicPropagationOptions {
+            copy_backend: backend,
+            ..Default::default()
+        };
+        detect_and_resolve_with(
+            &archive,
+            &config,
+            &detect::SearchDirectories::from_root(),
+            &options,
+        );
+
+        assert!(config.roots[1].join("empty").is_dir(), "{:?}", backend);
+        assert!(
+            config.roots[1].join("parent").join("placeholder").is_dir(),
+            "{:?}",
+            backend
+        );
+        // the directories are in the archive, so they aren't detected again
+        let dump = archive.dump_directory::<U2>(Path::new("")).unwrap();
+        assert!(dump.iter().any(|(path, _)| path == Path::new("empty")));
+        assert!(detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+        .differences
+        .is_empty());
+    }
+}
+
+#[test]
+fn test_replica_mtimes() {
+    use filetime::FileTime;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let (archive, config) = set_up("replica_mtimes");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    filetime::set_file_mtime(
+        config.roots[0].join("foo"),
+        FileTime::from_unix_time(1_000_000_000, 500),
+    )
+    .unwrap();
+    fs::write(config.roots[0].join("bar"), "Hello World").unwrap();
+    filetime::set_file_mtime(
+        config.roots[0].join("bar"),
+        FileTime::from_unix_time(-1_001, 999_999_500),
+    )
+    .unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mtimes = |path: &str| {
+        result
+            .differences
+            .iter()
+            .find(|d| d.path == Path::new(path))
+            .unwrap()
+            .replica_mtimes()
+    };
+    assert_eq!(
+        mtimes("foo")[0],
+        Some(UNIX_EPOCH + Duration::new(1_000_000_000, 500))
+    );
+    assert_eq!(mtimes("foo")[1], None);
+    assert_eq!(
+        mtimes("bar")[0],
+        Some(UNIX_EPOCH - Duration::new(1_000, 500))
+    );
+}
+
+#[test]
+fn test_native_copy_only_rewrites_changed_blocks() {
+    let (archive, config) = set_up("native_copy_only_rewrites_changed_blocks");
+
+    let mut contents: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    fs::write(config.roots[0].join("foo"), &contents).unwrap();
+    fs::write(config.roots[1].join("foo"), &contents).unwrap();
+    contents[100_000] ^= 1;
+    fs::write(config.roots[0].join("foo"), &contents).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        ..Default::default()
+    };
+    let progress = RecordingProgress::default();
+    propagate::propagate(&result.differences[0], 0, &archive, &options, &progress).unwrap();
+
+    assert_eq!(fs::read(config.roots[1].join("foo")).unwrap(), contents);
+    // only the 64KB block containing the changed byte was written
+    let transferred = progress.updates.borrow().last().unwrap().0;
+    assert_eq!(transferred, 64 * 1024);
+}
+
+#[test]
+fn test_first_sync_of_matching_replicas() {
+    let (archive, config) = set_up("first_sync_of_matching_replicas");
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("foo"), "Hello World").unwrap();
+    }
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    // identical items aren't conflicts, and are recorded straight away
+    let result = find();
+    assert!(result.differences.is_empty());
+    assert_eq!(result.statistics.archive_additions, 2);
+    assert_eq!(find().statistics.archive_hits, 2);
+
+    let (archive, config) = set_up_three("first_sync_of_matching_replicas_three");
+    for root in &config.roots[..2] {
+        fs::write(root.join("same"), "Hello World").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+    }
+    fs::write(config.roots[0].join("different"), "Hello World").unwrap();
+    fs::write(config.roots[1].join("different"), "Goodbye World").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 3);
+    for difference in &result.differences {
+        let operation = reconcile::guess_operation(difference);
+        if difference.path == Path::new("same") {
+            assert!(matches!(
+                operation,
+                reconcile::Operation::PropagateFromMaster(0)
+            ));
+            propagate::propagate(
+                difference,
+                0,
+                &archive,
+                &propagate::DefaultPropagationOptions,
+                &propagate::EmptyProgressCallback,
+            )
+            .unwrap();
+        } else {
+            // directories could have different contents
+            assert!(
+                matches!(
+                    operation,
+                    reconcile::Operation::ItemDiffersBetweenReplicasAndNoArchive
+                ),
+                "{:?}",
+                difference.path
+            );
+        }
+    }
+    assert_eq!(
+        fs::read_to_string(config.roots[2].join("same")).unwrap(),
+        "Hello World"
+    );
+}
+
+#[test]
+fn test_archive_iter_files() {
+    let (archive, config) = set_up("archive_iter_files");
+
+    for root in config.roots.iter() {
+        fs::create_dir_all(root.join("dir").join("subdir")).unwrap();
+        fs::write(root.join("dir").join("foo"), "Hello World").unwrap();
+        fs::write(root.join("dir").join("subdir").join("bar"), "Hello World").unwrap();
+    }
+    detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    // not an archive file
+    fs::write(
+        Path::new("tests/replicas/archive_iter_files/archive").join("notes.txt"),
+        "",
+    )
+    .unwrap();
+
+    let mut paths = Vec::new();
+    for mut file in archive.iter_files().unwrap() {
+        let entries: ubiquity::archive::ArchiveEntries<U2> = file.read().unwrap();
+        paths.extend(entries.iter().map(|(path, _)| path.to_path_buf()));
+    }
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("dir"),
+            PathBuf::from("dir/foo"),
+            PathBuf::from("dir/subdir"),
+            PathBuf::from("dir/subdir/bar"),
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compare_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (archive, mut config) = set_up("compare_permissions");
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+    for root in config.roots.iter() {
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("key"), "secret").unwrap();
+        fs::set_permissions(root.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(
+            root.join("dir").join("key"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+    }
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    fs::set_permissions(
+        config.roots[0].join("dir").join("key"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    fs::set_permissions(
+        config.roots[1].join("dir"),
+        fs::Permissions::from_mode(0o700),
+    )
+    .unwrap();
+    let find = |config: &SyncInfo| {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+
+    // permissions are ignored by default
+    config.compare_permissions = false;
+    assert!(find(&config).differences.is_empty());
+
+    // the archive now matches the new permissions, so change them again
+    fs::set_permissions(
+        config.roots[0].join("dir").join("key"),
+        fs::Permissions::from_mode(0o400),
+    )
+    .unwrap();
+    fs::set_permissions(
+        config.roots[1].join("dir"),
+        fs::Permissions::from_mode(0o750),
+    )
+    .unwrap();
+    config.compare_permissions = true;
+    let result = find(&config);
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("dir"));
+    let report = propagate::propagate(
+        &result.differences[0],
+        1,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(report.permissions_set, 1);
+    assert_eq!(mode(&config.roots[0].join("dir")), 0o750);
+
+    // the directory's contents are only scanned once it is in sync
+    let result = find(&config);
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("dir/key"));
+    assert!(matches!(
+        reconcile::guess_operation(&result.differences[0]),
+        reconcile::Operation::PropagateFromMaster(0)
+    ));
+    let report = propagate::propagate(
+        &result.differences[0],
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(report.permissions_set, 1);
+    assert_eq!(report.files_copied, 0);
+    assert_eq!(mode(&config.roots[1].join("dir").join("key")), 0o400);
+    assert!(find(&config).differences.is_empty());
+}
+
+#[test]
+fn test_sharded_archive_layout() {
+    use ubiquity::archive::ArchiveLayout;
+
+    let (mut archive, config) = set_up("sharded_archive_layout");
+    for root in config.roots.iter() {
+        fs::create_dir_all(root.join("dir").join("subdir")).unwrap();
+        fs::write(root.join("dir").join("subdir").join("foo"), "Hello World").unwrap();
+    }
+    let find = |archive: &Archive| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    assert_eq!(find(&archive).statistics.archive_additions, 3);
+
+    // existing flat files are moved into shards
+    archive.layout = ArchiveLayout::Sharded;
+    assert_eq!(archive.migrate_layout().unwrap(), 3);
+    assert_eq!(archive.migrate_layout().unwrap(), 0);
+    let hash = archive.hash(Path::new("dir"));
+    assert!(!archive.directory.join(hash.to_string()).exists());
+    assert!(archive
+        .directory
+        .join(format!("{:02x}", hash >> 56))
+        .join(hash.to_string())
+        .exists());
+
+    let result = find(&archive);
+    assert!(result.differences.is_empty());
+    assert_eq!(result.statistics.archive_hits, 3);
+    assert_eq!(archive.iter_files().unwrap().count(), 3);
+
+    // new archive files are created in shards too
+    fs::write(config.roots[0].join("bar"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert!(find(&archive).differences.is_empty());
+    let flat_files = fs::read_dir(&archive.directory)
+        .unwrap()
+        .map(Result::unwrap)
+        // apart from the record of which roots the archive belongs to
+        .filter(|entry| entry.file_type().unwrap().is_file() && entry.file_name() != "roots")
+        .count();
+    assert_eq!(flat_files, 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_backup_suffix() {
+    let (archive, config) = set_up("backup_suffix");
+    for root in config.roots.iter() {
+        fs::write(root.join("foo"), "Hello World").unwrap();
+    }
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // the native backend renames the overwritten file
+    fs::write(config.roots[0].join("foo"), "Goodbye World").unwrap();
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        backup_suffix: Some("~".into()),
+        ..Default::default()
+    };
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Goodbye World"
+    );
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo~")).unwrap(),
+        "Hello World"
+    );
+
+    // rsync is asked to do the same
+    let (wrapper, args_path) = recording_rsync("tests/replicas/backup_suffix");
+    fs::write(config.roots[0].join("foo"), "Hello again").unwrap();
+    let options = propagate::BasicPropagationOptions {
+        rsync_path: wrapper,
+        backup_suffix: Some(".bak".into()),
+        ..Default::default()
+    };
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    let args = fs::read_to_string(args_path).unwrap();
+    assert!(args.contains("--backup --suffix=.bak"), "{}", args);
+}
+
+#[test]
+fn test_invalid_master_index() {
+    let (archive, config) = set_up("invalid_master_index");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    match propagate::propagate(
+        &result.differences[0],
+        2,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::InvalidReplicaIndex {
+            index: 2,
+            replicas: 2,
+        }) => {}
+        other => panic!(
+            "unexpected result: {:?}",
+            other.map(|report| report.actions)
+        ),
+    }
+    assert!(matches!(
+        propagate::propagate_plan(&result.differences[0], 5),
+        Err(SyncError::InvalidReplicaIndex { index: 5, .. })
+    ));
+    assert!(!config.roots[1].join("foo").exists());
+}
+
+#[test]
+fn test_file_comparator() {
+    let (archive, mut config) = set_up("file_comparator");
+
+    // files are equal if they match after the first line
+    config.file_comparator = Some(FileComparator::new(|a, b| {
+        let body = |path: &Path| {
+            fs::read_to_string(path).map(|contents| {
+                contents
+                    .split_once('\n')
+                    .map(|(_, body)| body.to_string())
+                    .unwrap_or_default()
+            })
+        };
+        Ok(body(a)? == body(b)?)
+    }));
+    fs::write(
+        config.roots[0].join("foo"),
+        "generated at 10:00\nHello World",
+    )
+    .unwrap();
+    fs::write(
+        config.roots[1].join("foo"),
+        "generated at 10:05:30\nHello World",
+    )
+    .unwrap();
+    fs::write(
+        config.roots[0].join("bar"),
+        "generated at 10:00\nHello World",
+    )
+    .unwrap();
+    fs::write(
+        config.roots[1].join("bar"),
+        "generated at 10:00\nGoodbye World",
+    )
+    .unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("bar"));
+}
+
+#[test]
+fn test_resume_detection_from_checkpoint() {
+    let (archive, mut config) = set_up("resume_detection");
+    let checkpoint = PathBuf::from("tests/replicas/resume_detection/checkpoint");
+    let _ = fs::remove_file(&checkpoint);
+
+    for dir in &["one", "two", "three"] {
+        fs::create_dir_all(config.roots[0].join(dir)).unwrap();
+        fs::create_dir_all(config.roots[1].join(dir)).unwrap();
+        fs::File::create(config.roots[0].join(dir).join("file")).unwrap();
+    }
+
+    /// Cancels the scan once the first directory has been read.
+    struct CancelAfterFirst(CancellationToken);
+
+    impl detect::ProgressCallback for CancelAfterFirst {
+        fn reading_directory(&self, _: &Path, _: usize, _: usize) {
+            self.0.cancel();
+        }
+    }
+
+    let mut search = detect::SearchDirectories::from_root();
+    let progress = CancelAfterFirst(config.cancellation.clone());
+    assert!(matches!(
+        detect::find_updates_resumable(&archive, &mut search, &config, &progress, &checkpoint),
+        Err(SyncError::Cancelled)
+    ));
+    assert!(checkpoint.exists());
+    assert_eq!(search.directories.len(), 3);
+
+    config.cancellation = CancellationToken::new();
+    let result = detect::find_updates_resumable(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+        &checkpoint,
+    )
+    .unwrap();
+    assert!(!checkpoint.exists());
+    let mut paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("one/file"),
+            PathBuf::from("three/file"),
+            PathBuf::from("two/file")
+        ]
+    );
+}
+
+#[test]
+fn test_classify_differences() {
+    use detect::DifferenceKind;
+
+    let (archive, config) = set_up("classify_differences");
+    let kinds = |archive: &Archive, config: &SyncInfo| {
+        let result = detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        let mut kinds: Vec<_> = result
+            .differences
+            .iter()
+            .map(|d| (d.path.clone(), d.classify()))
+            .collect();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+        kinds
+    };
+
+    fs::write(config.roots[0].join("new"), "new").unwrap();
+    fs::write(config.roots[0].join("both"), "a").unwrap();
+    fs::write(config.roots[1].join("both"), "b").unwrap();
+    assert_eq!(
+        kinds(&archive, &config),
+        vec![
+            (PathBuf::from("both"), DifferenceKind::ConflictingCreate),
+            (PathBuf::from("new"), DifferenceKind::Created { replica: 0 }),
+        ]
+    );
+
+    fs::remove_file(config.roots[0].join("both")).unwrap();
+    fs::remove_file(config.roots[1].join("both")).unwrap();
+    fs::write(config.roots[0].join("modified"), "1").unwrap();
+    fs::write(config.roots[0].join("deleted"), "deleted").unwrap();
+    fs::write(config.roots[0].join("retyped"), "file").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    fs::write(config.roots[1].join("modified"), "22").unwrap();
+    fs::remove_file(config.roots[0].join("deleted")).unwrap();
+    fs::remove_file(config.roots[1].join("retyped")).unwrap();
+    fs::create_dir(config.roots[1].join("retyped")).unwrap();
+    assert_eq!(
+        kinds(&archive, &config),
+        vec![
+            (
+                PathBuf::from("deleted"),
+                DifferenceKind::Deleted { replica: 0 }
+            ),
+            (PathBuf::from("modified"), DifferenceKind::Modified),
+            (PathBuf::from("retyped"), DifferenceKind::TypeChanged),
+        ]
+    );
+}
+
+#[test]
+fn test_ignore_absolute_globs() {
+    let (archive, mut config) = set_up("ignore_absolute_globs");
+
+    // only the cache inside the first replica is meant to be ignored
+    let cache = fs::canonicalize(&config.roots[0]).unwrap().join("cache");
+    config
+        .ignore
+        .absolute_globs
+        .push(glob::Pattern::new(&glob::Pattern::escape(cache.to_str().unwrap())).unwrap());
+    config.ignore.absolute_globs.push(
+        glob::Pattern::new(&format!(
+            "{}/**",
+            glob::Pattern::escape(cache.to_str().unwrap())
+        ))
+        .unwrap(),
+    );
+
+    fs::create_dir(config.roots[0].join("cache")).unwrap();
+    fs::File::create(config.roots[0].join("cache").join("blob")).unwrap();
+    // the same relative path in the other replica is ignored along with it
+    fs::File::create(config.roots[1].join("cache")).unwrap();
+    fs::File::create(config.roots[1].join("notes")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("notes"));
+
+    // the glob is anchored at the first root, so caches elsewhere are still synced
+    fs::create_dir(config.roots[1].join("sub")).unwrap();
+    fs::File::create(config.roots[1].join("sub").join("cache")).unwrap();
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 2);
+}
+
+#[test]
+fn test_parallel_comparison() {
+    let (archive, mut config) = set_up("parallel_comparison");
+    config.comparison_threads = 4;
+
+    for i in 0..10 {
+        fs::write(config.roots[0].join(format!("{}", i)), "same").unwrap();
+        let contents = if i % 2 == 0 { "same" } else { "diff" };
+        fs::write(config.roots[1].join(format!("{}", i)), contents).unwrap();
+    }
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let mut paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        ["1", "3", "5", "7", "9"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(result.statistics.archive_additions, 5);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_special_files_are_skipped() {
+    use std::process::Command;
+    use ubiquity::state::ArchiveEntryPerReplica;
+
+    let (archive, config) = set_up("special_files_are_skipped");
+
+    let status = Command::new("mkfifo")
+        .arg(config.roots[0].join("pipe"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::File::create(config.roots[1].join("pipe")).unwrap();
+    fs::File::create(config.roots[0].join("regular")).unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("regular"));
+
+    // a difference built by hand can't be propagated either
+    let difference =
+        detect::Difference::from_paths(PathBuf::from("pipe"), config.roots.clone(), None);
+    assert!(matches!(
+        difference.current_state[0],
+        ArchiveEntryPerReplica::Other(_)
+    ));
+    match propagate::propagate(
+        &difference,
+        1,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::UnsupportedFileType(path)) => assert_eq!(path, config.roots[0].join("pipe")),
+        other => panic!("expected an unsupported file type, got {:?}", other),
+    }
+    assert!(config.roots[1].join("pipe").is_file());
+}
+
+#[test]
+fn test_archive_lock_scope() {
+    use ubiquity::archive::{ArchiveEntries, LockPolicy, LockScope};
+
+    let (mut archive, config) = set_up("archive_lock_scope");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    archive.lock_policy = LockPolicy::FailFast;
+    archive.lock_scope = LockScope::PerOperation;
+
+    let find = |archive: &Archive| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+    };
+
+    // the lock isn't held once the read and write are over
+    let mut file = archive.for_directory(Path::new(""));
+    let mut entries: ArchiveEntries<U2> = file.read().unwrap();
+    file.write(&mut entries).unwrap();
+    assert!(find(&archive).unwrap().differences.is_empty());
+
+    // unless it was taken explicitly
+    file.lock_exclusive().unwrap();
+    let mut entries: ArchiveEntries<U2> = file.read().unwrap();
+    file.write(&mut entries).unwrap();
+    assert!(matches!(find(&archive), Err(SyncError::ArchiveLocked(_))));
+    file.unlock();
+    assert!(find(&archive).unwrap().differences.is_empty());
+}
+
+#[test]
+fn test_require_resolution() {
+    let (archive, config) = set_up("require_resolution");
+
+    fs::write(config.roots[0].join("new"), "a").unwrap();
+    fs::write(config.roots[0].join("both"), "a").unwrap();
+    fs::write(config.roots[1].join("both"), "bb").unwrap();
+    let find = || {
+        let mut result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        result.differences.sort_by(|a, b| a.path.cmp(&b.path));
+        result
+    };
+
+    let result = find();
+    let require = |difference| {
+        reconcile::require_resolution(reconcile::guess_operation(difference), difference)
+    };
+    match require(&result.differences[0]) {
+        Err(SyncError::UnresolvedConflict { path, replicas }) => {
+            assert_eq!(path, Path::new("both"));
+            assert_eq!(replicas, vec![0, 1]);
+        }
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(require(&result.differences[1]).unwrap(), 0);
+
+    // once synced, a change on both replicas is a conflict between the replicas which changed
+    fs::remove_file(config.roots[1].join("both")).unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    fs::write(config.roots[0].join("both"), "aaa").unwrap();
+    fs::write(config.roots[1].join("both"), "bbbb").unwrap();
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    assert!(matches!(
+        require(&result.differences[0]),
+        Err(SyncError::UnresolvedConflict { ref replicas, .. }) if *replicas == vec![0, 1]
+    ));
+}
+
+#[test]
+fn test_archive_root_mismatch() {
+    let (mut archive, config) = set_up("archive_root_mismatch");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // the same archive pointed at other replicas (here, the same ones swapped around)
+    let [a, b]: [PathBuf; 2] = config.roots.clone().into();
+    let swapped = SyncInfo::new(arr![PathBuf; b, a]);
+    let find = |archive: &Archive, config: &SyncInfo| {
+        detect::find_updates(
+            archive,
+            &mut detect::SearchDirectories::from_root(),
+            config,
+            &detect::EmptyProgressCallback,
+        )
+    };
+    match find(&archive, &swapped) {
+        Err(SyncError::ArchiveRootMismatch(path)) => assert_eq!(path, archive.directory),
+        other => panic!("expected a root mismatch, got {:?}", other.map(|_| ())),
+    }
+    assert!(find(&archive, &config).unwrap().differences.is_empty());
+
+    archive.allow_root_mismatch = true;
+    assert!(find(&archive, &swapped).is_ok());
+    archive.allow_root_mismatch = false;
+
+    // recording the new roots makes them the only ones accepted
+    archive.record_roots(&swapped.roots).unwrap();
+    assert!(find(&archive, &swapped).is_ok());
+    assert!(matches!(
+        find(&archive, &config),
+        Err(SyncError::ArchiveRootMismatch(_))
+    ));
+}
+
+#[test]
+fn test_scan_control() {
+    use detect::ScanControl;
+
+    let (archive, config) = set_up("scan_control");
+    for dir in &["keep", "skip", "skip/nested"] {
+        fs::create_dir(config.roots[0].join(dir)).unwrap();
+        fs::create_dir(config.roots[1].join(dir)).unwrap();
+    }
+    fs::File::create(config.roots[0].join("keep").join("file")).unwrap();
+    fs::File::create(config.roots[0].join("skip").join("file")).unwrap();
+    fs::File::create(config.roots[0].join("skip/nested").join("file")).unwrap();
+
+    /// Skips (or aborts at) the directory called `name`.
+    struct Control {
+        name: &'static str,
+        decision: ScanControl,
+    }
+
+    impl detect::ProgressCallback for Control {
+        fn reading_directory(&self, _: &Path, _: usize, _: usize) {}
+
+        fn scan_control(&self, path: &Path) -> ScanControl {
+            if path == Path::new(self.name) {
+                self.decision
+            } else {
+                ScanControl::Continue
+            }
+        }
+    }
+
+    let find = |decision| {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &Control {
+                name: "skip",
+                decision,
+            },
+        )
+    };
+    let result = find(ScanControl::SkipChildren).unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert_eq!(result.differences[0].path, Path::new("keep/file"));
+
+    assert!(matches!(
+        find(ScanControl::Abort),
+        Err(SyncError::Cancelled)
+    ));
+    assert_eq!(find(ScanControl::Continue).unwrap().differences.len(), 3);
+}
+
+#[test]
+fn test_prefer_reflink() {
+    let (archive, config) = set_up("prefer_reflink");
+    let options = propagate::BasicPropagationOptions {
+        copy_backend: CopyBackend::Native,
+        prefer_reflink: true,
+        ..Default::default()
+    };
+
+    // whether or not this filesystem supports reflinks, the file ends up copied
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    fs::write(config.roots[0].join("foo"), "Goodbye").unwrap();
+    detect_and_resolve_with(
+        &archive,
+        &config,
+        &detect::SearchDirectories::from_root(),
+        &options,
+    );
+    assert_eq!(
+        fs::read_to_string(config.roots[1].join("foo")).unwrap(),
+        "Goodbye"
+    );
+    assert_eq!(fs::read_dir(&config.roots[1]).unwrap().count(), 1);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_find_updates_async() {
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    let (archive, config) = set_up("find_updates_async");
+    for name in &["foo", "bar", "baz"] {
+        fs::write(config.roots[0].join(name), name).unwrap();
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let (archive, config) = (Arc::new(archive), Arc::new(config));
+    let (paths, statistics) = runtime.block_on(async {
+        // a small buffer, so that the scan has to wait for the receiver
+        let (sender, mut receiver) = mpsc::channel(1);
+        let scan = tokio::spawn(detect::find_updates_async(
+            archive.clone(),
+            detect::SearchDirectories::from_root(),
+            config.clone(),
+            detect::EmptyProgressCallback,
+            sender,
+        ));
+        let mut paths = Vec::new();
+        while let Some(difference) = receiver.recv().await {
+            paths.push(difference.path);
+        }
+        (paths, scan.await.unwrap().unwrap())
+    });
+    let mut paths = paths;
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("bar"),
+            PathBuf::from("baz"),
+            PathBuf::from("foo")
+        ]
+    );
+    assert_eq!(statistics.differences, 3);
+
+    // dropping the receiver cancels the scan
+    let result = runtime.block_on(async {
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        detect::find_updates_async(
+            archive,
+            detect::SearchDirectories::from_root(),
+            config,
+            detect::EmptyProgressCallback,
+            sender,
+        )
+        .await
+    });
+    assert!(matches!(result, Err(SyncError::Cancelled)));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_spans() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    /// Records the name and fields of every span which is created.
+    #[derive(Default)]
+    struct SpanRecorder {
+        next_id: AtomicU64,
+        spans: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Fields<'a>(&'a mut String);
+
+    impl tracing::field::Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut description = span.metadata().name().to_owned();
+            span.record(&mut Fields(&mut description));
+            self.spans.lock().unwrap().push(description);
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    let (archive, config) = set_up("tracing_spans");
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+
+    let recorder = SpanRecorder::default();
+    let spans = recorder.spans.clone();
+    tracing::subscriber::with_default(recorder, || {
+        detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    });
+    let spans = spans.lock().unwrap();
+    assert!(spans.contains(&"find_updates search_directories=1".to_owned()));
+    assert!(spans.contains(&"scan_directory path=\"\"".to_owned()));
+    assert!(spans.contains(&"propagate path=\"foo\" master=0".to_owned()));
+    assert!(spans.contains(&"propagate_to_replica replica=1".to_owned()));
+    assert!(spans
+        .iter()
+        .any(|span| span.starts_with("transfer_file") && span.ends_with("bytes=11")));
+    assert!(spans.iter().any(|span| span.starts_with("run_rsync")));
+}
+
+#[test]
+fn test_comparison_block_size() {
+    let (archive, mut config) = set_up("comparison_block_size");
+    // smaller than the files, so that they are compared over several blocks
+    config.comparison_block_size = 3;
+
+    fs::write(config.roots[0].join("same"), "0123456789").unwrap();
+    fs::write(config.roots[1].join("same"), "0123456789").unwrap();
+    fs::write(config.roots[0].join("last_byte"), "0123456789").unwrap();
+    fs::write(config.roots[1].join("last_byte"), "012345678x").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+    assert_eq!(paths, vec![PathBuf::from("last_byte")]);
+
+    let a = config.roots[0].join("same");
+    let b = config.roots[1].join("same");
+    assert!(file_contents_equal_in_blocks(&a, &b, 0).unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_duplicate_root() {
+    let (archive, mut config) = set_up("duplicate_root");
+    fs::write(config.roots[0].join("file"), "contents").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    let mut difference = result.differences[0].clone();
+
+    // a second root which is really the first one
+    let link = PathBuf::from("tests/replicas/duplicate_root/link");
+    let _ = fs::remove_file(&link);
+    std::os::unix::fs::symlink(fs::canonicalize(&config.roots[0]).unwrap(), &link).unwrap();
+    config.roots[1] = link.clone();
+    difference.roots[1] = link.clone();
+
+    match detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    ) {
+        Err(SyncError::DuplicateRoot { a, b }) => {
+            assert_eq!(a, config.roots[0]);
+            assert_eq!(b, link);
+        }
+        other => panic!(
+            "expected a duplicate root error, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+    match propagate::propagate(
+        &difference,
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    ) {
+        Err(SyncError::DuplicateRoot { .. }) => {}
+        other => panic!("expected a duplicate root error, got {:?}", other),
+    }
+    assert!(config.roots[0].join("file").exists());
+}
+
+#[test]
+fn test_archive_inside_root() {
+    let (_, config) = set_up("archive_inside_root");
+    let archive = Archive::new(config.roots[0].join(".ubiquity")).unwrap();
+    fs::write(config.roots[0].join("file"), "contents").unwrap();
+
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+    assert_eq!(paths, vec![PathBuf::from("file")]);
+
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    assert!(config.roots[1].join("file").exists());
+    assert!(!config.roots[1].join(".ubiquity").exists());
+
+    // the archive has been written to since, but that still isn't a difference
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(result.differences.is_empty());
+}
+
+#[test]
+fn test_sync_info_builder() {
+    let (archive, config) = set_up("sync_info_builder");
+    let config: SyncInfo = SyncInfo::builder()
+        .root(config.roots[0].clone())
+        .root(config.roots[1].clone())
+        .ignore_regex(r"\.DS_Store$")
+        .ignore_glob("*.tmp")
+        .ignore_path("cache")
+        .compare_contents(false)
+        .build()
+        .unwrap();
+    assert_eq!(config.content_comparison, ContentComparison::SizeOnly);
+
+    for name in &[".DS_Store", "scratch.tmp", "cache", "file"] {
+        fs::write(config.roots[0].join(name), "contents").unwrap();
+    }
+    let result = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    let paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+    assert_eq!(paths, vec![PathBuf::from("file")]);
+
+    match SyncInfo::<U3>::builder().root("a").root("b").build() {
+        Err(SyncError::WrongNumberOfRoots { expected, found }) => {
+            assert_eq!((expected, found), (3, 2));
+        }
+        other => panic!("expected the wrong number of roots, got {:?}", other),
+    }
+    match SyncInfo::<U2>::builder()
+        .root("a")
+        .root("b")
+        .ignore_glob("[")
+        .build()
+    {
+        Err(SyncError::InvalidIgnorePattern { pattern, .. }) => assert_eq!(pattern, "["),
+        other => panic!("expected an invalid pattern, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_dynamic_root_count() {
+    use ubiquity::dynamic::*;
+
+    let (archive, config) = set_up_three("dynamic_root_count");
+    let config = DynamicSyncInfo::new(config.roots.iter().cloned().collect());
+    fs::write(config.roots[0].join("file"), "contents").unwrap();
+
+    let result = find_updates_dynamic(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    let difference = &result.differences[0];
+    assert_eq!(difference.current_state.len(), 3);
+    match difference.to_static::<U2>() {
+        Err(SyncError::WrongNumberOfRoots { expected, found }) => {
+            assert_eq!((expected, found), (2, 3));
+        }
+        other => panic!("expected the wrong number of roots, got {:?}", other),
+    }
+
+    let operation = guess_operation_dynamic(difference).unwrap();
+    assert!(matches!(
+        operation,
+        reconcile::Operation::PropagateFromMaster(0)
+    ));
+    propagate_dynamic(
+        difference,
+        0,
+        &archive,
+        &propagate::DefaultPropagationOptions,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(config.roots[1].join("file").exists());
+    assert!(config.roots[2].join("file").exists());
+
+    let result = find_updates_dynamic(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(result.differences.is_empty());
+
+    let too_many = DynamicSyncInfo::new(vec![config.roots[0].clone(); MAX_DYNAMIC_ROOTS + 1]);
+    match find_updates_dynamic(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &too_many,
+        &detect::EmptyProgressCallback,
+    ) {
+        Err(SyncError::UnsupportedRootCount(count)) => assert_eq!(count, MAX_DYNAMIC_ROOTS + 1),
+        other => panic!(
+            "expected an unsupported root count, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+}
+
+#[test]
+fn test_read_only_archive() {
+    let (archive, config) = set_up("read_only_archive");
+    fs::write(config.roots[0].join("synced"), "contents").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // one new difference, and one new item which is identical on both replicas
+    fs::write(config.roots[0].join("new"), "contents").unwrap();
+    fs::write(config.roots[0].join("same"), "contents").unwrap();
+    fs::write(config.roots[1].join("same"), "contents").unwrap();
+
+    let list_archive = || {
+        let mut files: Vec<_> = fs::read_dir(&archive.directory)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let contents = fs::read(&path).unwrap();
+                (path, contents)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = list_archive();
+
+    let read_only = Archive::read_only(archive.directory.clone());
+    for _ in 0..2 {
+        let result = detect::find_updates(
+            &read_only,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        let paths: Vec<_> = result.differences.iter().map(|d| d.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("new")]);
+        assert_eq!(result.statistics.archive_additions, 0);
+        assert_eq!(result.statistics.archive_hits, 1);
+    }
+    assert_eq!(list_archive(), before);
+
+    // a missing archive isn't created
+    let missing = PathBuf::from("tests/replicas/read_only_archive/missing");
+    let _ = fs::remove_dir_all(&missing);
+    let result = detect::find_updates(
+        &Archive::read_only(missing.clone()),
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(result.differences.len(), 1);
+    assert!(!missing.exists());
+}
+
+#[test]
+fn test_skip_locked() {
+    use fs2::FileExt;
+
+    let (archive, config) = set_up("skip_locked");
+    fs::write(config.roots[0].join("database"), "half written").unwrap();
+    fs::write(config.roots[0].join("file"), "contents").unwrap();
+    let lock = fs::File::open(config.roots[0].join("database")).unwrap();
+    lock.lock_exclusive().unwrap();
+
+    let options = propagate::BasicPropagationOptions {
+        skip_locked: true,
+        ..Default::default()
+    };
+    let find_updates = || {
+        let mut result = detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap();
+        result.differences.sort_by(|a, b| a.path.cmp(&b.path));
+        result.differences
+    };
+
+    let differences = find_updates();
+    assert_eq!(differences.len(), 2);
+    let mut skipped_locked = Vec::new();
+    for difference in &differences {
+        let report = propagate::propagate(
+            difference,
+            0,
+            &archive,
+            &options,
+            &propagate::EmptyProgressCallback,
+        )
+        .unwrap();
+        skipped_locked.extend(report.skipped_locked);
+    }
+    assert_eq!(skipped_locked, vec![config.roots[0].join("database")]);
+    assert!(!config.roots[1].join("database").exists());
+    assert!(config.roots[1].join("file").exists());
+
+    // the locked file is picked up again once it has been unlocked
+    let differences = find_updates();
+    assert_eq!(differences.len(), 1);
+    assert_eq!(differences[0].path, PathBuf::from("database"));
+    lock.unlock().unwrap();
+    let report = propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(report.skipped_locked.is_empty());
+    assert!(config.roots[1].join("database").exists());
+    assert!(find_updates().is_empty());
+}
+
+#[test]
+fn test_skip_locked_replacing_directory() {
+    use fs2::FileExt;
+
+    let (archive, config) = set_up_three("skip_locked_replacing_directory");
+    fs::create_dir(config.roots[0].join("item")).unwrap();
+    fs::write(config.roots[0].join("item/inner"), "contents").unwrap();
+    let find_updates = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+        .differences
+    };
+    for difference in find_updates() {
+        propagate::propagate(
+            &difference,
+            0,
+            &archive,
+            &propagate::DefaultPropagationOptions,
+            &propagate::EmptyProgressCallback,
+        )
+        .unwrap();
+    }
+
+    // the directory is replaced by a file, which is still being written
+    fs::remove_dir_all(config.roots[0].join("item")).unwrap();
+    fs::write(config.roots[0].join("item"), "half written").unwrap();
+    let lock = fs::File::open(config.roots[0].join("item")).unwrap();
+    lock.lock_exclusive().unwrap();
+
+    let options = propagate::BasicPropagationOptions {
+        skip_locked: true,
+        ..Default::default()
+    };
+    let differences = find_updates();
+    assert_eq!(differences.len(), 1);
+    let report = propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    // reported once, however many replicas it would have been copied to
+    assert_eq!(report.skipped_locked, vec![config.roots[0].join("item")]);
+    assert!(config.roots[1].join("item/inner").exists());
+    assert!(config.roots[2].join("item/inner").exists());
+
+    // the next sync still knows which replica changed, rather than seeing a conflict
+    let differences = find_updates();
+    assert_eq!(differences.len(), 1);
+    assert!(matches!(
+        reconcile::guess_operation(&differences[0]),
+        reconcile::Operation::PropagateFromMaster(0)
+    ));
+    lock.unlock().unwrap();
+    let report = propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(report.skipped_locked.is_empty());
+    assert!(config.roots[2].join("item").is_file());
+    assert!(find_updates().is_empty());
+}
+
+/// SQLite locks part of the database with `fcntl` while writing to it, which `flock` doesn't see.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_skip_locked_record_lock() {
+    use std::os::unix::io::AsRawFd;
+
+    let (archive, config) = set_up("skip_locked_record_lock");
+    fs::write(config.roots[0].join("database"), "half written").unwrap();
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(config.roots[0].join("database"))
+        .unwrap();
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = 1 << 30;
+    lock.l_len = 1;
+    assert_ne!(
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) },
+        -1
+    );
+
+    let differences = detect::find_updates(
+        &archive,
+        &mut detect::SearchDirectories::from_root(),
+        &config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap()
+    .differences;
+    let options = propagate::BasicPropagationOptions {
+        skip_locked: true,
+        ..Default::default()
+    };
+    let report = propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert_eq!(
+        report.skipped_locked,
+        vec![config.roots[0].join("database")]
+    );
+    assert!(!config.roots[1].join("database").exists());
+
+    // closing the file releases the lock
+    drop(file);
+    let report = propagate::propagate(
+        &differences[0],
+        0,
+        &archive,
+        &options,
+        &propagate::EmptyProgressCallback,
+    )
+    .unwrap();
+    assert!(report.skipped_locked.is_empty());
+    assert!(config.roots[1].join("database").exists());
+}
+
+#[test]
+fn test_three_replicas_same_edit() {
+    let (archive, config) = set_up_three("three_replicas_same_edit");
+
+    fs::write(config.roots[0].join("foo"), "Hello World").unwrap();
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+
+    // the same edit on two replicas isn't a conflict
+    fs::write(config.roots[1].join("foo"), "Hello Earth!").unwrap();
+    fs::write(config.roots[2].join("foo"), "Hello Earth!").unwrap();
+    let find = || {
+        detect::find_updates(
+            &archive,
+            &mut detect::SearchDirectories::from_root(),
+            &config,
+            &detect::EmptyProgressCallback,
+        )
+        .unwrap()
+    };
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    assert!(result.differences[0].changed_replicas_agree);
+    assert!(matches!(
+        reconcile::guess_operation(&result.differences[0]),
+        reconcile::Operation::PropagateFromMaster(1)
+    ));
+    detect_and_resolve(&archive, &config, &detect::SearchDirectories::from_root());
+    for root in config.roots.iter() {
+        assert_eq!(
+            fs::read_to_string(root.join("foo")).unwrap(),
+            "Hello Earth!"
+        );
+    }
+
+    // but different edits still are
+    fs::write(config.roots[0].join("foo"), "Hello Mars").unwrap();
+    fs::write(config.roots[2].join("foo"), "Hello Venus!!").unwrap();
+    let result = find();
+    assert_eq!(result.differences.len(), 1);
+    assert!(matches!(
+        reconcile::guess_operation(&result.differences[0]),
+        reconcile::Operation::ItemChangedOnMultipleReplicas
+    ));
+}
+
+#[test]
+fn test_regex_forward_slash() {
+    let r = regex::Regex::new(r"/target/").unwrap();
+    assert!(r.is_match("/Users/bob/awesome/target/foo"));
+    assert!(!r.is_match("/Users/bob/awesome/target"));
+}
+
+fn detect_and_resolve<N: NumRoots>(
+    archive: &Archive,
+    config: &SyncInfo<N>,
+    search_directories: &detect::SearchDirectories,
+) {
+    detect_and_resolve_with(
+        archive,
+        config,
+        search_directories,
+        &propagate::DefaultPropagationOptions,
+    );
+}
+
+fn detect_and_resolve_with<T: PropagationOptions, N: NumRoots>(
+    archive: &Archive,
+    config: &SyncInfo<N>,
+    search_directories: &detect::SearchDirectories,
+    options: &T,
+) {
+    let result = detect::find_updates(
+        archive,
+        &mut search_directories.clone(),
+        config,
+        &detect::EmptyProgressCallback,
+    )
+    .unwrap();
+
+    info!("{} differences", result.differences.len());
+    for difference in result.differences {
+        let operation = reconcile::guess_operation(&difference);
+        info!("difference {:?}: {:?}", difference.path, operation);
+        if let reconcile::Operation::PropagateFromMaster(master) = operation {
+            propagate::propagate(
+                &difference,
+                master,
+                archive,
+                options,
+                &propagate::EmptyProgressCallback,
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Writes an executable shell script called `name` into `dir`, returning its absolute path.
+#[cfg(unix)]
+fn write_script(dir: &str, name: &str, script: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = fs::canonicalize(dir).unwrap().join(name);
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+/// A wrapper around rsync in `dir` which records its arguments,
+/// returning the wrapper and the file the arguments are written to.
+#[cfg(unix)]
+fn recording_rsync(dir: &str) -> (PathBuf, PathBuf) {
+    let args_path = fs::canonicalize(dir).unwrap().join("args");
+    let wrapper = write_script(
+        dir,
+        "rsync",
+        &format!(
+            "#!/bin/sh\necho \"$@\" > {:?}\nexec rsync \"$@\"\n",
+            args_path
+        ),
+    );
+    (wrapper, args_path)
 }
 
 fn clean_directory(p: &Path) -> io::Result<()> {